@@ -15,3 +15,25 @@ CREATE TABLE skeb_illusts(
     creator_name TEXT NOT NULL,
     comment TEXT NOT NULL
 );
+
+CREATE TABLE rate_counters(
+    counter_key TEXT NOT NULL,
+    window_start INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (counter_key, window_start)
+);
+CREATE INDEX rate_counter_window_index ON rate_counters(window_start);
+
+CREATE TABLE usages(
+    platform TEXT NOT NULL,
+    user TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    cached_tokens INTEGER NOT NULL,
+    recorded_at INTEGER NOT NULL
+);
+CREATE INDEX usage_user_index ON usages(platform, user, recorded_at);
+CREATE INDEX usage_conversation_index ON usages(conversation_id);
+CREATE INDEX usage_recorded_at_index ON usages(recorded_at);
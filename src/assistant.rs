@@ -1,44 +1,69 @@
 use crate::{
-    error::AssistantError,
+    error::{AssistantError, LlmError},
+    metrics::METRICS,
     model::{
-        config::AppConfigAssistantIdentity,
-        conversation::{Conversation, ConversationAttachment, ConversationUpdate, IncompleteConversation},
-        message::{AssistantMessage, FunctionResponseMessage, Message, MessageFunctionCall, UserMessage},
+        command::AssistantCommand,
+        config::{
+            AppConfigAssistant, AppConfigAssistantIdentity, AppConfigAssistantSummary, AppConfigBudget,
+            AppConfigRateLimit,
+        },
+        conversation::{
+            Conversation, ConversationAttachment, ConversationEntry, ConversationOrigin, ConversationUpdate,
+            IncompleteConversation,
+        },
+        message::{
+            AssistantMessage, FunctionResponseMessage, Message, MessageFunctionCall, UserMessage, UserMessageContent,
+        },
+        usage::UsageRecord,
     },
-    specs::{function::simple::SimpleFunction, llm::Llm, storage::ConversationStorage},
+    specs::{
+        function::simple::{SimpleFunction, SimpleFunctionResponse},
+        hook::{AssistantHook, HookAction},
+        llm::{Llm, LlmAssistantResponse, LlmStreamEvent, LlmUpdate, LlmUsage},
+        storage::ConversationStorage,
+    },
+};
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use std::{collections::HashMap, fmt::Debug, sync::Arc};
+use futures::{TryStreamExt, future::join_all};
+use time::OffsetDateTime;
+use tokio::{sync::mpsc::UnboundedSender, time::timeout};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
-use tokio::sync::Mutex;
-use tracing::{info, warn};
+/// リクエスト数を数える期間。
+const RATE_LIMIT_MINUTE: Duration = Duration::from_secs(60);
+
+/// tool 呼び出し回数を数える期間。
+const RATE_LIMIT_DAY: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// 各種アシスタント動作の抽象化レイヤー。
 #[derive(Debug, Clone)]
 pub struct Assistant(Arc<AssistantInner>);
 
 impl Assistant {
-    pub fn new(
-        assistant_identity: &AppConfigAssistantIdentity,
-        llm: Box<dyn Llm + 'static>,
-        storage: Box<dyn ConversationStorage + 'static>,
-    ) -> Assistant {
+    pub fn new(profile: AssistantProfile, storage: Box<dyn ConversationStorage + 'static>) -> Assistant {
         Assistant(Arc::new(AssistantInner {
-            llm,
             storage,
-            simple_functions: Mutex::new(HashMap::new()),
-            system_role: assistant_identity.system_role.clone(),
-            sensitive_marker: assistant_identity.sensitive_marker.clone(),
+            profile: RwLock::new(Arc::new(profile)),
         }))
     }
 
-    /// `SimpleFunction` を登録する。
-    pub async fn add_simple_function(&self, simple_function: impl SimpleFunction + 'static) {
-        let descriptor = simple_function.get_descriptor();
+    /// `AssistantProfile` を差し替える。
+    /// 処理中の `Conversation` は差し替え前の `AssistantProfile` で最後まで処理される。
+    pub fn replace_profile(&self, profile: AssistantProfile) {
+        let mut locked = self.0.profile.write().expect("profile lock poisoned");
+        *locked = Arc::new(profile);
+    }
 
-        let mut locked = self.0.simple_functions.lock().await;
-        locked.insert(descriptor.name.clone(), Box::new(simple_function));
-        self.0.llm.add_simple_function(descriptor).await;
+    fn current_profile(&self) -> Arc<AssistantProfile> {
+        self.0.profile.read().expect("profile lock poisoned").clone()
     }
 
     /// 指定された `Conversation` が「完了」するまで処理する。
@@ -46,52 +71,164 @@ impl Assistant {
         &self,
         conversation: Conversation,
         user_message: UserMessage,
+        origin: &ConversationOrigin,
+    ) -> Result<ConversationUpdate, AssistantError> {
+        self.process_conversation_inner(conversation, user_message, origin, None)
+            .await
+    }
+
+    /// `process_conversation` と同様に処理するが、生成途中の応答テキストを `text_sender` に逐次送信する。
+    /// 途中のテキストは tool calling の前置きなども含むので、最終的な応答内容は必ず戻り値の `ConversationUpdate` で表示し直すこと。
+    pub async fn process_conversation_streaming(
+        &self,
+        conversation: Conversation,
+        user_message: UserMessage,
+        origin: &ConversationOrigin,
+        text_sender: UnboundedSender<String>,
+    ) -> Result<ConversationUpdate, AssistantError> {
+        self.process_conversation_inner(conversation, user_message, origin, Some(&text_sender))
+            .await
+    }
+
+    async fn process_conversation_inner(
+        &self,
+        conversation: Conversation,
+        user_message: UserMessage,
+        origin: &ConversationOrigin,
+        text_sender: Option<&UnboundedSender<String>>,
     ) -> Result<ConversationUpdate, AssistantError> {
-        let mut incomplete_conversation = IncompleteConversation::start(conversation, user_message);
+        let profile = self.current_profile();
+        METRICS.requests.with_label_values(&[&origin.platform]).inc();
 
-        let first_update = self.0.llm.send_conversation(&incomplete_conversation).await?;
-        let (assistant_update, attachments) = if let Some(tool_callings) = first_update.tool_callings {
-            let call_message = Message::new_function_calls(tool_callings.clone());
-            let (response_messages, attachments) = self.process_tool_callings(tool_callings).await?;
+        // コマンドは LLM に送らずにここで処理する
+        if let Some(command) = AssistantCommand::parse(&user_message) {
+            return Ok(self.execute_command(&profile, conversation, command));
+        }
 
-            incomplete_conversation.latest_messages.push(call_message);
-            incomplete_conversation
-                .latest_messages
-                .extend(response_messages.into_iter().map(|m| m.into()));
+        let (_, identity) = profile.resolve_identity(conversation.identity());
+        if !self.check_request_limit(&profile, origin).await {
+            info!("request from {}:{} rate limited", origin.platform, origin.user);
+            let assistant_message = AssistantMessage {
+                text: identity.rate_limited_message.clone(),
+                is_sensitive: false,
+                language: None,
+            };
+            return Ok(ConversationUpdate::without_history(conversation, assistant_message));
+        }
 
-            let second_update = self.0.llm.send_conversation(&incomplete_conversation).await?;
-            (second_update, attachments)
-        } else {
-            (first_update, vec![])
-        };
+        // 利用料金の上限が近ければ節約モード、超えていれば応答しない
+        let budget_status = self.check_budget(&profile, origin).await;
+        if budget_status == BudgetStatus::Exhausted {
+            info!("budget for {}:{} exhausted", origin.platform, origin.user);
+            let assistant_message = AssistantMessage {
+                text: identity.out_of_energy_message.clone(),
+                is_sensitive: false,
+                language: None,
+            };
+            return Ok(ConversationUpdate::without_history(conversation, assistant_message));
+        }
+        let economy = budget_status == BudgetStatus::Economy;
+        let llm = profile.llm_for(economy);
 
-        let Some(response) = assistant_update.response else {
-            return Err(AssistantError::ChatResponseExpected);
-        };
+        let mut turn_usage = TurnUsage::default();
+        let conversation = self
+            .summarize_if_needed(llm, conversation, identity.summary.as_ref(), &mut turn_usage)
+            .await;
+        let conversation_id = conversation.id();
+        // 差し替えられる前のテキストが表示されてしまうので、フックが許さなければストリーミングしない
+        let text_sender = text_sender.filter(|_| profile.hooks.iter().all(|h| h.allows_streaming()));
 
-        let (text, is_sensitive) = match response.sensitive {
-            Some(v) => (response.text, v),
-            None if self.0.sensitive_marker.is_empty() => (response.text, false),
-            _ => match response.text.strip_prefix(&self.0.sensitive_marker) {
-                Some(stripped) => (stripped.to_string(), true),
-                None => (response.text, false),
-            },
-        };
+        // フックで打ち切られた場合や途中で失敗した場合も、そこまでの利用量は記録する
+        let turn_result = async {
+            let original_conversation = conversation.clone();
+            let mut incomplete_conversation = IncompleteConversation::start(conversation, user_message);
+            for hook in &profile.hooks {
+                if let HookAction::Reply(message) = hook.pre_send(&mut incomplete_conversation).await? {
+                    return Ok(ConversationUpdate::without_history(original_conversation, message));
+                }
+            }
+
+            // tool calling がなくなるまで繰り返す
+            let mut attachments = vec![];
+            let mut tool_rounds = 0;
+            let response = loop {
+                let mut update = self
+                    .send_conversation(llm, &incomplete_conversation, text_sender, &identity.sensitive_marker)
+                    .await?;
+                turn_usage.add(update.usage.take());
+                for hook in &profile.hooks {
+                    if let HookAction::Reply(message) = hook.post_llm(&incomplete_conversation, &mut update).await? {
+                        return Ok(ConversationUpdate::without_history(original_conversation, message));
+                    }
+                }
+                let Some(tool_callings) = update.tool_callings.filter(|tc| !tc.is_empty()) else {
+                    break update.response.ok_or(AssistantError::ChatResponseExpected)?;
+                };
+
+                if tool_rounds >= identity.max_tool_rounds {
+                    warn!("tool calling exceeded {} round(s)", identity.max_tool_rounds);
+                    let Some(fallback_text) = &identity.tool_rounds_exceeded_message else {
+                        return Err(AssistantError::ToolRoundsExceeded(identity.max_tool_rounds));
+                    };
+                    break LlmAssistantResponse {
+                        text: fallback_text.clone(),
+                        language: None,
+                        sensitive: Some(false),
+                    };
+                }
+                tool_rounds += 1;
+
+                let call_message = Message::new_function_calls(tool_callings.clone());
+                let (response_messages, round_attachments) = self
+                    .process_tool_callings(&profile, origin, economy, tool_callings)
+                    .await;
+
+                incomplete_conversation.latest_messages.push(call_message);
+                incomplete_conversation
+                    .latest_messages
+                    .extend(response_messages.into_iter().map(|m| m.into()));
+                attachments.extend(round_attachments);
+            };
+
+            let (text, is_sensitive) = match response.sensitive {
+                Some(v) => (response.text, v),
+                None if identity.sensitive_marker.is_empty() => (response.text, false),
+                _ => match response.text.strip_prefix(&identity.sensitive_marker) {
+                    Some(stripped) => (stripped.to_string(), true),
+                    None => (response.text, false),
+                },
+            };
 
-        Ok(incomplete_conversation.finish(
-            AssistantMessage {
+            let mut assistant_message = AssistantMessage {
                 text,
                 is_sensitive,
                 language: response.language,
-            },
-            attachments,
-        ))
+            };
+            for hook in &profile.hooks {
+                if let HookAction::Reply(message) = hook
+                    .post_response(&incomplete_conversation, &mut assistant_message)
+                    .await?
+                {
+                    return Ok(ConversationUpdate::without_history(original_conversation, message));
+                }
+            }
+            Ok::<_, AssistantError>(incomplete_conversation.finish(assistant_message, attachments))
+        }
+        .await;
+
+        self.record_usage(origin, conversation_id, turn_usage).await;
+        turn_result
+    }
+
+    /// 永続化層を閉じる。シャットダウン時にすべてのプラットフォームが止まってから呼ぶこと。
+    pub async fn close(&self) {
+        self.0.storage.close().await;
     }
 
     /// 新しい `Conversation` を現在時刻の ID で初期化する。
-    pub fn new_conversation(&self) -> Conversation {
-        let system_message = Message::new_system(self.0.system_role.clone());
-        Conversation::new_now(Some(system_message))
+    /// `identity` が指定されていないか未定義の場合はデフォルトの identity を利用する。
+    pub fn new_conversation(&self, identity: Option<&str>) -> Conversation {
+        self.current_profile().new_conversation(identity)
     }
 
     pub async fn restore_conversation(
@@ -113,39 +250,1217 @@ impl Assistant {
         Ok(())
     }
 
+    /// 管理用に `Conversation` を ID で取得する。
+    pub async fn find_conversation(&self, id: &Uuid) -> Result<Option<Conversation>, AssistantError> {
+        let conversation = self.0.storage.find_by_id(id).await?;
+        Ok(conversation)
+    }
+
+    /// 管理用に永続化されている `Conversation` を新しい順に列挙する。
+    pub async fn list_conversations(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<ConversationEntry>, AssistantError> {
+        let entries = self.0.storage.list(limit, offset).await?;
+        Ok(entries)
+    }
+
+    /// 管理用に `Conversation` を削除する。存在した場合は true を返す。
+    pub async fn delete_conversation(&self, id: &Uuid) -> Result<bool, AssistantError> {
+        let deleted = self.0.storage.delete(id).await?;
+        Ok(deleted)
+    }
+
+    fn execute_command(
+        &self,
+        profile: &AssistantProfile,
+        mut conversation: Conversation,
+        command: AssistantCommand,
+    ) -> ConversationUpdate {
+        info!("executing command {command:?}");
+        let reply_text = match command {
+            AssistantCommand::Reset => {
+                conversation = profile.new_conversation(conversation.identity());
+                "会話をリセットしました。".to_string()
+            }
+            AssistantCommand::Identity(None) => {
+                let (current_name, _) = profile.resolve_identity(conversation.identity());
+                format!(
+                    "現在の identity: {current_name}\n利用可能な identity: {}",
+                    profile.identity_names().join(", ")
+                )
+            }
+            AssistantCommand::Identity(Some(name)) => match profile.identities.get(&name) {
+                Some(identity) => {
+                    let system_message = Message::new_system(identity.system_role.clone());
+                    conversation.switch_identity(&name, system_message);
+                    format!("identity を {name} に切り替えました。")
+                }
+                None => format!(
+                    "identity {name} は定義されていません。\n利用可能な identity: {}",
+                    profile.identity_names().join(", ")
+                ),
+            },
+            AssistantCommand::Tools => {
+                let mut tool_lines: Vec<_> = profile
+                    .simple_functions
+                    .values()
+                    .map(|registered| {
+                        let descriptor = registered.function.get_descriptor();
+                        let summary = descriptor
+                            .description
+                            .lines()
+                            .map(|l| l.trim())
+                            .find(|l| !l.is_empty())
+                            .unwrap_or_default()
+                            .to_string();
+                        format!("- {}: {summary}", descriptor.name)
+                    })
+                    .collect();
+                tool_lines.sort();
+                format!("利用可能な tool:\n{}", tool_lines.join("\n"))
+            }
+        };
+
+        let assistant_message = AssistantMessage {
+            text: reply_text,
+            is_sensitive: false,
+            language: None,
+        };
+        ConversationUpdate::without_history(conversation, assistant_message)
+    }
+
+    /// 設定された閾値を超えていたら古いターンを要約する。
+    /// 要約に失敗しても応答は続けられるので、その場合は元の `Conversation` をそのまま返す。
+    async fn summarize_if_needed(
+        &self,
+        llm: &dyn Llm,
+        mut conversation: Conversation,
+        summary_config: Option<&AppConfigAssistantSummary>,
+        turn_usage: &mut TurnUsage,
+    ) -> Conversation {
+        let Some(summary_config) = summary_config else {
+            return conversation;
+        };
+        let message_count = conversation
+            .messages()
+            .iter()
+            .filter(|m| !matches!(m, Message::System(_)))
+            .count();
+        if message_count <= summary_config.threshold_messages {
+            return conversation;
+        }
+
+        let targets = conversation.summary_targets(summary_config.keep_recent_turns);
+        if targets.is_empty() {
+            return conversation;
+        }
+        info!(
+            "summarizing {} message(s) of conversation {}",
+            targets.len(),
+            conversation.id()
+        );
+
+        // 元の会話とは別物として送る(Responses API の続きとして扱われないように ID も分ける)
+        let summary_request = IncompleteConversation {
+            id: Uuid::now_v7(),
+            latest_messages: vec![
+                Message::new_system(summary_config.prompt.clone()),
+                Message::new_user([UserMessageContent::Text(render_transcript(&targets))], None, None),
+            ],
+            identity: None,
+            use_tools: false,
+        };
+        match llm.send_conversation(&summary_request).await {
+            Ok(update) => {
+                turn_usage.add(update.usage);
+                match update.response {
+                    Some(response) => conversation.apply_summary(summary_config.keep_recent_turns, response.text),
+                    None => warn!("summary response not returned, keeping conversation as is"),
+                }
+            }
+            Err(err) => warn!("failed to summarize conversation: {err}"),
+        }
+        conversation
+    }
+
+    async fn send_conversation(
+        &self,
+        llm: &dyn Llm,
+        conversation: &IncompleteConversation,
+        text_sender: Option<&UnboundedSender<String>>,
+        sensitive_marker: &str,
+    ) -> Result<LlmUpdate, AssistantError> {
+        let started_at = Instant::now();
+        let result = Self::send_conversation_inner(llm, conversation, text_sender, sensitive_marker).await;
+        // フォールバックで実際に応答したモデルがわかるように、返ってきた usage のモデルで分ける
+        // 失敗したときはどのモデルまで試したかわからないので unknown にする
+        let (model, label) = match &result {
+            Ok(update) => (update.usage.as_ref().map_or("unknown", |u| u.model.as_str()), "ok"),
+            Err(_) => ("unknown", "error"),
+        };
+        METRICS
+            .llm_latency
+            .with_label_values(&[model, label])
+            .observe(started_at.elapsed().as_secs_f64());
+        result
+    }
+
+    async fn send_conversation_inner(
+        llm: &dyn Llm,
+        conversation: &IncompleteConversation,
+        text_sender: Option<&UnboundedSender<String>>,
+        sensitive_marker: &str,
+    ) -> Result<LlmUpdate, AssistantError> {
+        let Some(text_sender) = text_sender else {
+            return Ok(llm.send_conversation(conversation).await?);
+        };
+
+        // sensitive marker を表示しないように、付いているかどうか判断できるまで先頭を溜めておく
+        let mut head = Some(String::new());
+        let mut llm_stream = llm.send_conversation_stream(conversation);
+        while let Some(event) = llm_stream.try_next().await? {
+            match event {
+                LlmStreamEvent::TextDelta(delta) => {
+                    let text = match &mut head {
+                        Some(buffer) => {
+                            buffer.push_str(&delta);
+                            if buffer.len() < sensitive_marker.len() && sensitive_marker.starts_with(buffer.as_str()) {
+                                continue;
+                            }
+                            let text = buffer.strip_prefix(sensitive_marker).unwrap_or(buffer).to_string();
+                            head = None;
+                            text
+                        }
+                        None => delta,
+                    };
+                    // 受信側がいなくなっても生成自体は続ける
+                    if !text.is_empty() {
+                        text_sender.send(text).ok();
+                    }
+                }
+                LlmStreamEvent::ToolCallingDelta(delta) => {
+                    debug!(
+                        "tool calling delta #{} {:?} (id: {:?}): {}",
+                        delta.index, delta.name, delta.id, delta.arguments
+                    );
+                }
+                LlmStreamEvent::Finished(update) => return Ok(update),
+            }
+        }
+        Err(LlmError::NoChoice.into())
+    }
+
+    /// tool calling を処理する。
+    /// 失敗した呼び出しや存在しない tool もエラー内容を持つ `FunctionResponseMessage` として必ず応答する。
     async fn process_tool_callings(
         &self,
+        profile: &AssistantProfile,
+        origin: &ConversationOrigin,
+        economy: bool,
         tool_callings: Vec<MessageFunctionCall>,
-    ) -> Result<(Vec<FunctionResponseMessage>, Vec<ConversationAttachment>), AssistantError> {
-        let locked = self.0.simple_functions.lock().await;
+    ) -> (Vec<FunctionResponseMessage>, Vec<ConversationAttachment>) {
+        // 同一バッチ内の呼び出しは並行に実行し、結果は元の順序で返す
+        let call_futures = tool_callings.into_iter().map(|tool_calling| {
+            // MCP と複合するのをあとで考える
+            let registered = profile
+                .simple_functions
+                .get(&tool_calling.name)
+                .filter(|_| !(economy && profile.is_economy_disabled(&tool_calling.name)))
+                .cloned();
+            async move {
+                let result = match registered {
+                    Some(registered) => {
+                        if self.check_tool_limit(profile, origin, &tool_calling.name).await {
+                            Self::call_simple_function(&tool_calling, registered).await
+                        } else {
+                            info!(
+                                "tool {} for {}:{} rate limited",
+                                tool_calling.name, origin.platform, origin.user
+                            );
+                            SimpleFunctionResponse::error("daily limit for this tool reached, try again tomorrow")
+                        }
+                    }
+                    None => {
+                        warn!("tool {} not found", tool_calling.name);
+                        SimpleFunctionResponse::error(format!("tool {} not found", tool_calling.name))
+                    }
+                };
+                let response = FunctionResponseMessage {
+                    id: tool_calling.id,
+                    name: tool_calling.name,
+                    result: result.result,
+                };
+                (response, result.attachments)
+            }
+        });
 
         let mut responses = vec![];
         let mut attachments = vec![];
-        for tool_calling in tool_callings {
-            info!("calling tool {} (id: {})", tool_calling.name, tool_calling.id);
-            // MCP と複合するのをあとで考える
-            let Some(simple_function) = locked.get(&tool_calling.name) else {
-                warn!("tool {} not found, skipping", tool_calling.name);
-                continue;
+        for (response, call_attachments) in join_all(call_futures).await {
+            responses.push(response);
+            attachments.extend(call_attachments);
+        }
+
+        (responses, attachments)
+    }
+
+    /// 1 ターン分のトークン使用量を記録する。記録に失敗しても応答は返せるので警告に留める。
+    async fn record_usage(&self, origin: &ConversationOrigin, conversation_id: Uuid, turn_usage: TurnUsage) {
+        let recorded_at = unix_now();
+        for usage in turn_usage.0 {
+            let record = UsageRecord {
+                platform: origin.platform.clone(),
+                user: origin.user.clone(),
+                conversation_id,
+                model: usage.model,
+                tokens: usage.tokens,
+                recorded_at,
             };
-            let result = simple_function.call(&tool_calling.id, tool_calling.arguments).await?;
-            responses.push(FunctionResponseMessage {
-                id: tool_calling.id,
-                name: tool_calling.name,
-                result: result.result,
-            });
-            attachments.extend(result.attachments);
+            debug!("recording usage: {record:?}");
+            for (kind, count) in [
+                ("prompt", record.tokens.prompt_tokens),
+                ("completion", record.tokens.completion_tokens),
+                ("cached", record.tokens.cached_tokens),
+            ] {
+                METRICS.tokens.with_label_values(&[&record.model, kind]).inc_by(count);
+            }
+            if let Err(err) = self.0.storage.record_usage(&record).await {
+                warn!("failed to record usage: {err}");
+            }
+        }
+    }
+
+    /// 全体とユーザーごとの利用料金を集計し、上限に対する状態を返す。
+    /// 集計に失敗した場合は応答を止めないよう、警告して上限に達していないものとして扱う。
+    async fn check_budget(&self, profile: &AssistantProfile, origin: &ConversationOrigin) -> BudgetStatus {
+        let Some(budget) = &profile.budget else {
+            return BudgetStatus::Normal;
+        };
+
+        let today = OffsetDateTime::now_utc().date();
+        let day_start = today.midnight().assume_utc().unix_timestamp() as u64;
+        let month_start = today
+            .replace_day(1)
+            .expect("first day of month must exist")
+            .midnight()
+            .assume_utc()
+            .unix_timestamp() as u64;
+
+        let mut status = BudgetStatus::Normal;
+        for (limit, user) in [(&budget.global, None), (&budget.per_user, Some(origin))] {
+            for (limit, since) in [(limit.daily, day_start), (limit.monthly, month_start)] {
+                let Some(limit) = limit else {
+                    continue;
+                };
+                let usages = match self.0.storage.usage_by_model(since, user).await {
+                    Ok(usages) => usages,
+                    Err(err) => {
+                        warn!("failed to aggregate usage, treating as within budget: {err}");
+                        return BudgetStatus::Normal;
+                    }
+                };
+                let spent: f64 = usages
+                    .iter()
+                    .filter_map(|u| budget.prices.get(&u.model).map(|p| u.tokens.cost(p)))
+                    .sum();
+                status = status.max(BudgetStatus::evaluate(spent, limit, budget.economy_ratio));
+            }
+        }
+
+        if status != BudgetStatus::Normal {
+            debug!("budget status for {}:{}: {status:?}", origin.platform, origin.user);
+        }
+        status
+    }
+
+    /// ユーザーのリクエスト数を数え、制限以内であれば true を返す。
+    async fn check_request_limit(&self, profile: &AssistantProfile, origin: &ConversationOrigin) -> bool {
+        let rule = profile.rate_limit.rule_for(&origin.platform);
+        let Some(limit) = rule.requests_per_minute else {
+            return true;
+        };
+
+        let key = format!("requests:{}:{}", origin.platform, origin.user);
+        self.count_up(&key, RATE_LIMIT_MINUTE, limit).await
+    }
+
+    /// ユーザーの tool 呼び出し回数を数え、制限以内であれば true を返す。
+    async fn check_tool_limit(&self, profile: &AssistantProfile, origin: &ConversationOrigin, tool_name: &str) -> bool {
+        let rule = profile.rate_limit.rule_for(&origin.platform);
+        let Some(&limit) = rule.tool_calls_per_day.get(tool_name) else {
+            return true;
+        };
+
+        let key = format!("tool:{tool_name}:{}:{}", origin.platform, origin.user);
+        self.count_up(&key, RATE_LIMIT_DAY, limit).await
+    }
+
+    /// 固定期間のカウンタを進め、`limit` 以内であれば true を返す。
+    /// カウンタが壊れていても応答は止めたくないので、失敗した場合は警告して許可する。
+    async fn count_up(&self, key: &str, window: Duration, limit: u64) -> bool {
+        let now = unix_now();
+        let window_start = now - now % window.as_secs();
+        match self.0.storage.increment_counter(key, window_start).await {
+            Ok(count) => count <= limit,
+            Err(err) => {
+                warn!("failed to count up {key}, allowing: {err}");
+                true
+            }
+        }
+    }
+
+    async fn call_simple_function(
+        tool_calling: &MessageFunctionCall,
+        registered: RegisteredSimpleFunction,
+    ) -> SimpleFunctionResponse {
+        info!("calling tool {} (id: {})", tool_calling.name, tool_calling.id);
+        METRICS.tool_calls.with_label_values(&[&tool_calling.name]).inc();
+        let call_future = registered
+            .function
+            .call(&tool_calling.id, tool_calling.arguments.clone());
+        match timeout(registered.timeout, call_future).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(err)) => {
+                warn!("tool {} (id: {}) failed: {err}", tool_calling.name, tool_calling.id);
+                METRICS.tool_failures.with_label_values(&[&tool_calling.name]).inc();
+                SimpleFunctionResponse::error(err.to_string())
+            }
+            Err(_) => {
+                warn!("tool {} (id: {}) timed out", tool_calling.name, tool_calling.id);
+                METRICS.tool_failures.with_label_values(&[&tool_calling.name]).inc();
+                SimpleFunctionResponse::error(format!(
+                    "timed out after {} second(s)",
+                    registered.timeout.as_secs_f64()
+                ))
+            }
+        }
+    }
+}
+
+/// 利用料金の上限に対する状態。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum BudgetStatus {
+    Normal,
+
+    /// 上限が近いので節約モードで応答する。
+    Economy,
+
+    /// 上限に達したので応答しない。
+    Exhausted,
+}
+
+impl BudgetStatus {
+    fn evaluate(spent: f64, limit: f64, economy_ratio: f64) -> BudgetStatus {
+        if spent >= limit {
+            BudgetStatus::Exhausted
+        } else if spent >= limit * economy_ratio {
+            BudgetStatus::Economy
+        } else {
+            BudgetStatus::Normal
+        }
+    }
+}
+
+/// 1 ターン中に消費されたトークンのモデルごとの合計。
+#[derive(Debug, Default)]
+struct TurnUsage(Vec<LlmUsage>);
+
+impl TurnUsage {
+    fn add(&mut self, usage: Option<LlmUsage>) {
+        let Some(usage) = usage else {
+            return;
+        };
+        match self.0.iter_mut().find(|u| u.model == usage.model) {
+            Some(existing) => existing.tokens += usage.tokens,
+            None => self.0.push(usage),
         }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
-        Ok((responses, attachments))
+/// 要約させるための会話の書き起こしを生成する。
+fn render_transcript(messages: &[&Message]) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        let line = match message {
+            Message::System(_) => continue,
+            Message::Summary(summary_message) => format!("(これまでの要約) {}", summary_message.0),
+            Message::User(user_message) => {
+                let contents: Vec<_> = user_message
+                    .contents
+                    .iter()
+                    .map(|umc| match umc {
+                        UserMessageContent::Text(text) => text.clone(),
+                        UserMessageContent::ImageUrl(_) => "(画像)".to_string(),
+                    })
+                    .collect();
+                format!("ユーザー: {}", contents.join(" "))
+            }
+            Message::Assistant(assistant_message) => format!("アシスタント: {}", assistant_message.text),
+            Message::FunctionCalls(function_calls_message) => {
+                let names: Vec<_> = function_calls_message.0.iter().map(|c| c.name.as_str()).collect();
+                format!("(tool 呼び出し: {})", names.join(", "))
+            }
+            Message::FunctionResponse(function_response_message) => format!(
+                "(tool {} の結果: {})",
+                function_response_message.name, function_response_message.result
+            ),
+        };
+        transcript.push_str(&line);
+        transcript.push('\n');
     }
+    transcript
 }
 
 #[derive(Debug)]
 struct AssistantInner {
-    llm: Box<dyn Llm + 'static>,
     storage: Box<dyn ConversationStorage + 'static>,
-    simple_functions: Mutex<HashMap<String, Box<dyn SimpleFunction + 'static>>>,
-    system_role: String,
-    sensitive_marker: String,
+    profile: RwLock<Arc<AssistantProfile>>,
+}
+
+/// 設定から構築される、`Assistant` の差し替え可能な部分。
+/// 設定の再読み込み時にはまるごと作り直して `Assistant::replace_profile` で差し替える。
+#[derive(Debug)]
+pub struct AssistantProfile {
+    llm: Box<dyn Llm + 'static>,
+    simple_functions: HashMap<String, RegisteredSimpleFunction>,
+    identities: HashMap<String, AppConfigAssistantIdentity>,
+    default_identity: String,
+    rate_limit: AppConfigRateLimit,
+    budget: Option<AppConfigBudget>,
+    economy_llm: Option<Box<dyn Llm + 'static>>,
+    hooks: Vec<Box<dyn AssistantHook + 'static>>,
+}
+
+impl AssistantProfile {
+    pub fn new(config_assistant: &AppConfigAssistant, llm: Box<dyn Llm + 'static>) -> AssistantProfile {
+        AssistantProfile {
+            llm,
+            simple_functions: HashMap::new(),
+            identities: config_assistant.identities.clone(),
+            default_identity: config_assistant.identity.clone(),
+            rate_limit: config_assistant.rate_limit.clone(),
+            budget: config_assistant.budget.clone(),
+            economy_llm: None,
+            hooks: vec![],
+        }
+    }
+
+    /// 節約モードで利用する LLM を設定する。tool の登録より前に呼ぶこと。
+    pub fn with_economy_llm(mut self, economy_llm: Box<dyn Llm + 'static>) -> AssistantProfile {
+        self.economy_llm = Some(economy_llm);
+        self
+    }
+
+    /// `SimpleFunction` を登録する。
+    /// `timeout` を超えて実行が続いた呼び出しはエラーとして LLM に返される。
+    pub async fn add_simple_function(&mut self, simple_function: impl SimpleFunction + 'static, timeout: Duration) {
+        let descriptor = simple_function.get_descriptor();
+        let registered = RegisteredSimpleFunction {
+            function: Arc::new(simple_function),
+            timeout,
+        };
+
+        self.simple_functions.insert(descriptor.name.clone(), registered);
+        if let Some(economy_llm) = &self.economy_llm
+            && !self.is_economy_disabled(&descriptor.name)
+        {
+            economy_llm.add_simple_function(descriptor.clone()).await;
+        }
+        self.llm.add_simple_function(descriptor).await;
+    }
+
+    /// `AssistantHook` を登録する。フックは登録順に呼ばれる。
+    pub fn add_hook(&mut self, hook: impl AssistantHook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    fn llm_for(&self, economy: bool) -> &dyn Llm {
+        match &self.economy_llm {
+            Some(economy_llm) if economy => economy_llm.as_ref(),
+            _ => self.llm.as_ref(),
+        }
+    }
+
+    /// 節約モードで無効にされている tool かどうか。
+    fn is_economy_disabled(&self, tool_name: &str) -> bool {
+        self.budget
+            .as_ref()
+            .is_some_and(|b| b.economy_disabled_tools.iter().any(|t| t == tool_name))
+    }
+
+    fn new_conversation(&self, identity: Option<&str>) -> Conversation {
+        let (identity_name, identity) = self.resolve_identity(identity);
+        let system_message = Message::new_system(identity.system_role.clone());
+        Conversation::new_now(identity_name, Some(system_message))
+    }
+
+    /// identity 名から設定を引く。未指定や未定義の場合はデフォルトの identity になる。
+    fn resolve_identity(&self, name: Option<&str>) -> (&str, &AppConfigAssistantIdentity) {
+        if let Some(name) = name {
+            match self.identities.get_key_value(name) {
+                Some((name, identity)) => return (name, identity),
+                None => warn!("assistant identity {name} not defined, using default"),
+            }
+        }
+
+        let (name, identity) = self
+            .identities
+            .get_key_value(&self.default_identity)
+            .expect("default identity must be defined");
+        (name, identity)
+    }
+
+    fn identity_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.identities.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        names
+    }
+}
+
+/// 登録済みの `SimpleFunction` とその実行設定。
+#[derive(Debug, Clone)]
+struct RegisteredSimpleFunction {
+    function: Arc<dyn SimpleFunction + 'static>,
+    timeout: Duration,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        error::FunctionError,
+        impls::{function::SelfInfo, llm::create_llm, storage::create_storage},
+        model::{
+            config::{AppConfigLlm, AppConfigStorage},
+            schema::DescribedSchema,
+            usage::TokenUsage,
+        },
+        specs::function::simple::SimpleFunctionDescriptor,
+    };
+
+    use std::io::Write;
+
+    use futures::{FutureExt, future::BoxFuture};
+    use serde_json::{Value, json};
+    use tokio::time::sleep;
+
+    const SCRIPT: &str = r#"
+[[rules]]
+pattern = "バージョン"
+round = 0
+text = "ちょっと調べるッス"
+tool_calls = [{ name = "self_info" }]
+
+[[rules]]
+pattern = "バージョン"
+round = 1
+text = "最新ッス"
+language = "ja"
+
+[[rules]]
+pattern = "壊れて"
+error = { kind = "backend", message = "scripted failure" }
+
+[[rules]]
+pattern = "調べ続けて"
+tool_calls = [{ name = "self_info" }]
+
+[[rules]]
+pattern = "まとめて"
+round = 0
+tool_calls = [
+    { name = "sleep", arguments = { ms = 300 } },
+    { name = "sleep", arguments = { ms = 10 } },
+    { name = "sleep", arguments = { ms = 300 } },
+]
+
+[[rules]]
+pattern = "待って"
+round = 0
+tool_calls = [{ name = "sleep", arguments = { ms = 10000 } }]
+
+[[rules]]
+pattern = "まとめて|待って"
+round = 1
+text = "終わったッス"
+
+[[rules]]
+pattern = "存在しない"
+round = 0
+tool_calls = [{ name = "missing_tool" }]
+
+[[rules]]
+pattern = "存在しない"
+round = 1
+text = "そんな tool ないッス"
+"#;
+
+    const ASSISTANT_CONFIG: &str = r#"
+identity = "test"
+
+[identities.test]
+system_role = "テスト用のアシスタントです。"
+
+[identities.other]
+system_role = "別のアシスタントです。"
+"#;
+
+    /// 引数 `ms` だけ待ってから、待った時間を返す tool。
+    #[derive(Debug)]
+    struct Sleep;
+
+    impl SimpleFunction for Sleep {
+        fn get_descriptor(&self) -> SimpleFunctionDescriptor {
+            SimpleFunctionDescriptor {
+                name: "sleep".to_string(),
+                description: "指定された時間だけ待つ。".to_string(),
+                parameters: DescribedSchema::object("parameters", "引数", vec![]),
+            }
+        }
+
+        fn call<'a>(
+            &'a self,
+            _id: &str,
+            params: Value,
+        ) -> BoxFuture<'a, Result<SimpleFunctionResponse, FunctionError>> {
+            async move {
+                let ms = params["ms"].as_u64().unwrap_or_default();
+                sleep(Duration::from_millis(ms)).await;
+                Ok(SimpleFunctionResponse {
+                    result: json!({ "slept_ms": ms }),
+                    ..Default::default()
+                })
+            }
+            .boxed()
+        }
+    }
+
+    /// フックを割り込ませる段階。
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum HookStage {
+        PreSend,
+        PostLlm,
+        PostResponse,
+    }
+
+    /// 指定した段階で処理を打ち切って応答するフック。
+    #[derive(Debug)]
+    struct ReplyAt(HookStage);
+
+    impl ReplyAt {
+        fn action(&self, stage: HookStage) -> Result<HookAction, AssistantError> {
+            if self.0 != stage {
+                return Ok(HookAction::Continue);
+            }
+            Ok(HookAction::Reply(AssistantMessage {
+                text: format!("{stage:?} で打ち切ったッス"),
+                is_sensitive: false,
+                language: None,
+            }))
+        }
+    }
+
+    impl AssistantHook for ReplyAt {
+        fn pre_send<'a>(
+            &'a self,
+            _conversation: &'a mut IncompleteConversation,
+        ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+            async move { self.action(HookStage::PreSend) }.boxed()
+        }
+
+        fn post_llm<'a>(
+            &'a self,
+            _conversation: &'a IncompleteConversation,
+            _update: &'a mut LlmUpdate,
+        ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+            async move { self.action(HookStage::PostLlm) }.boxed()
+        }
+
+        fn post_response<'a>(
+            &'a self,
+            _conversation: &'a IncompleteConversation,
+            _message: &'a mut AssistantMessage,
+        ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+            async move { self.action(HookStage::PostResponse) }.boxed()
+        }
+    }
+
+    /// mock バックエンドで `script` に従って応答する profile を作る。
+    pub(crate) async fn mock_profile(script: &str) -> AssistantProfile {
+        mock_profile_with_config(script, ASSISTANT_CONFIG).await
+    }
+
+    /// `mock_profile` と同様だが、`[assistant]` の設定を `assistant_config` で与える。
+    /// 節約モード用のモデルが指定されていれば、同じスクリプトでモデル名だけを変えた mock を使う。
+    pub(crate) async fn mock_profile_with_config(script: &str, assistant_config: &str) -> AssistantProfile {
+        let mut script_file = tempfile::Builder::new()
+            .suffix(".toml")
+            .tempfile()
+            .expect("failed to create script");
+        script_file
+            .write_all(script.as_bytes())
+            .expect("failed to write script");
+        let config_llm: AppConfigLlm = toml::from_str(&format!(
+            "backend = \"mock\"\nmock = {{ script = '{}' }}",
+            script_file.path().display()
+        ))
+        .expect("invalid llm config");
+        let llm = create_llm(&config_llm).await.expect("failed to load script");
+
+        let config_assistant: AppConfigAssistant = toml::from_str(assistant_config).expect("invalid assistant config");
+        let mut profile = AssistantProfile::new(&config_assistant, llm);
+        if let Some(model) = config_assistant.budget.as_ref().and_then(|b| b.economy_model.as_ref()) {
+            let economy_llm = create_llm(&config_llm.with_model(model))
+                .await
+                .expect("failed to load script");
+            profile = profile.with_economy_llm(economy_llm);
+        }
+        profile
+            .add_simple_function(SelfInfo::new(), Duration::from_secs(5))
+            .await;
+        profile
+    }
+
+    /// メモリ上のストレージで `Assistant` を作る。
+    pub(crate) async fn create_assistant(profile: AssistantProfile) -> Assistant {
+        let config_storage: AppConfigStorage =
+            toml::from_str("backend = \"memory\"\nsqlite = { filepath = \"\" }").expect("invalid storage config");
+        let storage = create_storage(&config_storage).await.expect("failed to create storage");
+        Assistant::new(profile, storage)
+    }
+
+    fn user_message(text: &str) -> UserMessage {
+        UserMessage {
+            contents: vec![UserMessageContent::Text(text.to_string())],
+            ..Default::default()
+        }
+    }
+
+    /// 1 ターン分の履歴がある `Conversation` を作る。
+    async fn conversation_with_history(assistant: &Assistant, origin: &ConversationOrigin) -> Conversation {
+        let conversation = assistant.new_conversation(None);
+        assistant
+            .process_conversation(conversation, user_message("今のバージョンは？"), origin)
+            .await
+            .expect("conversation failed")
+            .finish()
+    }
+
+    /// `update` を確定しても `before` の履歴から変わっていないことを確かめる。
+    fn assert_history_unchanged(update: ConversationUpdate, before: &Conversation) {
+        let after = update.finish();
+        assert_eq!(after.id(), before.id());
+        assert_eq!(json!(after.messages()), json!(before.messages()));
+    }
+
+    /// `origin` がすでに今日 `prompt_tokens` だけ mock のトークンを使ったことにする。
+    async fn record_spent(assistant: &Assistant, origin: &ConversationOrigin, prompt_tokens: u64) {
+        let record = UsageRecord {
+            platform: origin.platform.clone(),
+            user: origin.user.clone(),
+            conversation_id: Uuid::now_v7(),
+            model: "mock".to_string(),
+            tokens: TokenUsage {
+                prompt_tokens,
+                ..Default::default()
+            },
+            recorded_at: unix_now(),
+        };
+        assistant
+            .0
+            .storage
+            .record_usage(&record)
+            .await
+            .expect("failed to record usage");
+    }
+
+    fn function_responses(conversation: &Conversation) -> Vec<&FunctionResponseMessage> {
+        conversation
+            .messages()
+            .iter()
+            .filter_map(|m| match m {
+                Message::FunctionResponse(response) => Some(response),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn processes_scripted_tool_call_round() {
+        let assistant = create_assistant(mock_profile(SCRIPT).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = assistant.new_conversation(None);
+        let update = assistant
+            .process_conversation(conversation, user_message("今のバージョンは？"), &origin)
+            .await
+            .expect("conversation failed");
+        assert_eq!(update.assistant_message().text, "最新ッス");
+        assert_eq!(update.assistant_message().language.as_deref(), Some("ja"));
+
+        let conversation = update.finish();
+        let messages = conversation.messages();
+        let calls = messages.iter().find_map(|m| match m {
+            Message::FunctionCalls(calls) => Some(calls),
+            _ => None,
+        });
+        let call = calls.and_then(|c| c.0.first()).expect("tool call not recorded");
+        assert_eq!(call.name, "self_info");
+
+        let response = messages.iter().find_map(|m| match m {
+            Message::FunctionResponse(response) => Some(response),
+            _ => None,
+        });
+        let response = response.expect("tool response not recorded");
+        assert_eq!(response.id, call.id);
+        assert_eq!(response.result["bot_version"], env!("CARGO_PKG_VERSION"));
+        assert!(matches!(messages.last(), Some(Message::Assistant(_))));
+    }
+
+    #[tokio::test]
+    async fn propagates_scripted_error() {
+        let assistant = create_assistant(mock_profile(SCRIPT).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = assistant.new_conversation(None);
+        let result = assistant
+            .process_conversation(conversation, user_message("壊れてる？"), &origin)
+            .await;
+        match result {
+            Err(AssistantError::Llm(LlmError::Backend(err))) => assert_eq!(err.to_string(), "scripted failure"),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_tool_rounds_exceeded() {
+        let config = r#"
+identity = "test"
+
+[identities.test]
+system_role = "テスト用のアシスタントです。"
+max_tool_rounds = 2
+tool_rounds_exceeded_message = "調べすぎたッス"
+"#;
+        let assistant = create_assistant(mock_profile_with_config(SCRIPT, config).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = assistant.new_conversation(None);
+        let update = assistant
+            .process_conversation(conversation, user_message("調べ続けて"), &origin)
+            .await
+            .expect("conversation failed");
+        assert_eq!(update.assistant_message().text, "調べすぎたッス");
+
+        let conversation = update.finish();
+        let messages = conversation.messages();
+        let call_rounds = messages
+            .iter()
+            .filter(|m| matches!(m, Message::FunctionCalls(_)))
+            .count();
+        assert_eq!(call_rounds, 2);
+        match messages.last() {
+            Some(Message::Assistant(message)) => assert_eq!(message.text, "調べすぎたッス"),
+            other => panic!("unexpected last message: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_when_tool_rounds_exceeded_without_fallback() {
+        let config = r#"
+identity = "test"
+
+[identities.test]
+system_role = "テスト用のアシスタントです。"
+max_tool_rounds = 2
+"#;
+        let assistant = create_assistant(mock_profile_with_config(SCRIPT, config).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = assistant.new_conversation(None);
+        let result = assistant
+            .process_conversation(conversation, user_message("調べ続けて"), &origin)
+            .await;
+        match result {
+            Err(AssistantError::ToolRoundsExceeded(rounds)) => assert_eq!(rounds, 2),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_call_order_of_concurrent_tools() {
+        let mut profile = mock_profile(SCRIPT).await;
+        profile.add_simple_function(Sleep, Duration::from_secs(5)).await;
+        let assistant = create_assistant(profile).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = assistant.new_conversation(None);
+        let started_at = Instant::now();
+        let update = assistant
+            .process_conversation(conversation, user_message("まとめて待って"), &origin)
+            .await
+            .expect("conversation failed");
+        // 順に実行していれば 610 ms はかかる
+        assert!(started_at.elapsed() < Duration::from_millis(600));
+        assert_eq!(update.assistant_message().text, "終わったッス");
+
+        let conversation = update.finish();
+        let calls = conversation.messages().iter().find_map(|m| match m {
+            Message::FunctionCalls(calls) => Some(calls),
+            _ => None,
+        });
+        let call_ids: Vec<_> = calls
+            .expect("tool calls not recorded")
+            .0
+            .iter()
+            .map(|c| &c.id)
+            .collect();
+        let responses = function_responses(&conversation);
+        let response_ids: Vec<_> = responses.iter().map(|r| &r.id).collect();
+        assert_eq!(response_ids, call_ids);
+        let slept: Vec<_> = responses.iter().map(|r| r.result["slept_ms"].as_u64()).collect();
+        assert_eq!(slept, [Some(300), Some(10), Some(300)]);
+    }
+
+    #[tokio::test]
+    async fn reports_timed_out_tool_as_error() {
+        let mut profile = mock_profile(SCRIPT).await;
+        profile.add_simple_function(Sleep, Duration::from_millis(50)).await;
+        let assistant = create_assistant(profile).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = assistant.new_conversation(None);
+        let started_at = Instant::now();
+        let update = assistant
+            .process_conversation(conversation, user_message("待って"), &origin)
+            .await
+            .expect("conversation failed");
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert_eq!(update.assistant_message().text, "終わったッス");
+
+        let conversation = update.finish();
+        let responses = function_responses(&conversation);
+        assert_eq!(responses.len(), 1);
+        let error = responses[0].result["error"].as_str().expect("error not returned");
+        assert!(error.starts_with("timed out"), "unexpected error: {error}");
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_tool_to_model() {
+        let assistant = create_assistant(mock_profile(SCRIPT).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = assistant.new_conversation(None);
+        let update = assistant
+            .process_conversation(conversation, user_message("存在しない tool を使って"), &origin)
+            .await
+            .expect("conversation failed");
+        assert_eq!(update.assistant_message().text, "そんな tool ないッス");
+
+        let conversation = update.finish();
+        let responses = function_responses(&conversation);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].name, "missing_tool");
+        assert_eq!(responses[0].result, json!({ "error": "tool missing_tool not found" }));
+    }
+
+    #[tokio::test]
+    async fn lists_tools_without_history() {
+        let assistant = create_assistant(mock_profile(SCRIPT).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = conversation_with_history(&assistant, &origin).await;
+        let update = assistant
+            .process_conversation(conversation.clone(), user_message("!tools"), &origin)
+            .await
+            .expect("command failed");
+        assert_eq!(
+            update.assistant_message().text,
+            "利用可能な tool:\n- self_info: この bot 自身に関する以下の情報を提供する。"
+        );
+        assert_history_unchanged(update, &conversation);
+    }
+
+    #[tokio::test]
+    async fn shows_and_switches_identity_without_history() {
+        let assistant = create_assistant(mock_profile(SCRIPT).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = conversation_with_history(&assistant, &origin).await;
+        let update = assistant
+            .process_conversation(conversation.clone(), user_message("!identity"), &origin)
+            .await
+            .expect("command failed");
+        assert_eq!(
+            update.assistant_message().text,
+            "現在の identity: test\n利用可能な identity: other, test"
+        );
+        assert_history_unchanged(update, &conversation);
+
+        let update = assistant
+            .process_conversation(conversation.clone(), user_message("!identity missing"), &origin)
+            .await
+            .expect("command failed");
+        assert!(
+            update
+                .assistant_message()
+                .text
+                .starts_with("identity missing は定義されていません。")
+        );
+        assert_history_unchanged(update, &conversation);
+
+        // system message だけが差し替えられ、応答は履歴に残らない
+        let update = assistant
+            .process_conversation(conversation.clone(), user_message("!identity other"), &origin)
+            .await
+            .expect("command failed");
+        assert_eq!(update.assistant_message().text, "identity を other に切り替えました。");
+        let switched = update.finish();
+        assert_eq!(switched.identity(), Some("other"));
+        assert_eq!(
+            json!(switched.messages()[0]),
+            json!(Message::new_system("別のアシスタントです。"))
+        );
+        assert_eq!(json!(switched.messages()[1..]), json!(conversation.messages()[1..]));
+    }
+
+    #[tokio::test]
+    async fn resets_conversation_without_history() {
+        let assistant = create_assistant(mock_profile(SCRIPT).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = conversation_with_history(&assistant, &origin).await;
+        let update = assistant
+            .process_conversation(conversation.clone(), user_message("!reset"), &origin)
+            .await
+            .expect("command failed");
+        assert_eq!(update.assistant_message().text, "会話をリセットしました。");
+        let reset = update.finish();
+        assert_ne!(reset.id(), conversation.id());
+        assert_eq!(reset.identity(), Some("test"));
+        assert_eq!(
+            json!(reset.messages()),
+            json!([Message::new_system("テスト用のアシスタントです。")])
+        );
+    }
+
+    const BUDGET_CONFIG: &str = r#"
+identity = "test"
+
+[identities.test]
+system_role = "テスト用のアシスタントです。"
+out_of_energy_message = "電池切れッス"
+
+[budget]
+per_user = { daily = 1.0 }
+economy_model = "mock-economy"
+
+[budget.prices]
+mock = { prompt = 1.0, completion = 1.0 }
+"#;
+
+    #[tokio::test]
+    async fn rate_limits_requests_without_history() {
+        let config = r#"
+identity = "test"
+
+[identities.test]
+system_role = "テスト用のアシスタントです。"
+rate_limited_message = "休ませてほしいッス"
+
+[rate_limit]
+requests_per_minute = 1
+"#;
+        let assistant = create_assistant(mock_profile_with_config(SCRIPT, config).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = conversation_with_history(&assistant, &origin).await;
+        let update = assistant
+            .process_conversation(conversation.clone(), user_message("今のバージョンは？"), &origin)
+            .await
+            .expect("conversation failed");
+        assert_eq!(update.assistant_message().text, "休ませてほしいッス");
+        assert_history_unchanged(update, &conversation);
+
+        // 制限はユーザーごとに数える
+        let other_origin = ConversationOrigin::new("test", "other");
+        let update = assistant
+            .process_conversation(conversation, user_message("今のバージョンは？"), &other_origin)
+            .await
+            .expect("conversation failed");
+        assert_eq!(update.assistant_message().text, "最新ッス");
+    }
+
+    #[tokio::test]
+    async fn switches_to_economy_llm_near_budget() {
+        let assistant = create_assistant(mock_profile_with_config(SCRIPT, BUDGET_CONFIG).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+        let other_origin = ConversationOrigin::new("test", "other");
+        record_spent(&assistant, &origin, 900_000).await;
+
+        for origin in [&origin, &other_origin] {
+            let conversation = assistant.new_conversation(None);
+            let update = assistant
+                .process_conversation(conversation, user_message("今のバージョンは？"), origin)
+                .await
+                .expect("conversation failed");
+            assert_eq!(update.assistant_message().text, "最新ッス");
+        }
+
+        // 上限に近いユーザーだけが節約モードのモデルで応答される
+        for (origin, economy) in [(&origin, true), (&other_origin, false)] {
+            let usages = assistant
+                .0
+                .storage
+                .usage_by_model(0, Some(origin))
+                .await
+                .expect("failed to aggregate usage");
+            let economy_used = usages.iter().any(|u| u.model == "mock-economy");
+            assert_eq!(economy_used, economy, "economy model usage of {}", origin.user);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_over_budget_without_history() {
+        let assistant = create_assistant(mock_profile_with_config(SCRIPT, BUDGET_CONFIG).await).await;
+        let origin = ConversationOrigin::new("test", "user");
+
+        let conversation = conversation_with_history(&assistant, &origin).await;
+        record_spent(&assistant, &origin, 1_000_000).await;
+        let update = assistant
+            .process_conversation(conversation.clone(), user_message("今のバージョンは？"), &origin)
+            .await
+            .expect("conversation failed");
+        assert_eq!(update.assistant_message().text, "電池切れッス");
+        assert_history_unchanged(update, &conversation);
+    }
+
+    #[tokio::test]
+    async fn hook_reply_short_circuits_without_history() {
+        let origin = ConversationOrigin::new("test", "user");
+        let plain_assistant = create_assistant(mock_profile(SCRIPT).await).await;
+        let conversation = conversation_with_history(&plain_assistant, &origin).await;
+
+        for stage in [HookStage::PreSend, HookStage::PostLlm, HookStage::PostResponse] {
+            let mut profile = mock_profile(SCRIPT).await;
+            profile.add_hook(ReplyAt(stage));
+            let assistant = create_assistant(profile).await;
+
+            let update = assistant
+                .process_conversation(conversation.clone(), user_message("今のバージョンは？"), &origin)
+                .await
+                .expect("conversation failed");
+            assert_eq!(update.assistant_message().text, format!("{stage:?} で打ち切ったッス"));
+            assert_history_unchanged(update, &conversation);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Debug, Clone, Parser)]
 #[clap(author, version)]
@@ -8,4 +8,17 @@ pub struct Arguments {
     /// Specify path for config file.
     #[clap(short, long, default_value = "./config.toml")]
     pub config: PathBuf,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Show daily token usage recorded in the storage and exit.
+    UsageReport {
+        /// Number of days to include (UTC, including today).
+        #[clap(short, long, default_value_t = 7)]
+        days: u64,
+    },
 }
@@ -1,4 +1,4 @@
-use std::error::Error as StdError;
+use std::{error::Error as StdError, time::Duration};
 
 use thiserror::Error as ThisError;
 
@@ -50,6 +50,10 @@ pub enum AssistantError {
     /// 期待されていた応答が存在しなかった。
     #[error("expected chat resnpose not found")]
     ChatResponseExpected,
+
+    /// tool calling の最大ラウンド数を超過した。
+    #[error("tool calling exceeded {0} round(s)")]
+    ToolRoundsExceeded(usize),
 }
 
 /// LLM 層のエラー。
@@ -61,6 +65,20 @@ pub enum LlmError {
     #[error("backend error: {0}")]
     Backend(#[source] Box<dyn StdError + Send + Sync + 'static>),
 
+    /// バックエンドが一時的に利用できない (429 や 5xx など)。
+    #[error("backend unavailable: {source}")]
+    Unavailable {
+        /// バックエンドから再試行までの待ち時間が指定された場合はその値。
+        retry_after: Option<Duration>,
+
+        #[source]
+        source: Box<dyn StdError + Send + Sync + 'static>,
+    },
+
+    /// 応答が時間内に返ってこなかった。
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+
     /// LLM が有効なレスポンスを生成しなかった。
     #[error("no choice returned")]
     NoChoice,
@@ -70,6 +88,24 @@ pub enum LlmError {
     ResponseFormat(#[source] Box<dyn StdError + Send + Sync + 'static>),
 }
 
+impl LlmError {
+    /// 時間をおいて再試行すれば成功する可能性があるかどうか。
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            LlmError::Communication(_) | LlmError::Unavailable { .. } | LlmError::Timeout(_)
+        )
+    }
+
+    /// バックエンドから指定された再試行までの待ち時間。
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LlmError::Unavailable { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
 /// Storage 層のエラー。
 #[derive(Debug, ThisError)]
 pub enum StorageError {
@@ -67,7 +67,7 @@ impl ImageGenerator {
 
     pub async fn generate(&self, prompt: String) -> Result<SimpleFunctionResponse, FunctionError> {
         if prompt.is_empty() {
-            return make_error_value("prompt is empty");
+            return Ok(SimpleFunctionResponse::error("prompt is empty"));
         }
 
         info!("generating image with {prompt:?}");
@@ -78,13 +78,13 @@ impl ImageGenerator {
         };
         let response = match self.client.images().create(request).await {
             Ok(r) => r,
-            Err(e) => return make_error_value(&e.to_string()),
+            Err(e) => return Ok(SimpleFunctionResponse::error(e.to_string())),
         };
         let Some(first_image) = response.data.first() else {
-            return make_error_value("no image was generated");
+            return Ok(SimpleFunctionResponse::error("no image was generated"));
         };
         let Image::Url { url, revised_prompt } = first_image.as_ref() else {
-            return make_error_value("invalid response generated");
+            return Ok(SimpleFunctionResponse::error("invalid response generated"));
         };
 
         let image_url = Url::parse(url)?;
@@ -104,22 +104,8 @@ impl ImageGenerator {
     }
 }
 
-fn make_error_value(message: &str) -> Result<SimpleFunctionResponse, FunctionError> {
-    Ok(SimpleFunctionResponse {
-        result: serde_json::to_value(GenerationError {
-            error: message.to_string(),
-        })?,
-        ..Default::default()
-    })
-}
-
 #[derive(Debug, Serialize)]
 struct GenerationResponse {
     image_url: Url,
     revised_prompt: String,
 }
-
-#[derive(Debug, Serialize)]
-struct GenerationError {
-    error: String,
-}
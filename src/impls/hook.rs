@@ -0,0 +1,3 @@
+mod keyword_filter;
+
+pub use self::keyword_filter::KeywordFilter;
@@ -0,0 +1,122 @@
+use crate::{
+    error::AssistantError,
+    model::{
+        config::AppConfigHookKeywordFilter,
+        conversation::IncompleteConversation,
+        message::{AssistantMessage, Message, UserMessageContent},
+    },
+    specs::hook::{AssistantHook, HookAction},
+};
+
+use futures::{FutureExt, future::BoxFuture};
+use tracing::info;
+
+/// 特定のキーワードを含む入力・応答を定型文に置き換えるフック。
+#[derive(Debug)]
+pub struct KeywordFilter {
+    keywords: Vec<String>,
+    reply: String,
+}
+
+impl AssistantHook for KeywordFilter {
+    /// 確定前のテキストは検査できないので、ストリーミングさせない。
+    fn allows_streaming(&self) -> bool {
+        false
+    }
+
+    fn pre_send<'a>(
+        &'a self,
+        conversation: &'a mut IncompleteConversation,
+    ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+        async move { Ok(self.check_user_message(conversation)) }.boxed()
+    }
+
+    fn post_response<'a>(
+        &'a self,
+        _conversation: &'a IncompleteConversation,
+        message: &'a mut AssistantMessage,
+    ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+        async move { Ok(self.check_text(&message.text)) }.boxed()
+    }
+}
+
+impl KeywordFilter {
+    pub fn new(config: &AppConfigHookKeywordFilter) -> KeywordFilter {
+        KeywordFilter {
+            // 空のキーワードはすべてのテキストに含まれてしまうので無視する
+            keywords: config
+                .keywords
+                .iter()
+                .filter(|k| !k.trim().is_empty())
+                .map(|k| k.to_lowercase())
+                .collect(),
+            reply: config.reply.clone(),
+        }
+    }
+
+    fn check_user_message(&self, conversation: &IncompleteConversation) -> HookAction {
+        let Some(Message::User(user_message)) = conversation.latest_messages.last() else {
+            return HookAction::Continue;
+        };
+        for content in &user_message.contents {
+            let UserMessageContent::Text(text) = content else {
+                continue;
+            };
+            if let HookAction::Reply(message) = self.check_text(text) {
+                return HookAction::Reply(message);
+            }
+        }
+        HookAction::Continue
+    }
+
+    fn check_text(&self, text: &str) -> HookAction {
+        let lowered = text.to_lowercase();
+        let Some(keyword) = self.keywords.iter().find(|k| lowered.contains(k.as_str())) else {
+            return HookAction::Continue;
+        };
+
+        info!("keyword filter matched: {keyword}");
+        HookAction::Reply(AssistantMessage {
+            text: self.reply.clone(),
+            is_sensitive: false,
+            language: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(keywords: &[&str]) -> KeywordFilter {
+        KeywordFilter::new(&AppConfigHookKeywordFilter {
+            enabled: true,
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            reply: "パスッス".to_string(),
+        })
+    }
+
+    fn replied_text(action: HookAction) -> Option<String> {
+        match action {
+            HookAction::Reply(message) => Some(message.text),
+            HookAction::Continue => None,
+        }
+    }
+
+    #[test]
+    fn replaces_text_containing_keyword_case_insensitively() {
+        let filter = filter(&["Secret"]);
+        assert_eq!(
+            replied_text(filter.check_text("my SECRET plan")).as_deref(),
+            Some("パスッス")
+        );
+        assert_eq!(replied_text(filter.check_text("public plan")), None);
+    }
+
+    #[test]
+    fn ignores_blank_keywords() {
+        let filter = filter(&["", "  ", "secret"]);
+        assert_eq!(replied_text(filter.check_text("hello")), None);
+        assert_eq!(replied_text(filter.check_text("secret")).as_deref(), Some("パスッス"));
+    }
+}
@@ -1,8 +1,27 @@
 mod claude;
+mod fallback;
+mod gemini;
+mod mock;
+mod ollama;
 mod openai;
+mod retry;
 
-use self::openai::{ChatCompletionBackend, ResponsesBackend};
+#[cfg(test)]
+pub(crate) mod fixtures;
+#[cfg(test)]
+mod stand_in;
+
+use self::{
+    claude::ClaudeBackend,
+    fallback::FallbackLlm,
+    gemini::GeminiBackend,
+    mock::MockBackend,
+    ollama::OllamaBackend,
+    openai::{ChatCompletionBackend, ResponsesBackend},
+    retry::RetryLlm,
+};
 use crate::{
+    USER_AGENT,
     error::LlmError,
     model::{
         config::{AppConfigLlm, AppConfigLlmBackend, AppConfigLlmOpenaiApi},
@@ -11,11 +30,35 @@ use crate::{
     specs::llm::Llm,
 };
 
-use std::{collections::HashMap, sync::LazyLock};
+use std::{
+    collections::HashMap,
+    sync::LazyLock,
+    time::{Duration, SystemTime},
+};
 
-use async_openai::error::OpenAIError;
-use reqwest::Error as ReqwestError;
+use async_openai::error::ApiError;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use reqwest::{
+    Client, Error as ReqwestError, Response, StatusCode,
+    header::{CONTENT_LENGTH, HeaderMap, RETRY_AFTER},
+};
 use serde_json::{Error as SerdeJsonError, Value, json};
+use url::Url;
+
+/// インラインで送る画像の最大サイズ。
+const MAX_INLINE_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// 画像のダウンロードを待つ最大時間。
+const IMAGE_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 画像のダウンロード用のクライアント。API キーを外部に送らないよう、API 用のクライアントとは分ける。
+static IMAGE_CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(IMAGE_DOWNLOAD_TIMEOUT)
+        .build()
+        .expect("failed to build image client")
+});
 
 // MEMO: proc macro で serde のついでに作った方が面白い
 pub static ASSISTANT_RESPONSE_SCHEMA: LazyLock<DescribedSchema> = LazyLock::new(|| {
@@ -34,14 +77,105 @@ pub static ASSISTANT_RESPONSE_SCHEMA: LazyLock<DescribedSchema> = LazyLock::new(
 });
 
 pub async fn create_llm(config: &AppConfigLlm) -> Result<Box<dyn Llm + 'static>, LlmError> {
-    match config.backend {
-        AppConfigLlmBackend::Openai => match config.openai.api {
-            AppConfigLlmOpenaiApi::ChatCompletion => Ok(Box::new(ChatCompletionBackend::new(&config.openai).await?)),
-            AppConfigLlmOpenaiApi::Resnposes => Ok(Box::new(ResponsesBackend::new(&config.openai).await?)),
-        },
+    let llm = create_single_llm(config).await?;
+    if config.fallbacks.is_empty() && config.timeout_seconds.is_none() {
+        return Ok(llm);
+    }
+
+    let mut members = vec![(llm, config.timeout_seconds.map(Duration::from_secs))];
+    for fallback_config in &config.fallbacks {
+        let fallback_llm = create_single_llm(fallback_config).await?;
+        members.push((fallback_llm, fallback_config.timeout_seconds.map(Duration::from_secs)));
+    }
+    Ok(Box::new(FallbackLlm::new(members)))
+}
+
+/// `fallbacks` を除いた 1 つのバックエンドを生成する。
+async fn create_single_llm(config: &AppConfigLlm) -> Result<Box<dyn Llm + 'static>, LlmError> {
+    let llm: Box<dyn Llm> = match config.backend {
+        AppConfigLlmBackend::Openai => {
+            let openai_config = config.openai.as_ref().ok_or_else(|| missing_section("openai"))?;
+            match openai_config.api {
+                AppConfigLlmOpenaiApi::ChatCompletion => Box::new(ChatCompletionBackend::new(openai_config).await?),
+                AppConfigLlmOpenaiApi::Resnposes => Box::new(ResponsesBackend::new(openai_config).await?),
+            }
+        }
+        AppConfigLlmBackend::Claude => {
+            let claude_config = config.claude.as_ref().ok_or_else(|| missing_section("claude"))?;
+            Box::new(ClaudeBackend::new(claude_config)?)
+        }
+        AppConfigLlmBackend::Ollama => {
+            let ollama_config = config.ollama.as_ref().ok_or_else(|| missing_section("ollama"))?;
+            Box::new(OllamaBackend::new(ollama_config)?)
+        }
+        AppConfigLlmBackend::Gemini => {
+            let gemini_config = config.gemini.as_ref().ok_or_else(|| missing_section("gemini"))?;
+            Box::new(GeminiBackend::new(gemini_config)?)
+        }
+        AppConfigLlmBackend::Mock => {
+            let mock_config = config.mock.as_ref().ok_or_else(|| missing_section("mock"))?;
+            Box::new(MockBackend::new(mock_config).await?)
+        }
+    };
+
+    match config.retry {
+        Some(policy) => Ok(Box::new(RetryLlm::new(llm, policy))),
+        None => Ok(llm),
+    }
+}
+
+fn missing_section(backend: &str) -> LlmError {
+    LlmError::Backend(format!("[llm.{backend}] section is required for {backend} backend").into())
+}
+
+/// HTTP API の応答のステータスを確認し、失敗していれば `LlmError` に変換する。
+/// OpenAI 互換でない API を直接叩くバックエンドで利用する。
+async fn check_http_status(response: Response) -> Result<Response, LlmError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = parse_retry_after(response.headers());
+    let body = response.text().await.unwrap_or_default();
+    let source = format!("HTTP {status}: {body}").into();
+    if is_unavailable_status(status) {
+        Err(LlmError::Unavailable { retry_after, source })
+    } else {
+        Err(LlmError::Backend(source))
     }
 }
 
+/// `Retry-After` ヘッダーを読み取る。秒数と HTTP-date のどちらの形式にも対応する。
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    parse_retry_after_value(value, SystemTime::now())
+}
+
+fn parse_retry_after_value(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    // 過去の日時が指定されていたらすぐに再試行してよい
+    let retry_at = httpdate::parse_http_date(value).ok()?;
+    Some(retry_at.duration_since(now).unwrap_or_default())
+}
+
+/// 一時的な障害を表す HTTP ステータスかどうか。
+fn is_unavailable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// OpenAI 互換 API のエラーのうち、一時的な障害を表すものかどうか。
+fn is_unavailable_api_error(api_error: &ApiError) -> bool {
+    const UNAVAILABLE_KINDS: &[&str] = &["rate_limit_exceeded", "server_error", "overloaded_error"];
+    [&api_error.r#type, &api_error.code]
+        .into_iter()
+        .flatten()
+        .any(|k| UNAVAILABLE_KINDS.contains(&k.as_str()))
+}
+
 fn convert_json_schema(schema: &DescribedSchema) -> Value {
     match &schema.field_type {
         DescribedSchemaType::Integer => json!({
@@ -76,10 +210,47 @@ fn convert_json_schema(schema: &DescribedSchema) -> Value {
     }
 }
 
-impl From<OpenAIError> for LlmError {
-    fn from(value: OpenAIError) -> Self {
-        LlmError::Backend(value.into())
+/// base64 にした画像。
+#[derive(Debug, Clone)]
+struct InlineImage {
+    mime_type: String,
+    data: String,
+}
+
+/// 画像を取得して base64 にする。インラインで送れない大きさのものはエラーにする。
+async fn download_image(url: &Url) -> Result<InlineImage, LlmError> {
+    let mut response = IMAGE_CLIENT.get(url.as_str()).send().await?.error_for_status()?;
+    let too_large = || LlmError::Backend(format!("image is larger than {MAX_INLINE_IMAGE_BYTES} bytes").into());
+    let content_length = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|l| l > MAX_INLINE_IMAGE_BYTES) {
+        return Err(too_large());
     }
+
+    // Content-Length がなくても読みすぎないように、受け取りながら確認する
+    let mut image_data = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if image_data.len() + chunk.len() > MAX_INLINE_IMAGE_BYTES {
+            return Err(too_large());
+        }
+        image_data.extend_from_slice(&chunk);
+    }
+
+    let mime_type = match infer::get(&image_data).map(|ft| ft.mime_type()) {
+        Some(mime_type) if mime_type.starts_with("image/") => mime_type.to_string(),
+        mime_type => {
+            return Err(LlmError::Backend(
+                format!("unsupported image type: {mime_type:?}").into(),
+            ));
+        }
+    };
+    Ok(InlineImage {
+        mime_type,
+        data: BASE64_STANDARD.encode(&image_data),
+    })
 }
 
 impl From<ReqwestError> for LlmError {
@@ -93,3 +264,32 @@ impl From<SerdeJsonError> for LlmError {
         LlmError::ResponseFormat(value.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_retry_after_in_seconds() {
+        let now = SystemTime::now();
+        assert_eq!(parse_retry_after_value("120", now), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn parses_retry_after_in_http_date() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").expect("valid http date");
+        assert_eq!(
+            parse_retry_after_value("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after_value("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_retry_after() {
+        assert_eq!(parse_retry_after_value("soon", SystemTime::now()), None);
+    }
+}
@@ -1 +1,507 @@
+use crate::{
+    USER_AGENT,
+    error::LlmError,
+    impls::llm::{ASSISTANT_RESPONSE_SCHEMA, check_http_status, convert_json_schema},
+    model::{
+        config::{AppConfigContextBudget, AppConfigLlmClaude},
+        conversation::IncompleteConversation,
+        message::{Message, MessageFunctionCall, UserMessageContent},
+        usage::TokenUsage,
+    },
+    specs::{
+        function::simple::SimpleFunctionDescriptor,
+        llm::{Llm, LlmAssistantResponse, LlmUpdate, LlmUsage},
+    },
+};
 
+use std::sync::Arc;
+
+use futures::{FutureExt, future::BoxFuture};
+use reqwest::{
+    Client,
+    header::{CONTENT_TYPE, HeaderMap, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// structured output の代わりに呼び出させる tool の名前。
+const RESPONSE_TOOL_NAME: &str = "respond_to_user";
+
+/// Anthropic Messages API を利用したバックエンド。
+#[derive(Debug, Clone)]
+pub struct ClaudeBackend(Arc<ClaudeBackendInner>);
+
+impl ClaudeBackend {
+    pub fn new(config: &AppConfigLlmClaude) -> Result<ClaudeBackend, LlmError> {
+        let mut headers = HeaderMap::new();
+        let api_key = HeaderValue::from_str(&config.token).map_err(|e| LlmError::Backend(e.into()))?;
+        headers.insert("x-api-key", api_key);
+        headers.insert("anthropic-version", HeaderValue::from_static(ANTHROPIC_VERSION));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .default_headers(headers)
+            .build()?;
+
+        Ok(ClaudeBackend(Arc::new(ClaudeBackendInner {
+            client,
+            endpoint: format!("{}/v1/messages", config.endpoint.trim_end_matches('/')),
+            tools: Mutex::new(vec![]),
+            model: config.model.clone(),
+            max_token: config.max_token,
+            structured_mode: config.use_structured_output,
+            prompt_caching: config.prompt_caching,
+            context_budget: config.context_budget,
+        })))
+    }
+}
+
+impl Llm for ClaudeBackend {
+    fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
+        async { self.0.add_simple_function(descriptor).await }.boxed()
+    }
+
+    fn send_conversation<'a>(
+        &'a self,
+        conversation: &'a IncompleteConversation,
+    ) -> BoxFuture<'a, Result<LlmUpdate, LlmError>> {
+        let cloned = self.0.clone();
+        async move { cloned.send_conversation(conversation).await }.boxed()
+    }
+}
+
+#[derive(Debug)]
+struct ClaudeBackendInner {
+    client: Client,
+    endpoint: String,
+    tools: Mutex<Vec<ClaudeTool>>,
+    model: String,
+    max_token: usize,
+    structured_mode: bool,
+    prompt_caching: bool,
+    context_budget: Option<AppConfigContextBudget>,
+}
+
+impl ClaudeBackendInner {
+    async fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) {
+        let tool = ClaudeTool {
+            name: descriptor.name,
+            description: descriptor.description,
+            input_schema: convert_json_schema(&descriptor.parameters),
+        };
+
+        let mut locked = self.tools.lock().await;
+        locked.push(tool);
+    }
+
+    async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
+        let (system, messages) = transform_messages(conversation.budgeted_messages(self.context_budget.as_ref()))?;
+        let request = self.create_request(system, messages, conversation.use_tools).await;
+
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await?;
+        let response = check_http_status(response).await?;
+        let claude_response: ClaudeResponse = serde_json::from_slice(&response.bytes().await?)?;
+        self.convert_response(claude_response)
+    }
+
+    async fn create_request(
+        &self,
+        mut system: Vec<ClaudeSystemBlock>,
+        messages: Vec<ClaudeMessage>,
+        use_tools: bool,
+    ) -> ClaudeRequest {
+        let mut tools = if use_tools {
+            self.tools.lock().await.clone()
+        } else {
+            vec![]
+        };
+        // 要約など tool を使わない送信では、応答用の tool も強制しない
+        let tool_choice = if self.structured_mode && use_tools {
+            tools.push(ClaudeTool {
+                name: RESPONSE_TOOL_NAME.to_string(),
+                description: "Respond to the user. Always use this tool for the final answer.".to_string(),
+                input_schema: convert_json_schema(&ASSISTANT_RESPONSE_SCHEMA),
+            });
+            Some(ClaudeToolChoice::Any)
+        } else {
+            None
+        };
+
+        // tools → system の順にキャッシュされるので、先頭の system block (identity の prompt) までを対象にする
+        if self.prompt_caching
+            && let Some(first_block) = system.first_mut()
+        {
+            first_block.cache_control = Some(ClaudeCacheControl::Ephemeral);
+        }
+
+        ClaudeRequest {
+            model: self.model.clone(),
+            max_tokens: self.max_token,
+            system,
+            messages,
+            tools,
+            tool_choice,
+        }
+    }
+
+    fn convert_response(&self, claude_response: ClaudeResponse) -> Result<LlmUpdate, LlmError> {
+        let usage = LlmUsage {
+            model: self.model.clone(),
+            tokens: claude_response.usage.into(),
+        };
+
+        let mut text = String::new();
+        let mut structured_response = None;
+        let mut tool_callings = vec![];
+        for block in claude_response.content {
+            match block {
+                ClaudeResponseBlock::Text { text: block_text } => text.push_str(&block_text),
+                ClaudeResponseBlock::ToolUse { id, name, input } => {
+                    if self.structured_mode && name == RESPONSE_TOOL_NAME {
+                        let response = serde_json::from_value(input).map_err(|e| LlmError::ResponseFormat(e.into()))?;
+                        structured_response = Some(response);
+                    } else {
+                        tool_callings.push(MessageFunctionCall {
+                            id,
+                            name,
+                            arguments: input,
+                        });
+                    }
+                }
+                ClaudeResponseBlock::Other => (),
+            }
+        }
+
+        let response = match structured_response {
+            Some(response) => Some(response),
+            None if text.is_empty() => None,
+            None => Some(LlmAssistantResponse {
+                text,
+                language: None,
+                sensitive: None,
+            }),
+        };
+        if response.is_none() && tool_callings.is_empty() {
+            return Err(LlmError::NoChoice);
+        }
+
+        Ok(LlmUpdate {
+            response,
+            tool_callings: (!tool_callings.is_empty()).then_some(tool_callings),
+            usage: Some(usage),
+        })
+    }
+}
+
+/// `Message` 列を top-level の system と messages に分ける。
+/// 同じ role が連続すると API がエラーを返すので、連続するものは 1 つのメッセージにまとめる。
+fn transform_messages(messages: Vec<&Message>) -> Result<(Vec<ClaudeSystemBlock>, Vec<ClaudeMessage>), LlmError> {
+    let mut system = vec![];
+    let mut claude_messages: Vec<ClaudeMessage> = vec![];
+    for message in messages {
+        let (role, blocks) = match message {
+            Message::System(system_message) => {
+                system.push(ClaudeSystemBlock::new(system_message.0.clone()));
+                continue;
+            }
+            Message::Summary(summary_message) => {
+                system.push(ClaudeSystemBlock::new(format!(
+                    "これまでの会話の要約:\n{}",
+                    summary_message.0
+                )));
+                continue;
+            }
+            Message::User(user_message) => {
+                let blocks = user_message
+                    .contents
+                    .iter()
+                    .map(|umc| match umc {
+                        UserMessageContent::Text(text) => ClaudeContentBlock::Text { text: text.clone() },
+                        UserMessageContent::ImageUrl(url) => ClaudeContentBlock::Image {
+                            source: ClaudeImageSource::Url { url: url.to_string() },
+                        },
+                    })
+                    .collect();
+                (ClaudeRole::User, blocks)
+            }
+            Message::Assistant(assistant_message) => (
+                ClaudeRole::Assistant,
+                vec![ClaudeContentBlock::Text {
+                    text: assistant_message.text.clone(),
+                }],
+            ),
+            Message::FunctionCalls(function_calls_message) => {
+                let blocks = function_calls_message
+                    .0
+                    .iter()
+                    .map(|c| ClaudeContentBlock::ToolUse {
+                        id: c.id.clone(),
+                        name: c.name.clone(),
+                        input: c.arguments.clone(),
+                    })
+                    .collect();
+                (ClaudeRole::Assistant, blocks)
+            }
+            Message::FunctionResponse(function_response_message) => (
+                ClaudeRole::User,
+                vec![ClaudeContentBlock::ToolResult {
+                    tool_use_id: function_response_message.id.clone(),
+                    content: serde_json::to_string(&function_response_message.result)?,
+                }],
+            ),
+        };
+
+        match claude_messages.last_mut() {
+            Some(last) if last.role == role => last.content.extend(blocks),
+            _ => claude_messages.push(ClaudeMessage { role, content: blocks }),
+        }
+    }
+    Ok((system, claude_messages))
+}
+
+#[derive(Debug, Serialize)]
+struct ClaudeRequest {
+    model: String,
+    max_tokens: usize,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    system: Vec<ClaudeSystemBlock>,
+    messages: Vec<ClaudeMessage>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ClaudeTool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ClaudeToolChoice>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ClaudeSystemBlock {
+    r#type: &'static str,
+    text: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<ClaudeCacheControl>,
+}
+
+impl ClaudeSystemBlock {
+    fn new(text: String) -> ClaudeSystemBlock {
+        ClaudeSystemBlock {
+            r#type: "text",
+            text,
+            cache_control: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClaudeCacheControl {
+    Ephemeral,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ClaudeRole {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Serialize)]
+struct ClaudeMessage {
+    role: ClaudeRole,
+    content: Vec<ClaudeContentBlock>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClaudeContentBlock {
+    Text { text: String },
+    Image { source: ClaudeImageSource },
+    ToolUse { id: String, name: String, input: Value },
+    ToolResult { tool_use_id: String, content: String },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClaudeImageSource {
+    Url { url: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ClaudeTool {
+    name: String,
+    description: String,
+    input_schema: Value,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClaudeToolChoice {
+    /// いずれかの tool を必ず呼び出させる。
+    Any,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClaudeResponse {
+    content: Vec<ClaudeResponseBlock>,
+    usage: ClaudeUsage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClaudeResponseBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+
+    /// thinking など、扱わないブロック。
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClaudeUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+
+    #[serde(default = "Default::default")]
+    cache_creation_input_tokens: Option<u64>,
+
+    #[serde(default = "Default::default")]
+    cache_read_input_tokens: Option<u64>,
+}
+
+impl From<ClaudeUsage> for TokenUsage {
+    /// `input_tokens` にはキャッシュの読み書き分が含まれないので足し合わせる。
+    fn from(value: ClaudeUsage) -> TokenUsage {
+        let cache_creation = value.cache_creation_input_tokens.unwrap_or_default();
+        let cache_read = value.cache_read_input_tokens.unwrap_or_default();
+        TokenUsage {
+            prompt_tokens: value.input_tokens + cache_creation + cache_read,
+            completion_tokens: value.output_tokens,
+            cached_tokens: cache_read,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::llm::fixtures::{assistant, function_call, user};
+
+    use serde_json::{json, to_value};
+
+    fn create_backend(structured: bool, prompt_caching: bool) -> ClaudeBackend {
+        let config: AppConfigLlmClaude = toml::from_str(&format!(
+            "token = \"test\"\nmodel = \"claude-test\"\nmax_token = 100\nuse_structured_output = {structured}\nprompt_caching = {prompt_caching}"
+        ))
+        .expect("invalid claude config");
+        ClaudeBackend::new(&config).expect("failed to create backend")
+    }
+
+    async fn request_json(backend: &ClaudeBackend, messages: &[Message], use_tools: bool) -> Value {
+        let (system, messages) = transform_messages(messages.iter().collect()).expect("failed to transform");
+        let request = backend.0.create_request(system, messages, use_tools).await;
+        to_value(request).expect("failed to serialize")
+    }
+
+    #[test]
+    fn extracts_system_and_summary() {
+        let messages = [
+            Message::new_system("identity"),
+            Message::new_summary("summary"),
+            user("hello"),
+        ];
+        let (system, claude_messages) =
+            transform_messages(messages.iter().collect()).expect("messages should transform");
+
+        let system_texts: Vec<_> = system.iter().map(|b| b.text.as_str()).collect();
+        assert_eq!(system_texts, ["identity", "これまでの会話の要約:\nsummary"]);
+        assert_eq!(claude_messages.len(), 1);
+        assert_eq!(claude_messages[0].role, ClaudeRole::User);
+    }
+
+    #[tokio::test]
+    async fn pairs_tool_use_with_tool_result_and_merges_same_role() {
+        let messages = [
+            user("version?"),
+            Message::new_function_calls([function_call("call_1"), function_call("call_2")]),
+            Message::new_function_response("call_1", "self_info", json!({ "v": 1 })),
+            Message::new_function_response("call_2", "self_info", json!({ "v": 2 })),
+            assistant("latest"),
+            user("thanks"),
+            user("again"),
+        ];
+        let request = request_json(&create_backend(false, false), &messages, true).await;
+
+        assert_eq!(
+            request["messages"],
+            json!([
+                { "role": "user", "content": [{ "type": "text", "text": "version?" }] },
+                {
+                    "role": "assistant",
+                    "content": [
+                        { "type": "tool_use", "id": "call_1", "name": "self_info", "input": {} },
+                        { "type": "tool_use", "id": "call_2", "name": "self_info", "input": {} },
+                    ],
+                },
+                {
+                    "role": "user",
+                    "content": [
+                        { "type": "tool_result", "tool_use_id": "call_1", "content": "{\"v\":1}" },
+                        { "type": "tool_result", "tool_use_id": "call_2", "content": "{\"v\":2}" },
+                    ],
+                },
+                { "role": "assistant", "content": [{ "type": "text", "text": "latest" }] },
+                {
+                    "role": "user",
+                    "content": [{ "type": "text", "text": "thanks" }, { "type": "text", "text": "again" }],
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn places_cache_control_on_first_system_block() {
+        let messages = [
+            Message::new_system("identity"),
+            Message::new_system("local"),
+            user("hello"),
+        ];
+
+        let request = request_json(&create_backend(false, true), &messages, true).await;
+        assert_eq!(request["system"][0]["cache_control"], json!({ "type": "ephemeral" }));
+        assert!(request["system"][1].get("cache_control").is_none());
+
+        let request = request_json(&create_backend(false, false), &messages, true).await;
+        assert!(request["system"][0].get("cache_control").is_none());
+    }
+
+    #[tokio::test]
+    async fn forces_response_tool_only_when_tools_are_used() {
+        let backend = create_backend(true, false);
+        let messages = [user("hello")];
+
+        let request = request_json(&backend, &messages, true).await;
+        assert_eq!(request["tool_choice"], json!({ "type": "any" }));
+        assert_eq!(request["tools"][0]["name"], RESPONSE_TOOL_NAME);
+
+        let request = request_json(&backend, &messages, false).await;
+        assert!(request.get("tool_choice").is_none());
+        assert!(request.get("tools").is_none());
+    }
+}
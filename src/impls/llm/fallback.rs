@@ -0,0 +1,154 @@
+use crate::{
+    error::LlmError,
+    model::conversation::IncompleteConversation,
+    specs::{
+        function::simple::SimpleFunctionDescriptor,
+        llm::{Llm, LlmStream, LlmUpdate},
+    },
+};
+
+use std::{sync::Arc, time::Duration};
+
+use futures::{
+    FutureExt, StreamExt,
+    future::{BoxFuture, join_all},
+    stream,
+};
+use tokio::time::timeout;
+use tracing::warn;
+
+/// 複数の `Llm` を順に試し、最初に成功した結果を返す `Llm`。
+#[derive(Debug, Clone)]
+pub struct FallbackLlm(Arc<FallbackLlmInner>);
+
+impl FallbackLlm {
+    /// `members` は優先度の高い順に並べる。`Duration` はそのバックエンドの応答を待つ最大時間。
+    pub fn new(members: Vec<(Box<dyn Llm + 'static>, Option<Duration>)>) -> FallbackLlm {
+        let members = members
+            .into_iter()
+            .map(|(llm, timeout)| FallbackMember { llm, timeout })
+            .collect();
+        FallbackLlm(Arc::new(FallbackLlmInner { members }))
+    }
+}
+
+impl Llm for FallbackLlm {
+    fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
+        async move { self.0.add_simple_function(descriptor).await }.boxed()
+    }
+
+    fn send_conversation<'a>(
+        &'a self,
+        conversation: &'a IncompleteConversation,
+    ) -> BoxFuture<'a, Result<LlmUpdate, LlmError>> {
+        async move { self.0.send_conversation(conversation).await }.boxed()
+    }
+
+    fn send_conversation_stream<'a>(&'a self, conversation: &'a IncompleteConversation) -> LlmStream<'a> {
+        async move { self.0.send_conversation_stream(conversation).await }
+            .flatten_stream()
+            .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct FallbackLlmInner {
+    members: Vec<FallbackMember>,
+}
+
+#[derive(Debug)]
+struct FallbackMember {
+    llm: Box<dyn Llm + 'static>,
+    timeout: Option<Duration>,
+}
+
+impl FallbackLlmInner {
+    async fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) {
+        let futures = self
+            .members
+            .iter()
+            .map(|m| m.llm.add_simple_function(descriptor.clone()));
+        join_all(futures).await;
+    }
+
+    async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
+        let mut last_error = LlmError::NoChoice;
+        for (index, member) in self.members.iter().enumerate() {
+            let send_future = member.llm.send_conversation(conversation);
+            let result = match member.timeout {
+                Some(limit) => timeout(limit, send_future)
+                    .await
+                    .unwrap_or(Err(LlmError::Timeout(limit))),
+                None => send_future.await,
+            };
+            match result {
+                Ok(update) => return Ok(update),
+                Err(err) => {
+                    warn!("LLM #{index} in fallback chain failed: {err}");
+                    last_error = err;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// 最初のイベントを受け取る前に失敗した場合のみ次のバックエンドに切り替える。
+    /// 切り替えた後もイベントの間隔が timeout を超えたら失敗として打ち切る。
+    async fn send_conversation_stream<'a>(&'a self, conversation: &'a IncompleteConversation) -> LlmStream<'a> {
+        let mut last_error = LlmError::NoChoice;
+        for (index, member) in self.members.iter().enumerate() {
+            let mut llm_stream = member.llm.send_conversation_stream(conversation);
+            let first_event = match member.timeout {
+                Some(limit) => timeout(limit, llm_stream.next())
+                    .await
+                    .unwrap_or(Some(Err(LlmError::Timeout(limit)))),
+                None => llm_stream.next().await,
+            };
+            match first_event {
+                Some(Err(err)) => {
+                    warn!("LLM #{index} in fallback chain failed: {err}");
+                    last_error = err;
+                }
+                first_event => {
+                    let rest = match member.timeout {
+                        Some(limit) => with_idle_timeout(llm_stream, limit),
+                        None => llm_stream,
+                    };
+                    return stream::iter(first_event).chain(rest).boxed();
+                }
+            }
+        }
+        stream::iter([Err(last_error)]).boxed()
+    }
+}
+
+/// 次のイベントが `limit` 以内に来なければ `LlmError::Timeout` を流して終わるストリームにする。
+fn with_idle_timeout(llm_stream: LlmStream<'_>, limit: Duration) -> LlmStream<'_> {
+    stream::unfold(Some(llm_stream), move |llm_stream| async move {
+        let mut llm_stream = llm_stream?;
+        match timeout(limit, llm_stream.next()).await {
+            Ok(Some(event)) => Some((event, Some(llm_stream))),
+            Ok(None) => None,
+            Err(_) => Some((Err(LlmError::Timeout(limit)), None)),
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::specs::llm::LlmStreamEvent;
+
+    #[tokio::test]
+    async fn stops_stream_stalled_after_first_event() {
+        let stalled = stream::iter([Ok(LlmStreamEvent::TextDelta("a".to_string()))])
+            .chain(stream::pending())
+            .boxed();
+        let events: Vec<_> = with_idle_timeout(stalled, Duration::from_millis(50)).collect().await;
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], Ok(LlmStreamEvent::TextDelta(ref text)) if text == "a"));
+        assert!(matches!(events[1], Err(LlmError::Timeout(_))));
+    }
+}
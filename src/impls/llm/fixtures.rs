@@ -0,0 +1,41 @@
+use crate::model::{
+    conversation::IncompleteConversation,
+    message::{Message, MessageFunctionCall, UserMessageContent},
+};
+
+use serde_json::json;
+use uuid::Uuid;
+
+/// テキストだけの user message。
+pub fn user(text: &str) -> Message {
+    Message::new_user([UserMessageContent::Text(text.to_string())], None, None)
+}
+
+/// sensitive でない assistant message。
+pub fn assistant(text: &str) -> Message {
+    Message::new_assistant(text, false, None)
+}
+
+/// 引数なしの `self_info` の呼び出し。
+pub fn function_call(id: &str) -> MessageFunctionCall {
+    MessageFunctionCall {
+        id: id.to_string(),
+        name: "self_info".to_string(),
+        arguments: json!({}),
+    }
+}
+
+/// 新しい ID で、tool を使える `IncompleteConversation` を作る。
+pub fn incomplete(latest_messages: Vec<Message>) -> IncompleteConversation {
+    incomplete_with_id(Uuid::now_v7(), latest_messages)
+}
+
+/// 同じ会話の続きとして送るために、ID を指定して `IncompleteConversation` を作る。
+pub fn incomplete_with_id(id: Uuid, latest_messages: Vec<Message>) -> IncompleteConversation {
+    IncompleteConversation {
+        id,
+        latest_messages,
+        identity: None,
+        use_tools: true,
+    }
+}
@@ -0,0 +1,826 @@
+use crate::{
+    USER_AGENT,
+    error::LlmError,
+    impls::llm::{ASSISTANT_RESPONSE_SCHEMA, check_http_status, download_image},
+    model::{
+        config::{AppConfigContextBudget, AppConfigLlmGemini},
+        conversation::IncompleteConversation,
+        message::{Message, MessageFunctionCall, UserMessageContent},
+        schema::{DescribedSchema, DescribedSchemaType},
+        usage::TokenUsage,
+    },
+    specs::{
+        function::simple::SimpleFunctionDescriptor,
+        llm::{Llm, LlmAssistantResponse, LlmUpdate, LlmUsage},
+    },
+};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use futures::{FutureExt, future::BoxFuture};
+use reqwest::{
+    Client,
+    header::{CONTENT_TYPE, HeaderMap, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+use tracing::warn;
+use url::Url;
+use uuid::Uuid;
+
+/// 保持しておく thought signature の上限。
+const MAX_THOUGHT_SIGNATURES: usize = 1024;
+
+/// tool と同時に structured output を使うとき、代わりに呼び出させる関数の名前。
+const RESPONSE_FUNCTION_NAME: &str = "respond_to_user";
+
+/// Gemini API (generateContent) を利用したバックエンド。
+#[derive(Debug, Clone)]
+pub struct GeminiBackend(Arc<GeminiBackendInner>);
+
+impl GeminiBackend {
+    pub fn new(config: &AppConfigLlmGemini) -> Result<GeminiBackend, LlmError> {
+        let mut headers = HeaderMap::new();
+        let api_key = HeaderValue::from_str(&config.token).map_err(|e| LlmError::Backend(e.into()))?;
+        headers.insert("x-goog-api-key", api_key);
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .default_headers(headers)
+            .build()?;
+
+        Ok(GeminiBackend(Arc::new(GeminiBackendInner {
+            client,
+            endpoint: format!(
+                "{}/models/{}:generateContent",
+                config.endpoint.trim_end_matches('/'),
+                config.model
+            ),
+            tools: Mutex::new(vec![]),
+            thought_signatures: Mutex::new(BTreeMap::new()),
+            model: config.model.clone(),
+            max_token: config.max_token,
+            structured_mode: config.use_structured_output,
+            context_budget: config.context_budget,
+        })))
+    }
+}
+
+impl Llm for GeminiBackend {
+    fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
+        async { self.0.add_simple_function(descriptor).await }.boxed()
+    }
+
+    fn send_conversation<'a>(
+        &'a self,
+        conversation: &'a IncompleteConversation,
+    ) -> BoxFuture<'a, Result<LlmUpdate, LlmError>> {
+        let cloned = self.0.clone();
+        async move { cloned.send_conversation(conversation).await }.boxed()
+    }
+}
+
+#[derive(Debug)]
+struct GeminiBackendInner {
+    client: Client,
+    endpoint: String,
+    tools: Mutex<Vec<GeminiFunctionDeclaration>>,
+
+    /// tool calling の ID ごとの thought signature。送り返さないと拒否するモデルがある。
+    thought_signatures: Mutex<BTreeMap<String, String>>,
+    model: String,
+    max_token: usize,
+    structured_mode: bool,
+    context_budget: Option<AppConfigContextBudget>,
+}
+
+impl GeminiBackendInner {
+    async fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) {
+        let declaration = GeminiFunctionDeclaration {
+            name: descriptor.name,
+            description: descriptor.description,
+            parameters: has_fields(&descriptor.parameters).then(|| convert_gemini_schema(&descriptor.parameters)),
+        };
+
+        let mut locked = self.tools.lock().await;
+        locked.push(declaration);
+    }
+
+    async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
+        let (system_parts, contents) = self
+            .transform_messages(conversation.budgeted_messages(self.context_budget.as_ref()))
+            .await?;
+
+        let request = self
+            .create_request(system_parts, contents, conversation.use_tools)
+            .await;
+        let structured = request.generation_config.response_schema.is_some();
+
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await?;
+        let response = check_http_status(response).await?;
+        let gemini_response: GeminiResponse = serde_json::from_slice(&response.bytes().await?)?;
+        self.convert_response(gemini_response, structured).await
+    }
+
+    async fn create_request(
+        &self,
+        system_parts: Vec<GeminiPart>,
+        contents: Vec<GeminiContent>,
+        use_tools: bool,
+    ) -> GeminiRequest {
+        let mut function_declarations = if use_tools {
+            self.tools.lock().await.clone()
+        } else {
+            vec![]
+        };
+        // function calling と responseSchema を同時に指定すると拒否するモデルがあるので、
+        // tool を送るときは responseSchema の代わりに応答用の関数を必ず呼ばせる
+        let (tool_config, response_schema) = match (self.structured_mode, function_declarations.is_empty()) {
+            (false, _) => (None, None),
+            (true, true) => (None, Some(convert_gemini_schema(&ASSISTANT_RESPONSE_SCHEMA))),
+            (true, false) => {
+                function_declarations.push(GeminiFunctionDeclaration {
+                    name: RESPONSE_FUNCTION_NAME.to_string(),
+                    description: "Respond to the user. Always use this function for the final answer.".to_string(),
+                    parameters: Some(convert_gemini_schema(&ASSISTANT_RESPONSE_SCHEMA)),
+                });
+                (Some(GeminiToolConfig::any()), None)
+            }
+        };
+        let tools = if function_declarations.is_empty() {
+            vec![]
+        } else {
+            vec![GeminiTool { function_declarations }]
+        };
+
+        GeminiRequest {
+            system_instruction: (!system_parts.is_empty()).then_some(GeminiSystemInstruction { parts: system_parts }),
+            contents,
+            tools,
+            tool_config,
+            generation_config: GeminiGenerationConfig {
+                max_output_tokens: self.max_token,
+                response_mime_type: response_schema.is_some().then_some("application/json"),
+                response_schema,
+            },
+        }
+    }
+
+    async fn convert_response(&self, gemini_response: GeminiResponse, structured: bool) -> Result<LlmUpdate, LlmError> {
+        let usage = gemini_response.usage_metadata.map(|u| LlmUsage {
+            model: self.model.clone(),
+            tokens: u.into(),
+        });
+
+        let Some(candidate) = gemini_response.candidates.into_iter().next() else {
+            return match gemini_response.prompt_feedback.and_then(|f| f.block_reason) {
+                Some(reason) => Err(LlmError::Backend(format!("prompt blocked: {reason}").into())),
+                None => Err(LlmError::NoChoice),
+            };
+        };
+
+        let mut text = String::new();
+        let mut structured_response = None;
+        let mut tool_callings = vec![];
+        let mut thought_signatures = vec![];
+        for part in candidate.content.map(|c| c.parts).unwrap_or_default() {
+            if part.thought {
+                continue;
+            }
+            if let Some(part_text) = part.text {
+                text.push_str(&part_text);
+            }
+            if let Some(function_call) = part.function_call {
+                if self.structured_mode && function_call.name == RESPONSE_FUNCTION_NAME {
+                    let arguments = function_call.args.unwrap_or_else(|| json!({}));
+                    let response = serde_json::from_value(arguments).map_err(|e| LlmError::ResponseFormat(e.into()))?;
+                    structured_response = Some(response);
+                    continue;
+                }
+
+                // Gemini の ID は省略されることがあるので、常にこちらで振る
+                let id = format!("call_{}", Uuid::now_v7().simple());
+                if let Some(signature) = part.thought_signature {
+                    thought_signatures.push((id.clone(), signature));
+                }
+                tool_callings.push(MessageFunctionCall {
+                    id,
+                    name: function_call.name,
+                    arguments: function_call.args.unwrap_or_else(|| json!({})),
+                });
+            }
+        }
+        self.remember_thought_signatures(thought_signatures).await;
+
+        let response = match structured_response {
+            Some(response) => Some(response),
+            None if text.is_empty() => None,
+            None if structured => Some(serde_json::from_str(&text).map_err(|e| LlmError::ResponseFormat(e.into()))?),
+            None => Some(LlmAssistantResponse {
+                text,
+                language: None,
+                sensitive: None,
+            }),
+        };
+        if response.is_none() && tool_callings.is_empty() {
+            return match candidate.finish_reason {
+                Some(reason) if reason != "STOP" => {
+                    Err(LlmError::Backend(format!("generation stopped: {reason}").into()))
+                }
+                _ => Err(LlmError::NoChoice),
+            };
+        }
+
+        Ok(LlmUpdate {
+            response,
+            tool_callings: (!tool_callings.is_empty()).then_some(tool_callings),
+            usage,
+        })
+    }
+
+    async fn remember_thought_signatures(&self, signatures: Vec<(String, String)>) {
+        if signatures.is_empty() {
+            return;
+        }
+
+        let mut locked = self.thought_signatures.lock().await;
+        locked.extend(signatures);
+        // ID は UUIDv7 から作っているので先頭が最も古い
+        while locked.len() > MAX_THOUGHT_SIGNATURES {
+            locked.pop_first();
+        }
+    }
+
+    /// `Message` 列を systemInstruction と contents に分ける。連続する同じ role は 1 つにまとめる。
+    async fn transform_messages(
+        &self,
+        messages: Vec<&Message>,
+    ) -> Result<(Vec<GeminiPart>, Vec<GeminiContent>), LlmError> {
+        let thought_signatures = self.thought_signatures.lock().await.clone();
+        // 履歴の画像を毎回ダウンロードし直さないように、埋め込むのは最新のユーザー入力の画像だけにする
+        let latest_user_index = messages.iter().rposition(|m| matches!(m, Message::User(_)));
+        let mut system_parts = vec![];
+        let mut contents: Vec<GeminiContent> = vec![];
+        for (index, message) in messages.into_iter().enumerate() {
+            let (role, parts) = match message {
+                Message::System(system_message) => {
+                    system_parts.push(GeminiPart::text(system_message.0.clone()));
+                    continue;
+                }
+                Message::Summary(summary_message) => {
+                    system_parts.push(GeminiPart::text(format!(
+                        "これまでの会話の要約:\n{}",
+                        summary_message.0
+                    )));
+                    continue;
+                }
+                Message::User(user_message) => {
+                    let mut parts = vec![];
+                    for umc in &user_message.contents {
+                        let part = match umc {
+                            UserMessageContent::Text(text) => GeminiPart::text(text.clone()),
+                            UserMessageContent::ImageUrl(url) if Some(index) == latest_user_index => {
+                                self.transform_image(url).await
+                            }
+                            UserMessageContent::ImageUrl(url) => GeminiPart::text(format!("[画像: {url}]")),
+                        };
+                        parts.push(part);
+                    }
+                    (GeminiRole::User, parts)
+                }
+                Message::Assistant(assistant_message) => (
+                    GeminiRole::Model,
+                    vec![GeminiPart::text(assistant_message.text.clone())],
+                ),
+                Message::FunctionCalls(function_calls_message) => {
+                    let parts = function_calls_message
+                        .0
+                        .iter()
+                        .map(|c| GeminiPart {
+                            function_call: Some(GeminiFunctionCall {
+                                name: c.name.clone(),
+                                args: Some(c.arguments.clone()),
+                            }),
+                            thought_signature: thought_signatures.get(&c.id).cloned(),
+                            ..Default::default()
+                        })
+                        .collect();
+                    (GeminiRole::Model, parts)
+                }
+                Message::FunctionResponse(function_response_message) => {
+                    // response は object でなければならない
+                    let response = match &function_response_message.result {
+                        Value::Object(_) => function_response_message.result.clone(),
+                        other => json!({ "result": other }),
+                    };
+                    let part = GeminiPart {
+                        function_response: Some(GeminiFunctionResponse {
+                            name: function_response_message.name.clone(),
+                            response,
+                        }),
+                        ..Default::default()
+                    };
+                    (GeminiRole::User, vec![part])
+                }
+            };
+
+            match contents.last_mut() {
+                Some(last) if last.role == role => last.parts.extend(parts),
+                _ => contents.push(GeminiContent { role, parts }),
+            }
+        }
+        Ok((system_parts, contents))
+    }
+
+    /// 画像 URL を part に変換する。ダウンロードに失敗した場合は URL をテキストとして伝える。
+    /// `fileData` は Files API か Cloud Storage の URI しか受け付けないので、ダウンロードして埋め込む。
+    async fn transform_image(&self, url: &Url) -> GeminiPart {
+        match download_image(url).await {
+            Ok(image) => GeminiPart {
+                inline_data: Some(GeminiInlineData {
+                    mime_type: image.mime_type,
+                    data: image.data,
+                }),
+                ..Default::default()
+            },
+            Err(err) => {
+                warn!("failed to download image {url}: {err}");
+                GeminiPart::text(format!("[画像: {url}]"))
+            }
+        }
+    }
+}
+
+/// 引数のない関数に空の OBJECT を渡すとエラーになるので、その判定に使う。
+fn has_fields(schema: &DescribedSchema) -> bool {
+    match &schema.field_type {
+        DescribedSchemaType::Object(fields) => !fields.is_empty(),
+        _ => true,
+    }
+}
+
+/// `DescribedSchema` を Gemini の Schema (OpenAPI のサブセット) に変換する。
+fn convert_gemini_schema(schema: &DescribedSchema) -> Value {
+    match &schema.field_type {
+        DescribedSchemaType::Integer => json!({
+            "type": "INTEGER",
+            "description": schema.description,
+        }),
+        DescribedSchemaType::Float => json!({
+            "type": "NUMBER",
+            "description": schema.description,
+        }),
+        DescribedSchemaType::Boolean => json!({
+            "type": "BOOLEAN",
+            "description": schema.description,
+        }),
+        DescribedSchemaType::String => json!({
+            "type": "STRING",
+            "description": schema.description,
+        }),
+        DescribedSchemaType::Object(fields) => {
+            let properties: HashMap<_, _> = fields
+                .iter()
+                .map(|f| (f.name.clone(), convert_gemini_schema(f)))
+                .collect();
+            let keys: Vec<_> = fields.iter().map(|f| f.name.clone()).collect();
+            json!({
+                "type": "OBJECT",
+                "description": schema.description,
+                "properties": properties,
+                "required": keys,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<GeminiSystemInstruction>,
+    contents: Vec<GeminiContent>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_config: Option<GeminiToolConfig>,
+    generation_config: GeminiGenerationConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiSystemInstruction {
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GeminiRole {
+    User,
+    Model,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiContent {
+    role: GeminiRole,
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiPart {
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    inline_data: Option<GeminiInlineData>,
+
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    function_call: Option<GeminiFunctionCall>,
+
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    function_response: Option<GeminiFunctionResponse>,
+
+    /// thinking の要約であるかどうか。応答にのみ現れる。
+    #[serde(default = "Default::default", skip_serializing)]
+    thought: bool,
+
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    thought_signature: Option<String>,
+}
+
+impl GeminiPart {
+    fn text(text: String) -> GeminiPart {
+        GeminiPart {
+            text: Some(text),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiInlineData {
+    mime_type: String,
+    data: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiFunctionCall {
+    name: String,
+
+    #[serde(default = "Default::default")]
+    args: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiFunctionResponse {
+    name: String,
+    response: Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct GeminiFunctionDeclaration {
+    name: String,
+    description: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiTool {
+    function_declarations: Vec<GeminiFunctionDeclaration>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiToolConfig {
+    function_calling_config: GeminiFunctionCallingConfig,
+}
+
+impl GeminiToolConfig {
+    /// いずれかの関数を必ず呼び出させる。
+    fn any() -> GeminiToolConfig {
+        GeminiToolConfig {
+            function_calling_config: GeminiFunctionCallingConfig { mode: "ANY" },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiFunctionCallingConfig {
+    mode: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiGenerationConfig {
+    max_output_tokens: usize,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    #[serde(default = "Default::default")]
+    candidates: Vec<GeminiCandidate>,
+
+    #[serde(default = "Default::default")]
+    prompt_feedback: Option<GeminiPromptFeedback>,
+
+    #[serde(default = "Default::default")]
+    usage_metadata: Option<GeminiUsageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCandidate {
+    #[serde(default = "Default::default")]
+    content: Option<GeminiContent>,
+
+    #[serde(default = "Default::default")]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiPromptFeedback {
+    #[serde(default = "Default::default")]
+    block_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiUsageMetadata {
+    #[serde(default = "Default::default")]
+    prompt_token_count: u64,
+
+    #[serde(default = "Default::default")]
+    candidates_token_count: u64,
+
+    #[serde(default = "Default::default")]
+    thoughts_token_count: u64,
+
+    #[serde(default = "Default::default")]
+    cached_content_token_count: u64,
+}
+
+impl From<GeminiUsageMetadata> for TokenUsage {
+    /// thinking のトークンも出力として課金されるので含める。
+    fn from(value: GeminiUsageMetadata) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: value.prompt_token_count,
+            completion_tokens: value.candidates_token_count + value.thoughts_token_count,
+            cached_tokens: value.cached_content_token_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::impls::llm::{
+        fixtures::{assistant, incomplete, user},
+        stand_in::StandInServer,
+    };
+
+    use serde_json::{from_value, to_value};
+
+    fn create_backend(endpoint: &str, structured: bool) -> GeminiBackend {
+        let config: AppConfigLlmGemini = toml::from_str(&format!(
+            "endpoint = \"{endpoint}\"\ntoken = \"test\"\nmodel = \"gemini-test\"\nmax_token = 100\nuse_structured_output = {structured}"
+        ))
+        .expect("invalid gemini config");
+        GeminiBackend::new(&config).expect("failed to create backend")
+    }
+
+    fn response_body(parts: Value) -> Value {
+        json!({
+            "candidates": [{ "content": { "role": "model", "parts": parts }, "finishReason": "STOP" }],
+            "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 5, "thoughtsTokenCount": 3 },
+        })
+    }
+
+    fn gemini_response(parts: Value) -> GeminiResponse {
+        from_value(response_body(parts)).expect("invalid response")
+    }
+
+    #[tokio::test]
+    async fn converts_described_schema_to_function_declarations() {
+        let backend = create_backend("http://127.0.0.1:1", false);
+        backend
+            .add_simple_function(SimpleFunctionDescriptor {
+                name: "search".to_string(),
+                description: "search something".to_string(),
+                parameters: DescribedSchema::object(
+                    "parameters",
+                    "search parameters",
+                    vec![
+                        DescribedSchema::string("query", "query text"),
+                        DescribedSchema::object("range", "range", vec![DescribedSchema::integer("limit", "limit")]),
+                    ],
+                ),
+            })
+            .await;
+        backend
+            .add_simple_function(SimpleFunctionDescriptor {
+                name: "self_info".to_string(),
+                description: "info".to_string(),
+                parameters: DescribedSchema::object("parameters", "none", vec![]),
+            })
+            .await;
+
+        let declarations = to_value(&*backend.0.tools.lock().await).expect("failed to serialize");
+        assert_eq!(
+            declarations,
+            json!([
+                {
+                    "name": "search",
+                    "description": "search something",
+                    "parameters": {
+                        "type": "OBJECT",
+                        "description": "search parameters",
+                        "properties": {
+                            "query": { "type": "STRING", "description": "query text" },
+                            "range": {
+                                "type": "OBJECT",
+                                "description": "range",
+                                "properties": { "limit": { "type": "INTEGER", "description": "limit" } },
+                                "required": ["limit"],
+                            },
+                        },
+                        "required": ["query", "range"],
+                    },
+                },
+                { "name": "self_info", "description": "info" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_function_calls_and_responses() {
+        let backend = create_backend("http://127.0.0.1:1", false);
+        let response = gemini_response(json!([
+            { "text": "thinking...", "thought": true },
+            { "text": "調べるッス" },
+            { "functionCall": { "name": "search", "args": { "query": "rust" } }, "thoughtSignature": "sig" },
+            { "functionCall": { "name": "self_info" } },
+        ]));
+        let update = backend
+            .0
+            .convert_response(response, false)
+            .await
+            .expect("failed to convert");
+
+        assert_eq!(update.response.map(|r| r.text).as_deref(), Some("調べるッス"));
+        let usage = update.usage.expect("no usage");
+        assert_eq!((usage.tokens.prompt_tokens, usage.tokens.completion_tokens), (10, 8));
+        let calls = update.tool_callings.expect("no tool calls");
+        assert_eq!(calls.len(), 2);
+        assert_ne!(calls[0].id, calls[1].id);
+        assert_eq!(calls[0].arguments, json!({ "query": "rust" }));
+        assert_eq!(calls[1].arguments, json!({}));
+
+        // 送り返すときは thought signature を付け直し、object でない結果は包む
+        let messages = [
+            Message::new_function_calls(calls.clone()),
+            Message::new_function_response(&calls[0].id, "search", json!({ "hits": 1 })),
+            Message::new_function_response(&calls[1].id, "self_info", json!("ok")),
+        ];
+        let (_, contents) = backend
+            .0
+            .transform_messages(messages.iter().collect())
+            .await
+            .expect("failed to transform");
+        assert_eq!(
+            to_value(&contents).expect("failed to serialize"),
+            json!([
+                {
+                    "role": "model",
+                    "parts": [
+                        { "functionCall": { "name": "search", "args": { "query": "rust" } }, "thoughtSignature": "sig" },
+                        { "functionCall": { "name": "self_info", "args": {} } },
+                    ],
+                },
+                {
+                    "role": "user",
+                    "parts": [
+                        { "functionResponse": { "name": "search", "response": { "hits": 1 } } },
+                        { "functionResponse": { "name": "self_info", "response": { "result": "ok" } } },
+                    ],
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn parses_structured_response() {
+        let backend = create_backend("http://127.0.0.1:1", true);
+        let text = json!({ "text": "こんにちは", "language": "ja", "sensitive": false }).to_string();
+        let update = backend
+            .0
+            .convert_response(gemini_response(json!([{ "text": text }])), true)
+            .await
+            .expect("failed to convert");
+
+        let response = update.response.expect("no response");
+        assert_eq!(response.text, "こんにちは");
+        assert_eq!(response.language.as_deref(), Some("ja"));
+    }
+
+    #[tokio::test]
+    async fn returns_structured_response_while_tools_are_registered() {
+        let arguments = json!({ "text": "こんにちは", "language": "ja", "sensitive": false });
+        let summary = json!({ "text": "要約", "language": "ja", "sensitive": false }).to_string();
+        let replies = vec![
+            response_body(json!([{ "functionCall": { "name": RESPONSE_FUNCTION_NAME, "args": arguments } }])),
+            response_body(json!([{ "text": summary }])),
+        ];
+        let server = StandInServer::spawn_sequence("/models/gemini-test:generateContent", replies).await;
+        let backend = create_backend(&server.endpoint, true);
+        backend
+            .add_simple_function(SimpleFunctionDescriptor {
+                name: "self_info".to_string(),
+                description: "info".to_string(),
+                parameters: DescribedSchema::object("parameters", "none", vec![]),
+            })
+            .await;
+
+        let mut conversation = incomplete(vec![user("hello")]);
+        let update = backend.send_conversation(&conversation).await.expect("request failed");
+        assert!(update.tool_callings.is_none());
+        let response = update.response.expect("no response");
+        assert_eq!(response.text, "こんにちは");
+        assert_eq!(response.language.as_deref(), Some("ja"));
+        assert_eq!(response.sensitive, Some(false));
+
+        // 要約など tool を使わない送信では responseSchema で返させる
+        conversation.use_tools = false;
+        let update = backend.send_conversation(&conversation).await.expect("request failed");
+        assert_eq!(update.response.map(|r| r.text).as_deref(), Some("要約"));
+
+        let requests = server.requests();
+        let names: Vec<_> = requests[0]["tools"][0]["functionDeclarations"]
+            .as_array()
+            .expect("no function declarations")
+            .iter()
+            .map(|d| d["name"].as_str().unwrap_or_default().to_string())
+            .collect();
+        assert_eq!(names, ["self_info", RESPONSE_FUNCTION_NAME]);
+        assert_eq!(
+            requests[0]["toolConfig"],
+            json!({ "functionCallingConfig": { "mode": "ANY" } })
+        );
+        assert!(requests[0]["generationConfig"].get("responseSchema").is_none());
+        assert!(requests[1].get("tools").is_none() && requests[1].get("toolConfig").is_none());
+        assert_eq!(requests[1]["generationConfig"]["responseSchema"]["type"], "OBJECT");
+    }
+
+    #[tokio::test]
+    async fn inlines_only_latest_user_images() {
+        let server = StandInServer::spawn_sequence("/models/gemini-test:generateContent", vec![]).await;
+        let image_url = Url::parse(&format!("{}/image.png", server.endpoint)).expect("invalid url");
+
+        let with_image = |text: &str| {
+            Message::new_user(
+                [
+                    UserMessageContent::Text(text.to_string()),
+                    UserMessageContent::ImageUrl(image_url.clone()),
+                ],
+                None,
+                None,
+            )
+        };
+        let messages = [with_image("old"), assistant("ok"), with_image("new")];
+        let (_, contents) = create_backend(&server.endpoint, false)
+            .0
+            .transform_messages(messages.iter().collect())
+            .await
+            .expect("failed to transform");
+
+        let contents = to_value(&contents).expect("failed to serialize");
+        assert_eq!(
+            contents[0]["parts"][1],
+            json!({ "text": format!("[画像: {image_url}]") })
+        );
+        assert_eq!(contents[2]["parts"][1]["inlineData"]["mimeType"], "image/png");
+    }
+}
@@ -0,0 +1,466 @@
+use crate::{
+    error::LlmError,
+    model::{
+        config::AppConfigLlmMock,
+        conversation::IncompleteConversation,
+        message::{Message, MessageFunctionCall, UserMessageContent},
+        usage::TokenUsage,
+    },
+    specs::{
+        function::simple::SimpleFunctionDescriptor,
+        llm::{Llm, LlmAssistantResponse, LlmUpdate, LlmUsage},
+    },
+};
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use futures::{FutureExt, future::BoxFuture};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{fs::read_to_string, time::sleep};
+use tracing::debug;
+use uuid::Uuid;
+
+/// スクリプトに従って応答を返すバックエンド。実際の API を使わずに動作確認するためのもの。
+#[derive(Debug, Clone)]
+pub struct MockBackend(Arc<MockBackendInner>);
+
+impl MockBackend {
+    pub async fn new(config: &AppConfigLlmMock) -> Result<MockBackend, LlmError> {
+        let rules = load_script(&config.script).await?;
+
+        Ok(MockBackend(Arc::new(MockBackendInner {
+            rules,
+            model: config.model.clone(),
+        })))
+    }
+}
+
+impl Llm for MockBackend {
+    fn add_simple_function(&self, _descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    fn send_conversation<'a>(
+        &'a self,
+        conversation: &'a IncompleteConversation,
+    ) -> BoxFuture<'a, Result<LlmUpdate, LlmError>> {
+        let cloned = self.0.clone();
+        async move { cloned.send_conversation(conversation).await }.boxed()
+    }
+}
+
+#[derive(Debug)]
+struct MockBackendInner {
+    rules: Vec<MockRule>,
+    model: String,
+}
+
+impl MockBackendInner {
+    async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
+        let position = MockPosition::of(&conversation.latest_messages);
+        let Some((index, rule)) = self.rules.iter().enumerate().find(|(_, r)| r.matches(&position)) else {
+            debug!("no mock rule matched: {position:?}");
+            return Err(LlmError::NoChoice);
+        };
+        debug!("mock rule #{index} matched: {position:?}");
+
+        if let Some(delay) = rule.delay {
+            sleep(delay).await;
+        }
+        rule.reply.to_update(&self.model)
+    }
+}
+
+/// スクリプトを読み込む。拡張子が `.jsonl` なら 1 行 1 ルール、それ以外は TOML の `[[rules]]` として扱う。
+async fn load_script(path: &Path) -> Result<Vec<MockRule>, LlmError> {
+    let script = read_to_string(path).await.map_err(|e| LlmError::Backend(e.into()))?;
+    parse_script(&script, path.extension().is_some_and(|e| e == "jsonl"))
+}
+
+fn parse_script(script: &str, jsonl: bool) -> Result<Vec<MockRule>, LlmError> {
+    let definitions: Vec<MockRuleDefinition> = if jsonl {
+        script
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(|e| LlmError::Backend(e.into()))?
+    } else {
+        let script: MockScript = toml::from_str(script).map_err(|e| LlmError::Backend(e.into()))?;
+        script.rules
+    };
+
+    definitions
+        .into_iter()
+        .enumerate()
+        .map(|(i, d)| {
+            d.compile()
+                .map_err(|e| LlmError::Backend(format!("mock rule #{i}: {e}").into()))
+        })
+        .collect()
+}
+
+/// 会話の中での現在位置。
+#[derive(Debug)]
+struct MockPosition {
+    /// user message の数。
+    turn: usize,
+
+    /// 最後の user message 以降の tool calling の回数。
+    round: usize,
+
+    /// 最後の user message のテキスト。
+    last_user_text: Option<String>,
+}
+
+impl MockPosition {
+    fn of(messages: &[Message]) -> MockPosition {
+        let turn = messages.iter().filter(|m| matches!(m, Message::User(_))).count();
+        let last_user_index = messages.iter().rposition(|m| matches!(m, Message::User(_)));
+        let round = messages[last_user_index.map_or(0, |i| i + 1)..]
+            .iter()
+            .filter(|m| matches!(m, Message::FunctionCalls(_)))
+            .count();
+        let last_user_text = last_user_index.and_then(|i| match &messages[i] {
+            Message::User(user_message) => {
+                let texts: Vec<_> = user_message
+                    .contents
+                    .iter()
+                    .filter_map(|umc| match umc {
+                        UserMessageContent::Text(text) => Some(text.as_str()),
+                        UserMessageContent::ImageUrl(_) => None,
+                    })
+                    .collect();
+                Some(texts.join("\n"))
+            }
+            _ => None,
+        });
+
+        MockPosition {
+            turn,
+            round,
+            last_user_text,
+        }
+    }
+}
+
+/// スクリプトの TOML 形式。
+#[derive(Debug, Deserialize)]
+struct MockScript {
+    rules: Vec<MockRuleDefinition>,
+}
+
+/// スクリプトの 1 ルール。条件はすべて満たす必要があり、先頭から順に評価する。
+#[derive(Debug, Deserialize)]
+struct MockRuleDefinition {
+    /// 何番目のユーザー入力か(1 始まり)。要約後は残っているものだけを数える。
+    #[serde(default = "Default::default")]
+    turn: Option<usize>,
+
+    /// 同じユーザー入力に対する何回目の tool calling の後か(0 始まり)。
+    #[serde(default = "Default::default")]
+    round: Option<usize>,
+
+    /// 最後のユーザー入力にマッチする正規表現。
+    #[serde(default = "Default::default")]
+    pattern: Option<String>,
+
+    /// 応答を返すまでの待ち時間。
+    #[serde(default = "Default::default")]
+    delay_ms: Option<u64>,
+
+    #[serde(default = "Default::default")]
+    text: Option<String>,
+
+    #[serde(default = "Default::default")]
+    language: Option<String>,
+
+    #[serde(default = "Default::default")]
+    sensitive: Option<bool>,
+
+    #[serde(default = "Default::default")]
+    tool_calls: Vec<MockToolCall>,
+
+    #[serde(default = "Default::default")]
+    error: Option<MockError>,
+}
+
+impl MockRuleDefinition {
+    fn compile(self) -> Result<MockRule, String> {
+        let pattern = self
+            .pattern
+            .map(|p| Regex::new(&p))
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        let has_reply = self.text.is_some() || !self.tool_calls.is_empty();
+        let reply = match (self.error, has_reply) {
+            (Some(error), false) => MockReply::Error(error),
+            (None, true) => MockReply::Update {
+                response: self.text.map(|text| LlmAssistantResponse {
+                    text,
+                    language: self.language,
+                    sensitive: self.sensitive,
+                }),
+                tool_calls: self.tool_calls,
+            },
+            (Some(_), true) => return Err("error cannot be combined with text or tool_calls".to_string()),
+            (None, false) => return Err("one of text, tool_calls or error is required".to_string()),
+        };
+
+        Ok(MockRule {
+            turn: self.turn,
+            round: self.round,
+            pattern,
+            delay: self.delay_ms.map(Duration::from_millis),
+            reply,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MockToolCall {
+    name: String,
+
+    #[serde(default = "default_mock_arguments")]
+    arguments: Value,
+}
+
+fn default_mock_arguments() -> Value {
+    json!({})
+}
+
+#[derive(Debug, Deserialize)]
+struct MockError {
+    kind: MockErrorKind,
+
+    #[serde(default = "default_mock_error_message")]
+    message: String,
+
+    /// `kind = "unavailable"` のときの `Retry-After` 相当。
+    #[serde(default = "Default::default")]
+    retry_after_ms: Option<u64>,
+}
+
+fn default_mock_error_message() -> String {
+    "scripted error".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MockErrorKind {
+    Communication,
+    Backend,
+    Unavailable,
+    NoChoice,
+    ResponseFormat,
+}
+
+#[derive(Debug)]
+struct MockRule {
+    turn: Option<usize>,
+    round: Option<usize>,
+    pattern: Option<Regex>,
+    delay: Option<Duration>,
+    reply: MockReply,
+}
+
+impl MockRule {
+    fn matches(&self, position: &MockPosition) -> bool {
+        let turn_matched = self.turn.is_none_or(|t| t == position.turn);
+        let round_matched = self.round.is_none_or(|r| r == position.round);
+        let pattern_matched = match (&self.pattern, &position.last_user_text) {
+            (None, _) => true,
+            (Some(pattern), Some(text)) => pattern.is_match(text),
+            (Some(_), None) => false,
+        };
+        turn_matched && round_matched && pattern_matched
+    }
+}
+
+#[derive(Debug)]
+enum MockReply {
+    Update {
+        response: Option<LlmAssistantResponse>,
+        tool_calls: Vec<MockToolCall>,
+    },
+    Error(MockError),
+}
+
+impl MockReply {
+    fn to_update(&self, model: &str) -> Result<LlmUpdate, LlmError> {
+        let (response, tool_calls) = match self {
+            MockReply::Update { response, tool_calls } => (response, tool_calls),
+            MockReply::Error(error) => return Err(error.to_llm_error()),
+        };
+
+        let tool_callings: Vec<_> = tool_calls
+            .iter()
+            .map(|c| MessageFunctionCall {
+                id: format!("call_{}", Uuid::now_v7().simple()),
+                name: c.name.clone(),
+                arguments: c.arguments.clone(),
+            })
+            .collect();
+        Ok(LlmUpdate {
+            response: response.clone(),
+            tool_callings: (!tool_callings.is_empty()).then_some(tool_callings),
+            usage: Some(LlmUsage {
+                model: model.to_string(),
+                tokens: TokenUsage::default(),
+            }),
+        })
+    }
+}
+
+impl MockError {
+    fn to_llm_error(&self) -> LlmError {
+        let source = self.message.clone().into();
+        match self.kind {
+            MockErrorKind::Communication => LlmError::Communication(source),
+            MockErrorKind::Backend => LlmError::Backend(source),
+            MockErrorKind::Unavailable => LlmError::Unavailable {
+                retry_after: self.retry_after_ms.map(Duration::from_millis),
+                source,
+            },
+            MockErrorKind::NoChoice => LlmError::NoChoice,
+            MockErrorKind::ResponseFormat => LlmError::ResponseFormat(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::llm::fixtures::{assistant, incomplete, user};
+
+    use std::time::Instant;
+
+    fn backend(script: &str) -> MockBackendInner {
+        MockBackendInner {
+            rules: parse_script(script, false).expect("invalid script"),
+            model: "mock".to_string(),
+        }
+    }
+
+    fn backend_error(script: &str, jsonl: bool) -> String {
+        match parse_script(script, jsonl) {
+            Err(LlmError::Backend(err)) => err.to_string(),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    async fn reply_text(backend: &MockBackendInner, messages: Vec<Message>) -> Option<String> {
+        let update = backend.send_conversation(&incomplete(messages)).await.ok()?;
+        update.response.map(|r| r.text)
+    }
+
+    #[test]
+    fn counts_turns_and_rounds() {
+        let call = MessageFunctionCall {
+            id: "call_1".to_string(),
+            name: "self_info".to_string(),
+            arguments: json!({}),
+        };
+        let messages = vec![
+            Message::new_system("system"),
+            user("first"),
+            assistant("reply"),
+            user("second"),
+            Message::new_function_calls([call]),
+            Message::new_function_response("call_1", "self_info", json!({})),
+        ];
+
+        let position = MockPosition::of(&messages);
+        assert_eq!(position.turn, 2);
+        assert_eq!(position.round, 1);
+        assert_eq!(position.last_user_text.as_deref(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn matches_rules_in_order() {
+        let backend = backend(
+            r#"
+[[rules]]
+pattern = "^help"
+text = "help"
+
+[[rules]]
+turn = 2
+text = "second turn"
+
+[[rules]]
+text = "fallback"
+"#,
+        );
+
+        assert_eq!(
+            reply_text(&backend, vec![user("hello")]).await.as_deref(),
+            Some("fallback")
+        );
+        assert_eq!(
+            reply_text(&backend, vec![user("help me")]).await.as_deref(),
+            Some("help")
+        );
+        let second_turn = vec![user("hello"), assistant("fallback"), user("again")];
+        assert_eq!(reply_text(&backend, second_turn).await.as_deref(), Some("second turn"));
+    }
+
+    #[tokio::test]
+    async fn fails_without_matching_rule() {
+        let backend = backend("[[rules]]\nturn = 2\ntext = \"second turn\"");
+        let result = backend.send_conversation(&incomplete(vec![user("hello")])).await;
+        assert!(matches!(result, Err(LlmError::NoChoice)));
+    }
+
+    #[tokio::test]
+    async fn waits_for_delay() {
+        let backend = backend("[[rules]]\ndelay_ms = 100\ntext = \"late\"");
+        let started_at = Instant::now();
+        assert_eq!(reply_text(&backend, vec![user("hello")]).await.as_deref(), Some("late"));
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn parses_jsonl_script() {
+        let script = r#"
+{"round": 0, "tool_calls": [{"name": "self_info"}]}
+
+{"text": "done", "sensitive": true}
+{"error": {"kind": "unavailable", "retry_after_ms": 500}}
+"#;
+        let rules = parse_script(script, true).expect("invalid script");
+        assert_eq!(rules.len(), 3);
+
+        let update = rules[0].reply.to_update("mock").expect("no update");
+        let calls = update.tool_callings.expect("no tool calls");
+        assert_eq!((calls[0].name.as_str(), &calls[0].arguments), ("self_info", &json!({})));
+        let update = rules[1].reply.to_update("mock").expect("no update");
+        assert_eq!(update.response.and_then(|r| r.sensitive), Some(true));
+        match rules[2].reply.to_update("mock") {
+            Err(LlmError::Unavailable { retry_after, source }) => {
+                assert_eq!(retry_after, Some(Duration::from_millis(500)));
+                assert_eq!(source.to_string(), "scripted error");
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn reports_invalid_rules() {
+        assert_eq!(
+            backend_error("[[rules]]\ntext = \"a\"\nerror = { kind = \"backend\" }", false),
+            "mock rule #0: error cannot be combined with text or tool_calls"
+        );
+        assert_eq!(
+            backend_error("{\"text\": \"a\"}\n{\"turn\": 1}", true),
+            "mock rule #1: one of text, tool_calls or error is required"
+        );
+        assert!(
+            backend_error("[[rules]]\npattern = \"(\"\ntext = \"a\"", false)
+                .starts_with("mock rule #0: regex parse error")
+        );
+        assert!(parse_script("{\"text\": ", true).is_err());
+    }
+}
@@ -0,0 +1,540 @@
+use crate::{
+    USER_AGENT,
+    error::LlmError,
+    impls::llm::{ASSISTANT_RESPONSE_SCHEMA, check_http_status, convert_json_schema, download_image},
+    model::{
+        config::{AppConfigContextBudget, AppConfigLlmOllama, AppConfigLlmOllamaStructuredOutput},
+        conversation::IncompleteConversation,
+        message::{Message, MessageFunctionCall, UserMessage, UserMessageContent},
+        usage::TokenUsage,
+    },
+    specs::{
+        function::simple::SimpleFunctionDescriptor,
+        llm::{Llm, LlmAssistantResponse, LlmUpdate, LlmUsage},
+    },
+};
+
+use std::sync::{Arc, LazyLock};
+
+use futures::{FutureExt, future::BoxFuture};
+use regex::Regex;
+use reqwest::{
+    Client,
+    header::{CONTENT_TYPE, HeaderMap, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+/// `Prompt` モードで応答の先頭行に書かせるメタデータ。
+static RE_META_LINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)^\s*\[?\s*language\s*[=:]\s*([A-Za-z0-9-]+)\s*,?\s*sensitive\s*[=:]\s*(true|false)\s*\]?\s*$"#)
+        .expect("invalid regex")
+});
+
+const META_INSTRUCTION: &str = "応答の 1 行目には `language=<IETF BCP47 言語タグ> sensitive=<true|false>` の形式で、本文の言語と性的な話題を含むかどうかだけを書いてください。本文は 2 行目から書いてください。";
+
+/// Ollama の chat API を利用したバックエンド。
+#[derive(Debug, Clone)]
+pub struct OllamaBackend(Arc<OllamaBackendInner>);
+
+impl OllamaBackend {
+    pub fn new(config: &AppConfigLlmOllama) -> Result<OllamaBackend, LlmError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .default_headers(headers)
+            .build()?;
+
+        Ok(OllamaBackend(Arc::new(OllamaBackendInner {
+            client,
+            endpoint: format!("{}/api/chat", config.endpoint.trim_end_matches('/')),
+            tools: Mutex::new(vec![]),
+            model: config.model.clone(),
+            max_token: config.max_token,
+            structured_output: config.structured_output,
+            use_tools: config.use_tools,
+            use_images: config.use_images,
+            context_budget: config.context_budget,
+        })))
+    }
+}
+
+impl Llm for OllamaBackend {
+    fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
+        async { self.0.add_simple_function(descriptor).await }.boxed()
+    }
+
+    fn send_conversation<'a>(
+        &'a self,
+        conversation: &'a IncompleteConversation,
+    ) -> BoxFuture<'a, Result<LlmUpdate, LlmError>> {
+        let cloned = self.0.clone();
+        async move { cloned.send_conversation(conversation).await }.boxed()
+    }
+}
+
+#[derive(Debug)]
+struct OllamaBackendInner {
+    client: Client,
+    endpoint: String,
+    tools: Mutex<Vec<Value>>,
+    model: String,
+    max_token: usize,
+    structured_output: AppConfigLlmOllamaStructuredOutput,
+    use_tools: bool,
+    use_images: bool,
+    context_budget: Option<AppConfigContextBudget>,
+}
+
+impl OllamaBackendInner {
+    async fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) {
+        if !self.use_tools {
+            return;
+        }
+
+        let tool = json!({
+            "type": "function",
+            "function": {
+                "name": descriptor.name,
+                "description": descriptor.description,
+                "parameters": convert_json_schema(&descriptor.parameters),
+            },
+        });
+        let mut locked = self.tools.lock().await;
+        locked.push(tool);
+    }
+
+    async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
+        let budgeted_messages = conversation.budgeted_messages(self.context_budget.as_ref());
+        // 履歴の画像を毎回取得し直さないように、画像を送るのは最新のユーザー入力だけにする
+        let latest_user_index = budgeted_messages.iter().rposition(|m| matches!(m, Message::User(_)));
+        let mut messages = vec![];
+        for (index, message) in budgeted_messages.into_iter().enumerate() {
+            let ollama_message = match message {
+                Message::User(user_message) if self.use_images && Some(index) == latest_user_index => {
+                    transform_user_message_with_images(user_message).await
+                }
+                message => transform_message(message)?,
+            };
+            messages.push(ollama_message);
+        }
+        if self.structured_output == AppConfigLlmOllamaStructuredOutput::Prompt {
+            // system prompt の直後に置く
+            let position = messages.iter().take_while(|m| m.role == "system").count();
+            messages.insert(position, OllamaMessage::text("system", META_INSTRUCTION.to_string()));
+        }
+
+        let format = (self.structured_output == AppConfigLlmOllamaStructuredOutput::JsonSchema)
+            .then(|| convert_json_schema(&ASSISTANT_RESPONSE_SCHEMA));
+        let tools = if conversation.use_tools {
+            self.tools.lock().await.clone()
+        } else {
+            vec![]
+        };
+        let request = OllamaRequest {
+            model: self.model.clone(),
+            messages,
+            tools,
+            format,
+            stream: false,
+            options: json!({ "num_predict": self.max_token }),
+        };
+
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .body(serde_json::to_vec(&request)?)
+            .send()
+            .await?;
+        let response = check_http_status(response).await?;
+        let ollama_response: OllamaResponse = serde_json::from_slice(&response.bytes().await?)?;
+        self.convert_response(ollama_response)
+    }
+
+    fn convert_response(&self, ollama_response: OllamaResponse) -> Result<LlmUpdate, LlmError> {
+        let usage = LlmUsage {
+            model: self.model.clone(),
+            tokens: TokenUsage {
+                prompt_tokens: ollama_response.prompt_eval_count.unwrap_or_default(),
+                completion_tokens: ollama_response.eval_count.unwrap_or_default(),
+                cached_tokens: 0,
+            },
+        };
+
+        // Ollama は tool calling に ID を振らないのでこちらで振る
+        let tool_callings: Vec<_> = ollama_response
+            .message
+            .tool_calls
+            .into_iter()
+            .map(|c| MessageFunctionCall {
+                id: format!("call_{}", Uuid::now_v7().simple()),
+                name: c.function.name,
+                arguments: c.function.arguments,
+            })
+            .collect();
+
+        let text = ollama_response.message.content;
+        let response = match (text.trim().is_empty(), self.structured_output) {
+            (true, _) => None,
+            (false, AppConfigLlmOllamaStructuredOutput::JsonSchema) => {
+                Some(serde_json::from_str(&text).map_err(|e| LlmError::ResponseFormat(e.into()))?)
+            }
+            (false, AppConfigLlmOllamaStructuredOutput::Prompt) => parse_meta_line(&text),
+            (false, AppConfigLlmOllamaStructuredOutput::None) => Some(LlmAssistantResponse {
+                text,
+                language: None,
+                sensitive: None,
+            }),
+        };
+        if response.is_none() && tool_callings.is_empty() {
+            return Err(LlmError::NoChoice);
+        }
+
+        Ok(LlmUpdate {
+            response,
+            tool_callings: (!tool_callings.is_empty()).then_some(tool_callings),
+            usage: Some(usage),
+        })
+    }
+}
+
+/// 先頭行のメタデータを取り出す。指示に従っていなければ全体を本文として扱う。
+/// メタデータだけで本文がなければ `None` を返す。
+fn parse_meta_line(text: &str) -> Option<LlmAssistantResponse> {
+    let text = text.trim();
+    let (first_line, rest) = text.split_once('\n').unwrap_or((text, ""));
+    let response = match RE_META_LINE.captures(first_line) {
+        Some(captures) => LlmAssistantResponse {
+            text: rest.trim().to_string(),
+            language: Some(captures[1].to_string()),
+            sensitive: Some(captures[2].eq_ignore_ascii_case("true")),
+        },
+        None => LlmAssistantResponse {
+            text: text.to_string(),
+            language: None,
+            sensitive: None,
+        },
+    };
+    (!response.text.is_empty()).then_some(response)
+}
+
+/// 画像を base64 で添付した user message にする。取得できなかった画像は URL をテキストとして伝える。
+async fn transform_user_message_with_images(user_message: &UserMessage) -> OllamaMessage {
+    let mut texts = vec![];
+    let mut images = vec![];
+    for umc in &user_message.contents {
+        match umc {
+            UserMessageContent::Text(text) => texts.push(text.clone()),
+            UserMessageContent::ImageUrl(url) => match download_image(url).await {
+                Ok(image) => images.push(image.data),
+                Err(err) => {
+                    warn!("failed to download image {url}: {err}");
+                    texts.push(format!("[画像: {url}]"));
+                }
+            },
+        }
+    }
+
+    OllamaMessage {
+        images,
+        ..OllamaMessage::text("user", texts.join("\n"))
+    }
+}
+
+fn transform_message(message: &Message) -> Result<OllamaMessage, LlmError> {
+    let message = match message {
+        Message::System(system_message) => OllamaMessage::text("system", system_message.0.clone()),
+        Message::Summary(summary_message) => {
+            OllamaMessage::text("system", format!("これまでの会話の要約:\n{}", summary_message.0))
+        }
+        Message::User(user_message) => {
+            // 画像は base64 で渡す必要があるので、最新の入力以外では URL をテキストとして伝えるに留める
+            let text = user_message
+                .contents
+                .iter()
+                .map(|umc| match umc {
+                    UserMessageContent::Text(text) => text.clone(),
+                    UserMessageContent::ImageUrl(url) => format!("[画像: {url}]"),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            OllamaMessage::text("user", text)
+        }
+        Message::Assistant(assistant_message) => OllamaMessage::text("assistant", assistant_message.text.clone()),
+        Message::FunctionCalls(function_calls_message) => OllamaMessage {
+            tool_calls: function_calls_message
+                .0
+                .iter()
+                .map(|c| OllamaToolCall {
+                    function: OllamaFunctionCall {
+                        name: c.name.clone(),
+                        arguments: c.arguments.clone(),
+                    },
+                })
+                .collect(),
+            ..OllamaMessage::text("assistant", String::new())
+        },
+        Message::FunctionResponse(function_response_message) => {
+            OllamaMessage::text("tool", serde_json::to_string(&function_response_message.result)?)
+        }
+    };
+    Ok(message)
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+    stream: bool,
+    options: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaMessage {
+    role: &'static str,
+    content: String,
+
+    /// base64 の画像。
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+impl OllamaMessage {
+    fn text(role: &'static str, content: String) -> OllamaMessage {
+        OllamaMessage {
+            role,
+            content,
+            images: vec![],
+            tool_calls: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    arguments: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaResponse {
+    message: OllamaResponseMessage,
+
+    #[serde(default = "Default::default")]
+    prompt_eval_count: Option<u64>,
+
+    #[serde(default = "Default::default")]
+    eval_count: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaResponseMessage {
+    #[serde(default = "Default::default")]
+    content: String,
+
+    #[serde(default = "Default::default")]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        impls::llm::{
+            fixtures::{assistant, incomplete, user},
+            stand_in::{PNG_HEADER, StandInServer},
+        },
+        model::schema::DescribedSchema,
+    };
+
+    use base64::{Engine, prelude::BASE64_STANDARD};
+    use url::Url;
+
+    fn create_backend(endpoint: &str, structured_output: &str, use_images: bool) -> OllamaBackend {
+        let config: AppConfigLlmOllama = toml::from_str(&format!(
+            "endpoint = \"{endpoint}\"\nmodel = \"test-model\"\nmax_token = 100\nstructured_output = \"{structured_output}\"\nuse_images = {use_images}"
+        ))
+        .expect("invalid ollama config");
+        OllamaBackend::new(&config).expect("failed to create backend")
+    }
+
+    fn reply(content: &str) -> Value {
+        json!({ "message": { "role": "assistant", "content": content }, "prompt_eval_count": 10, "eval_count": 5 })
+    }
+
+    #[tokio::test]
+    async fn assigns_ids_to_tool_calls() {
+        let tool_calls = json!({
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [
+                    { "function": { "name": "self_info", "arguments": {} } },
+                    { "function": { "name": "self_info", "arguments": { "verbose": true } } },
+                ],
+            },
+        });
+        let server = StandInServer::spawn_sequence("/api/chat", vec![tool_calls, reply("done")]).await;
+        let backend = create_backend(&server.endpoint, "none", false);
+        backend
+            .add_simple_function(SimpleFunctionDescriptor {
+                name: "self_info".to_string(),
+                description: "info".to_string(),
+                parameters: DescribedSchema::object("parameters", "none", vec![]),
+            })
+            .await;
+
+        let mut messages = vec![user("version?")];
+        let update = backend
+            .send_conversation(&incomplete(messages.clone()))
+            .await
+            .expect("request failed");
+        assert!(update.response.is_none());
+        let calls = update.tool_callings.expect("no tool calls");
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|c| c.id.starts_with("call_") && c.name == "self_info"));
+        assert_ne!(calls[0].id, calls[1].id);
+        assert_eq!(calls[1].arguments, json!({ "verbose": true }));
+
+        messages.push(Message::new_function_calls(calls.clone()));
+        messages.push(Message::new_function_response(
+            &calls[0].id,
+            "self_info",
+            json!({ "v": 1 }),
+        ));
+        let update = backend
+            .send_conversation(&incomplete(messages))
+            .await
+            .expect("request failed");
+        assert_eq!(update.response.map(|r| r.text).as_deref(), Some("done"));
+
+        let requests = server.requests();
+        assert_eq!(requests[0]["tools"][0]["function"]["name"], "self_info");
+        let sent = &requests[1]["messages"];
+        assert_eq!(sent[1]["role"], "assistant");
+        assert_eq!(
+            sent[1]["tool_calls"][1]["function"]["arguments"],
+            json!({ "verbose": true })
+        );
+        assert_eq!(sent[2], json!({ "role": "tool", "content": "{\"v\":1}" }));
+    }
+
+    #[tokio::test]
+    async fn parses_json_schema_response() {
+        let content = json!({ "text": "こんにちは", "language": "ja", "sensitive": false }).to_string();
+        let server = StandInServer::spawn_sequence("/api/chat", vec![reply(&content)]).await;
+        let backend = create_backend(&server.endpoint, "json_schema", false);
+
+        let update = backend
+            .send_conversation(&incomplete(vec![user("hello")]))
+            .await
+            .expect("request failed");
+        let response = update.response.expect("no response");
+        assert_eq!(response.text, "こんにちは");
+        assert_eq!(response.language.as_deref(), Some("ja"));
+        assert_eq!(response.sensitive, Some(false));
+        let usage = update.usage.expect("no usage");
+        assert_eq!((usage.tokens.prompt_tokens, usage.tokens.completion_tokens), (10, 5));
+
+        let requests = server.requests();
+        assert_eq!(requests[0]["format"]["type"], "object");
+        assert_eq!(requests[0]["stream"], false);
+    }
+
+    #[tokio::test]
+    async fn reads_meta_line_in_prompt_mode() {
+        let replies = vec![reply("language=ja sensitive=true\n本文です"), reply("本文だけです")];
+        let server = StandInServer::spawn_sequence("/api/chat", replies).await;
+        let backend = create_backend(&server.endpoint, "prompt", false);
+        let messages = vec![Message::new_system("identity"), user("hello")];
+
+        let with_meta = backend
+            .send_conversation(&incomplete(messages.clone()))
+            .await
+            .expect("request failed")
+            .response
+            .expect("no response");
+        assert_eq!(with_meta.text, "本文です");
+        assert_eq!(with_meta.language.as_deref(), Some("ja"));
+        assert_eq!(with_meta.sensitive, Some(true));
+
+        let without_meta = backend
+            .send_conversation(&incomplete(messages))
+            .await
+            .expect("request failed")
+            .response
+            .expect("no response");
+        assert_eq!(without_meta.text, "本文だけです");
+        assert!(without_meta.language.is_none() && without_meta.sensitive.is_none());
+
+        // 指示は system prompt の直後に入る
+        let requests = server.requests();
+        let roles: Vec<_> = requests[0]["messages"]
+            .as_array()
+            .expect("no messages")
+            .iter()
+            .map(|m| m["role"].as_str().unwrap_or_default().to_string())
+            .collect();
+        assert_eq!(roles, ["system", "system", "user"]);
+        assert_eq!(requests[0]["messages"][1]["content"], META_INSTRUCTION);
+        assert!(requests[0].get("format").is_none());
+    }
+
+    #[test]
+    fn parses_meta_line_variants() {
+        let bracketed = parse_meta_line("[language: en-US, sensitive: FALSE]\nHello").expect("no response");
+        assert_eq!(bracketed.text, "Hello");
+        assert_eq!(bracketed.language.as_deref(), Some("en-US"));
+        assert_eq!(bracketed.sensitive, Some(false));
+
+        assert!(parse_meta_line("language=ja sensitive=false").is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_only_latest_user_images() {
+        let server = StandInServer::spawn_sequence("/api/chat", vec![reply("見たッス")]).await;
+        let backend = create_backend(&server.endpoint, "none", true);
+        let image_url = Url::parse(&format!("{}/image.png", server.endpoint)).expect("invalid url");
+        let with_image = |text: &str| {
+            Message::new_user(
+                [
+                    UserMessageContent::Text(text.to_string()),
+                    UserMessageContent::ImageUrl(image_url.clone()),
+                ],
+                None,
+                None,
+            )
+        };
+        let messages = vec![with_image("old"), assistant("ok"), with_image("new")];
+
+        backend
+            .send_conversation(&incomplete(messages))
+            .await
+            .expect("request failed");
+
+        let requests = server.requests();
+        let sent = &requests[0]["messages"];
+        assert_eq!(sent[0]["content"], format!("old\n[画像: {image_url}]"));
+        assert!(sent[0].get("images").is_none());
+        assert_eq!(sent[2]["content"], "new");
+        assert_eq!(sent[2]["images"], json!([BASE64_STANDARD.encode(PNG_HEADER)]));
+    }
+}
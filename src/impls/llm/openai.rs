@@ -9,11 +9,18 @@ pub use responses::ResponsesBackend;
 use crate::{
     USER_AGENT,
     error::LlmError,
-    impls::llm::{ASSISTANT_RESPONSE_SCHEMA, convert_json_schema},
+    impls::llm::{
+        ASSISTANT_RESPONSE_SCHEMA, convert_json_schema, is_unavailable_api_error, is_unavailable_status,
+        parse_retry_after,
+    },
     model::config::AppConfigLlmOpenai,
 };
 
-use async_openai::{Client, config::OpenAIConfig, types::ResponseFormatJsonSchema};
+use async_openai::{error::ApiError, types::ResponseFormatJsonSchema};
+use eventsource_stream::Eventsource;
+use futures::{StreamExt, future, stream::BoxStream};
+use reqwest::{Response, header::CONTENT_TYPE};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 static RESPONSE_JSON_SCHEMA: LazyLock<ResponseFormatJsonSchema> = LazyLock::new(|| ResponseFormatJsonSchema {
     name: "response".into(),
@@ -22,12 +29,109 @@ static RESPONSE_JSON_SCHEMA: LazyLock<ResponseFormatJsonSchema> = LazyLock::new(
     strict: Some(true),
 });
 
-async fn create_openai_client(openai_config: &AppConfigLlmOpenai) -> Result<Client<OpenAIConfig>, LlmError> {
-    let config = OpenAIConfig::new()
-        .with_api_key(&openai_config.token)
-        .with_api_base(&openai_config.endpoint);
-    let http_client = reqwest::ClientBuilder::new().user_agent(USER_AGENT).build()?;
+/// OpenAI 互換 API のクライアント。
+/// async-openai の `Client` は内部でリトライする上に `Retry-After` を捨ててしまうため、
+/// 通信はここで行い、async-openai は型定義のためだけに使う。リトライは `RetryLlm` に任せる。
+#[derive(Debug, Clone)]
+struct OpenaiClient {
+    http_client: reqwest::Client,
+    endpoint: String,
+    token: String,
+}
+
+impl OpenaiClient {
+    fn new(openai_config: &AppConfigLlmOpenai) -> Result<OpenaiClient, LlmError> {
+        let http_client = reqwest::ClientBuilder::new().user_agent(USER_AGENT).build()?;
+        Ok(OpenaiClient {
+            http_client,
+            endpoint: openai_config.endpoint.trim_end_matches('/').to_string(),
+            token: openai_config.token.clone(),
+        })
+    }
+
+    /// リクエストを送り、応答全体を受け取る。
+    async fn post<Req: Serialize, Res: DeserializeOwned>(&self, path: &str, request: &Req) -> Result<Res, LlmError> {
+        let response = self.send(path, request).await?;
+        let body = response.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// リクエストを送り、Server-Sent Events として応答を受け取る。
+    async fn post_stream<Req: Serialize, Res: DeserializeOwned + 'static>(
+        &self,
+        path: &str,
+        request: &Req,
+    ) -> Result<BoxStream<'static, Result<Res, LlmError>>, LlmError> {
+        let response = self.send(path, request).await?;
+        let stream = response
+            .bytes_stream()
+            .eventsource()
+            .take_while(|event| future::ready(!matches!(event, Ok(e) if e.data == "[DONE]")))
+            .map(|event| match event {
+                Ok(event) => parse_stream_data(&event.data),
+                Err(err) => Err(LlmError::Communication(err.to_string().into())),
+            });
+        Ok(stream.boxed())
+    }
+
+    async fn send<Req: Serialize>(&self, path: &str, request: &Req) -> Result<Response, LlmError> {
+        let response = self
+            .http_client
+            .post(format!("{}{path}", self.endpoint))
+            .bearer_auth(&self.token)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(request)?)
+            .send()
+            .await?;
+        check_openai_status(response).await
+    }
+}
+
+/// OpenAI 互換 API のエラー応答。
+#[derive(Debug, Deserialize)]
+struct OpenaiErrorResponse {
+    error: ApiError,
+}
+
+/// 応答のステータスを確認し、失敗していればエラー本文と `Retry-After` から `LlmError` に変換する。
+async fn check_openai_status(response: Response) -> Result<Response, LlmError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = parse_retry_after(response.headers());
+    let body = response.text().await.unwrap_or_default();
+    let Ok(OpenaiErrorResponse { error }) = serde_json::from_str(&body) else {
+        let source = format!("HTTP {status}: {body}").into();
+        return Err(if is_unavailable_status(status) {
+            LlmError::Unavailable { retry_after, source }
+        } else {
+            LlmError::Backend(source)
+        });
+    };
+
+    // クォータ切れは 429 で返ってくるが、待っても回復しない
+    let quota_exceeded = error.code.as_deref() == Some("insufficient_quota");
+    let source = format!("HTTP {status}: {error}").into();
+    if (is_unavailable_status(status) && !quota_exceeded) || is_unavailable_api_error(&error) {
+        Err(LlmError::Unavailable { retry_after, source })
+    } else {
+        Err(LlmError::Backend(source))
+    }
+}
 
-    let client = Client::with_config(config).with_http_client(http_client);
-    Ok(client)
+/// ストリームの 1 イベントを解釈する。途中でエラーが送られてくることもある。
+fn parse_stream_data<Res: DeserializeOwned>(data: &str) -> Result<Res, LlmError> {
+    match serde_json::from_str(data) {
+        Ok(chunk) => Ok(chunk),
+        Err(err) => match serde_json::from_str::<OpenaiErrorResponse>(data) {
+            Ok(OpenaiErrorResponse { error }) if is_unavailable_api_error(&error) => Err(LlmError::Unavailable {
+                retry_after: None,
+                source: error.to_string().into(),
+            }),
+            Ok(OpenaiErrorResponse { error }) => Err(LlmError::Backend(error.to_string().into())),
+            Err(_) => Err(err.into()),
+        },
+    }
 }
@@ -2,33 +2,36 @@ use crate::{
     error::LlmError,
     impls::llm::{
         convert_json_schema,
-        openai::{RESPONSE_JSON_SCHEMA, create_openai_client},
+        openai::{OpenaiClient, RESPONSE_JSON_SCHEMA},
     },
     model::{
-        config::AppConfigLlmOpenai,
+        config::{AppConfigContextBudget, AppConfigLlmOpenai},
         conversation::IncompleteConversation,
         message::{Message, MessageFunctionCall, UserMessageContent},
+        usage::TokenUsage,
     },
     specs::{
         function::simple::SimpleFunctionDescriptor,
-        llm::{Llm, LlmAssistantResponse, LlmUpdate},
+        llm::{Llm, LlmAssistantResponse, LlmStream, LlmStreamEvent, LlmToolCallingDelta, LlmUpdate, LlmUsage},
     },
 };
 
 use std::sync::Arc;
 
-use async_openai::{
-    Client,
-    config::OpenAIConfig,
-    types::{
-        ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
-        ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
-        ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageContent, ChatCompletionRequestUserMessage,
-        ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart, ChatCompletionTool,
-        ChatCompletionToolType, CreateChatCompletionRequest, FunctionCall, FunctionObject, ImageUrl, ResponseFormat,
-    },
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
+    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageContent, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart, ChatCompletionStreamOptions,
+    ChatCompletionTool, ChatCompletionToolType, CompletionUsage, CreateChatCompletionRequest,
+    CreateChatCompletionResponse, CreateChatCompletionStreamResponse, FunctionCall, FunctionObject, ImageUrl,
+    ResponseFormat,
+};
+use futures::{
+    FutureExt, StreamExt, TryFutureExt,
+    future::BoxFuture,
+    stream::{self, BoxStream},
 };
-use futures::{FutureExt, future::BoxFuture};
 use tokio::sync::Mutex;
 
 /// OpenAI Chat Completion API を利用したバックエンド。
@@ -37,7 +40,7 @@ pub struct ChatCompletionBackend(Arc<ChatCompletionBackendInner>);
 
 impl ChatCompletionBackend {
     pub async fn new(config: &AppConfigLlmOpenai) -> Result<ChatCompletionBackend, LlmError> {
-        let client = create_openai_client(config).await?;
+        let client = OpenaiClient::new(config)?;
         let model = config.model.clone();
 
         Ok(ChatCompletionBackend(Arc::new(ChatCompletionBackendInner {
@@ -46,6 +49,7 @@ impl ChatCompletionBackend {
             model,
             max_token: config.max_token,
             structured_mode: config.use_structured_output,
+            context_budget: config.context_budget,
         })))
     }
 }
@@ -62,15 +66,23 @@ impl Llm for ChatCompletionBackend {
         let cloned = self.0.clone();
         async move { cloned.send_conversation(conversation).await }.boxed()
     }
+
+    fn send_conversation_stream<'a>(&'a self, conversation: &'a IncompleteConversation) -> LlmStream<'a> {
+        let cloned = self.0.clone();
+        async move { cloned.send_conversation_stream(conversation).await }
+            .try_flatten_stream()
+            .boxed()
+    }
 }
 
 #[derive(Debug)]
 struct ChatCompletionBackendInner {
-    client: Client<OpenAIConfig>,
+    client: OpenaiClient,
     tools: Mutex<Vec<ChatCompletionTool>>,
     model: String,
     max_token: usize,
     structured_mode: bool,
+    context_budget: Option<AppConfigContextBudget>,
 }
 
 impl ChatCompletionBackendInner {
@@ -89,28 +101,82 @@ impl ChatCompletionBackendInner {
         locked.push(tool);
     }
 
+    async fn create_request(
+        &self,
+        messages: Vec<ChatCompletionRequestMessage>,
+        use_tools: bool,
+    ) -> CreateChatCompletionRequest {
+        let response_format = self.structured_mode.then(|| ResponseFormat::JsonSchema {
+            json_schema: RESPONSE_JSON_SCHEMA.clone(),
+        });
+        let tools = if use_tools {
+            Some(self.tools.lock().await.clone())
+        } else {
+            None
+        };
+        CreateChatCompletionRequest {
+            messages,
+            tools,
+            model: self.model.clone(),
+            response_format,
+            max_completion_tokens: Some(self.max_token as u32),
+            ..Default::default()
+        }
+    }
+
+    async fn send_conversation_stream(
+        &self,
+        conversation: &IncompleteConversation,
+    ) -> Result<LlmStream<'static>, LlmError> {
+        let messages: Result<_, _> = conversation
+            .budgeted_messages(self.context_budget.as_ref())
+            .into_iter()
+            .map(transform_message)
+            .collect();
+        let request = CreateChatCompletionRequest {
+            stream: Some(true),
+            stream_options: Some(ChatCompletionStreamOptions { include_usage: true }),
+            ..self.create_request(messages?, conversation.use_tools).await
+        };
+        let openai_stream = self.client.post_stream("/chat/completions", &request).await?;
+
+        let state = ChatCompletionStreamState {
+            openai_stream,
+            model: self.model.clone(),
+            usage: None,
+            structured_mode: self.structured_mode,
+            choice_received: false,
+            text: String::new(),
+            tool_callings: vec![],
+            finished: false,
+        };
+        let stream = stream::unfold(state, ChatCompletionStreamState::next_events).flat_map(stream::iter);
+        Ok(stream.boxed())
+    }
+
     async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
-        let messages: Result<_, _> = conversation.latest_messages.iter().map(transform_message).collect();
+        let messages: Result<_, _> = conversation
+            .budgeted_messages(self.context_budget.as_ref())
+            .into_iter()
+            .map(transform_message)
+            .collect();
         if self.structured_mode {
-            self.send_conversation_structured(messages?).await
+            self.send_conversation_structured(messages?, conversation.use_tools)
+                .await
         } else {
-            self.send_conversation_normal(messages?).await
+            self.send_conversation_normal(messages?, conversation.use_tools).await
         }
     }
 
     async fn send_conversation_normal(
         &self,
         messages: Vec<ChatCompletionRequestMessage>,
+        use_tools: bool,
     ) -> Result<LlmUpdate, LlmError> {
-        let request = CreateChatCompletionRequest {
-            messages,
-            tools: Some(self.tools.lock().await.clone()),
-            model: self.model.clone(),
-            max_completion_tokens: Some(self.max_token as u32),
-            ..Default::default()
-        };
+        let request = self.create_request(messages, use_tools).await;
 
-        let openai_response = self.client.chat().create(request).await?;
+        let openai_response: CreateChatCompletionResponse = self.client.post("/chat/completions", &request).await?;
+        let usage = openai_response.usage.map(|u| convert_usage(&self.model, u));
         let Some(first_choice) = openai_response.choices.into_iter().next() else {
             return Err(LlmError::NoChoice);
         };
@@ -139,6 +205,7 @@ impl ChatCompletionBackendInner {
                 sensitive: None,
             }),
             tool_callings,
+            usage,
         };
         Ok(update)
     }
@@ -146,19 +213,12 @@ impl ChatCompletionBackendInner {
     async fn send_conversation_structured(
         &self,
         messages: Vec<ChatCompletionRequestMessage>,
+        use_tools: bool,
     ) -> Result<LlmUpdate, LlmError> {
-        let request = CreateChatCompletionRequest {
-            messages,
-            tools: Some(self.tools.lock().await.clone()),
-            model: self.model.clone(),
-            response_format: Some(ResponseFormat::JsonSchema {
-                json_schema: RESPONSE_JSON_SCHEMA.clone(),
-            }),
-            max_completion_tokens: Some(self.max_token as u32),
-            ..Default::default()
-        };
+        let request = self.create_request(messages, use_tools).await;
 
-        let openai_response = self.client.chat().create(request).await?;
+        let openai_response: CreateChatCompletionResponse = self.client.post("/chat/completions", &request).await?;
+        let usage = openai_response.usage.map(|u| convert_usage(&self.model, u));
         let Some(first_choice) = openai_response.choices.into_iter().next() else {
             return Err(LlmError::NoChoice);
         };
@@ -190,14 +250,163 @@ impl ChatCompletionBackendInner {
         let update = LlmUpdate {
             response,
             tool_callings,
+            usage,
         };
         Ok(update)
     }
 }
 
+/// ストリーミング中に受信した内容の蓄積。
+struct ChatCompletionStreamState {
+    openai_stream: BoxStream<'static, Result<CreateChatCompletionStreamResponse, LlmError>>,
+    model: String,
+    usage: Option<LlmUsage>,
+    structured_mode: bool,
+    choice_received: bool,
+    text: String,
+    tool_callings: Vec<PartialToolCalling>,
+    finished: bool,
+}
+
+/// 受信途中の tool calling。
+#[derive(Debug, Default)]
+struct PartialToolCalling {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl ChatCompletionStreamState {
+    async fn next_events(mut self) -> Option<(Vec<Result<LlmStreamEvent, LlmError>>, Self)> {
+        if self.finished {
+            return None;
+        }
+
+        match self.openai_stream.next().await {
+            Some(Ok(chunk)) => {
+                let events = self.accumulate(chunk).into_iter().map(Ok).collect();
+                Some((events, self))
+            }
+            Some(Err(err)) => {
+                self.finished = true;
+                Some((vec![Err(err)], self))
+            }
+            None => {
+                self.finished = true;
+                let finished_event = self.build_update().map(LlmStreamEvent::Finished);
+                Some((vec![finished_event], self))
+            }
+        }
+    }
+
+    fn accumulate(&mut self, chunk: CreateChatCompletionStreamResponse) -> Vec<LlmStreamEvent> {
+        // usage は choices が空の最後のチャンクで送られてくる
+        if let Some(usage) = chunk.usage {
+            self.usage = Some(convert_usage(&self.model, usage));
+        }
+        let Some(first_choice) = chunk.choices.into_iter().find(|c| c.index == 0) else {
+            return vec![];
+        };
+        self.choice_received = true;
+
+        let mut events = vec![];
+        if let Some(content) = first_choice.delta.content.filter(|c| !c.is_empty()) {
+            self.text.push_str(&content);
+            // structured output の JSON 断片はそのまま表示できないので流さない
+            if !self.structured_mode {
+                events.push(LlmStreamEvent::TextDelta(content));
+            }
+        }
+        for tool_call_chunk in first_choice.delta.tool_calls.into_iter().flatten() {
+            let index = tool_call_chunk.index as usize;
+            if self.tool_callings.len() <= index {
+                self.tool_callings.resize_with(index + 1, Default::default);
+            }
+
+            let partial = &mut self.tool_callings[index];
+            let name = tool_call_chunk.function.as_ref().and_then(|f| f.name.clone());
+            let arguments = tool_call_chunk.function.and_then(|f| f.arguments).unwrap_or_default();
+            if let Some(id) = &tool_call_chunk.id {
+                partial.id.push_str(id);
+            }
+            if let Some(name) = &name {
+                partial.name.push_str(name);
+            }
+            partial.arguments.push_str(&arguments);
+
+            events.push(LlmStreamEvent::ToolCallingDelta(LlmToolCallingDelta {
+                index,
+                id: tool_call_chunk.id,
+                name,
+                arguments,
+            }));
+        }
+        events
+    }
+
+    fn build_update(&mut self) -> Result<LlmUpdate, LlmError> {
+        if !self.choice_received {
+            return Err(LlmError::NoChoice);
+        }
+
+        let tool_callings = if self.tool_callings.is_empty() {
+            None
+        } else {
+            let converted_calls: Result<Vec<_>, _> = self
+                .tool_callings
+                .drain(..)
+                .map(|c| {
+                    let arguments = if c.arguments.is_empty() { "{}" } else { &c.arguments };
+                    serde_json::from_str(arguments).map(|args| MessageFunctionCall {
+                        id: c.id,
+                        name: c.name,
+                        arguments: args,
+                    })
+                })
+                .collect();
+            Some(converted_calls?)
+        };
+
+        let text = std::mem::take(&mut self.text);
+        let response = match (text.is_empty(), self.structured_mode) {
+            (true, _) => None,
+            (false, true) => Some(serde_json::from_str(&text).map_err(|e| LlmError::ResponseFormat(e.into()))?),
+            (false, false) => Some(LlmAssistantResponse {
+                text,
+                language: None,
+                sensitive: None,
+            }),
+        };
+
+        Ok(LlmUpdate {
+            response,
+            tool_callings,
+            usage: self.usage.take(),
+        })
+    }
+}
+
+fn convert_usage(model: &str, usage: CompletionUsage) -> LlmUsage {
+    let cached_tokens = usage
+        .prompt_tokens_details
+        .and_then(|d| d.cached_tokens)
+        .unwrap_or_default();
+    LlmUsage {
+        model: model.to_string(),
+        tokens: TokenUsage {
+            prompt_tokens: usage.prompt_tokens as u64,
+            completion_tokens: usage.completion_tokens as u64,
+            cached_tokens: cached_tokens as u64,
+        },
+    }
+}
+
 fn transform_message(message: &Message) -> Result<ChatCompletionRequestMessage, LlmError> {
     let message = match message {
         Message::System(system_message) => ChatCompletionRequestMessage::System(system_message.0.clone().into()),
+        Message::Summary(summary_message) => {
+            ChatCompletionRequestMessage::System(format!("これまでの会話の要約:\n{}", summary_message.0).into())
+        }
         Message::User(user_message) => {
             let contents =
                 user_message
@@ -1,17 +1,42 @@
 use crate::{
     error::LlmError,
-    impls::llm::openai::create_openai_client,
-    model::{config::AppConfigLlmOpenai, conversation::IncompleteConversation},
+    impls::llm::{
+        convert_json_schema,
+        openai::{OpenaiClient, RESPONSE_JSON_SCHEMA},
+    },
+    model::{
+        config::{AppConfigContextBudget, AppConfigLlmOpenai},
+        conversation::IncompleteConversation,
+        message::{Message, MessageFunctionCall, UserMessageContent},
+        usage::TokenUsage,
+    },
     specs::{
         function::simple::SimpleFunctionDescriptor,
-        llm::{Llm, LlmUpdate},
+        llm::{Llm, LlmAssistantResponse, LlmUpdate, LlmUsage},
     },
 };
 
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
-use async_openai::{Client, config::OpenAIConfig};
+use async_openai::types::responses::{
+    Content, CreateResponse, Function, Input, InputContent, InputItem, InputMessage, OutputContent, Response, Role,
+    TextConfig, TextResponseFormat, ToolDefinition, Usage,
+};
 use futures::{FutureExt, future::BoxFuture};
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// 保持しておく `ResponseChain` の上限。
+const MAX_RESPONSE_CHAINS: usize = 1024;
 
 /// OpenAI Responses API を利用したバックエンド。
 #[derive(Debug, Clone)]
@@ -19,16 +44,25 @@ pub struct ResponsesBackend(Arc<ResponsesBackendInner>);
 
 impl ResponsesBackend {
     pub async fn new(config: &AppConfigLlmOpenai) -> Result<ResponsesBackend, LlmError> {
-        let client = create_openai_client(config).await?;
+        let client = OpenaiClient::new(config)?;
         let model = config.model.clone();
 
-        Ok(ResponsesBackend(Arc::new(ResponsesBackendInner { client, model })))
+        Ok(ResponsesBackend(Arc::new(ResponsesBackendInner {
+            client,
+            tools: Mutex::new(vec![]),
+            chains: Mutex::new(BTreeMap::new()),
+            chain_clock: AtomicU64::new(0),
+            model,
+            max_token: config.max_token,
+            structured_mode: config.use_structured_output,
+            context_budget: config.context_budget,
+        })))
     }
 }
 
 impl Llm for ResponsesBackend {
-    fn add_simple_function(&self, _descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
-        todo!()
+    fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
+        async { self.0.add_simple_function(descriptor).await }.boxed()
     }
 
     fn send_conversation<'a>(
@@ -41,14 +75,651 @@ impl Llm for ResponsesBackend {
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 struct ResponsesBackendInner {
-    client: Client<OpenAIConfig>,
+    client: OpenaiClient,
+    tools: Mutex<Vec<ToolDefinition>>,
+    chains: Mutex<BTreeMap<Uuid, ResponseChain>>,
+
+    /// `ResponseChain` の最終利用順を表すカウンター。
+    chain_clock: AtomicU64,
     model: String,
+    max_token: usize,
+    structured_mode: bool,
+    context_budget: Option<AppConfigContextBudget>,
 }
 
 impl ResponsesBackendInner {
-    async fn send_conversation(&self, _conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
-        todo!();
+    async fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) {
+        let tool = ToolDefinition::Function(Function {
+            name: descriptor.name,
+            parameters: convert_json_schema(&descriptor.parameters),
+            strict: true,
+            description: Some(descriptor.description),
+        });
+
+        let mut locked = self.tools.lock().await;
+        locked.push(tool);
+    }
+
+    async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
+        // サーバー側に残る履歴には context_budget が効かないので、収まらなくなったら続けずに切り詰めて送り直す
+        let budgeted_messages = conversation.budgeted_messages(self.context_budget.as_ref());
+        let within_budget = budgeted_messages.len() == conversation.latest_messages.len();
+
+        // 前回の応答から続けられるなら、それ以降に追加されたメッセージだけを送る
+        if within_budget && let Some((response_id, start)) = self.resume_point(conversation).await {
+            debug!(
+                "continuing conversation {} from response {response_id}",
+                conversation.id
+            );
+            let messages = conversation.latest_messages[start..].iter().collect();
+            match self.send_messages(conversation, messages, Some(response_id)).await {
+                Ok(update) => return Ok(update),
+                Err(err) if err.is_retryable() => return Err(err),
+                Err(err) => {
+                    // 前回の応答が期限切れなどで参照できない場合は全体を送り直す
+                    warn!("failed to continue from previous response, resending whole conversation: {err}");
+                    self.chains.lock().await.remove(&conversation.id);
+                }
+            }
+        }
+
+        self.send_messages(conversation, budgeted_messages, None).await
+    }
+
+    async fn send_messages(
+        &self,
+        conversation: &IncompleteConversation,
+        messages: Vec<&Message>,
+        previous_response_id: Option<String>,
+    ) -> Result<LlmUpdate, LlmError> {
+        let mut input_items = vec![];
+        for message in messages {
+            input_items.extend(transform_message(message)?);
+        }
+
+        let text = self.structured_mode.then(|| TextConfig {
+            format: TextResponseFormat::JsonSchema(RESPONSE_JSON_SCHEMA.clone()),
+        });
+        let tools = if conversation.use_tools {
+            Some(self.tools.lock().await.clone())
+        } else {
+            None
+        };
+        let request = CreateResponse {
+            input: Input::Items(input_items),
+            model: self.model.clone(),
+            max_output_tokens: Some(self.max_token as u32),
+            previous_response_id,
+            store: Some(true),
+            text,
+            tools,
+            ..Default::default()
+        };
+
+        let openai_response: Response = self.client.post("/responses", &request).await?;
+        let response_id = openai_response.id.clone();
+        let update = self.convert_response(openai_response)?;
+        self.remember_chain(conversation, response_id, &update).await;
+        Ok(update)
+    }
+
+    /// 前回の応答を起点にできる場合、その ID と送信を始めるメッセージの位置を返す。
+    async fn resume_point(&self, conversation: &IncompleteConversation) -> Option<(String, usize)> {
+        let mut locked = self.chains.lock().await;
+        let chain = locked.get_mut(&conversation.id)?;
+        let messages = &conversation.latest_messages;
+        let output_index = chain.input_length;
+        if messages.len() <= output_index + 1 || fingerprint(&messages[..output_index]) != chain.input_fingerprint {
+            return None;
+        }
+
+        // 前回の応答に対応するメッセージは送らずに済ませる
+        let output_matched = match (&messages[output_index], &chain.output) {
+            (Message::Assistant(_), ChainOutput::Text) => true,
+            (Message::FunctionCalls(calls), ChainOutput::ToolCallings(ids)) => calls.0.iter().map(|c| &c.id).eq(ids),
+            _ => false,
+        };
+        if !output_matched {
+            return None;
+        }
+        chain.last_used = self.chain_clock.fetch_add(1, Ordering::Relaxed);
+        Some((chain.response_id.clone(), output_index + 1))
+    }
+
+    async fn remember_chain(&self, conversation: &IncompleteConversation, response_id: String, update: &LlmUpdate) {
+        let output = match update.tool_callings.as_ref().filter(|tc| !tc.is_empty()) {
+            Some(tool_callings) => ChainOutput::ToolCallings(tool_callings.iter().map(|c| c.id.clone()).collect()),
+            None => ChainOutput::Text,
+        };
+        let chain = ResponseChain {
+            response_id,
+            input_length: conversation.latest_messages.len(),
+            input_fingerprint: fingerprint(&conversation.latest_messages),
+            output,
+            last_used: self.chain_clock.fetch_add(1, Ordering::Relaxed),
+        };
+
+        let mut locked = self.chains.lock().await;
+        locked.insert(conversation.id, chain);
+        // 長く続いている会話ほど ID は古いので、作成順ではなく最後に使われた順で捨てる
+        while locked.len() > MAX_RESPONSE_CHAINS {
+            let Some(least_used) = locked.iter().min_by_key(|(_, c)| c.last_used).map(|(id, _)| *id) else {
+                break;
+            };
+            locked.remove(&least_used);
+        }
+    }
+
+    fn convert_response(&self, openai_response: Response) -> Result<LlmUpdate, LlmError> {
+        if let Some(error) = openai_response.error {
+            return Err(LlmError::Backend(format!("{}: {}", error.code, error.message).into()));
+        }
+        let usage = openai_response.usage.map(|u| convert_usage(&self.model, u));
+
+        let mut text = String::new();
+        let mut tool_callings = vec![];
+        for output in openai_response.output {
+            match output {
+                OutputContent::Message(message) => {
+                    for content in message.content {
+                        match content {
+                            Content::OutputText(output_text) => text.push_str(&output_text.text),
+                            Content::Refusal(refusal) => text.push_str(&refusal.refusal),
+                        }
+                    }
+                }
+                OutputContent::FunctionCall(call) => {
+                    let arguments = if call.arguments.is_empty() {
+                        "{}"
+                    } else {
+                        &call.arguments
+                    };
+                    tool_callings.push(MessageFunctionCall {
+                        id: call.call_id,
+                        name: call.name,
+                        arguments: serde_json::from_str(arguments)?,
+                    });
+                }
+                _ => (),
+            }
+        }
+        if text.is_empty() && tool_callings.is_empty() {
+            return Err(LlmError::NoChoice);
+        }
+
+        let response = match (text.is_empty(), self.structured_mode) {
+            (true, _) => None,
+            (false, true) => Some(serde_json::from_str(&text).map_err(|e| LlmError::ResponseFormat(e.into()))?),
+            (false, false) => Some(LlmAssistantResponse {
+                text,
+                language: None,
+                sensitive: None,
+            }),
+        };
+        Ok(LlmUpdate {
+            response,
+            tool_callings: (!tool_callings.is_empty()).then_some(tool_callings),
+            usage,
+        })
+    }
+}
+
+/// `previous_response_id` で続けるために覚えておく、直前の応答とそのときの入力。
+#[derive(Debug)]
+struct ResponseChain {
+    response_id: String,
+    input_length: usize,
+    input_fingerprint: u64,
+    output: ChainOutput,
+    last_used: u64,
+}
+
+/// 直前の応答の種類。`Conversation` にはテキストか tool calling のどちらか一方が記録される。
+#[derive(Debug)]
+enum ChainOutput {
+    Text,
+    ToolCallings(Vec<String>),
+}
+
+fn fingerprint(messages: &[Message]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    messages.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn convert_usage(model: &str, usage: Usage) -> LlmUsage {
+    LlmUsage {
+        model: model.to_string(),
+        tokens: TokenUsage {
+            prompt_tokens: usage.input_tokens as u64,
+            completion_tokens: usage.output_tokens as u64,
+            cached_tokens: usage.input_tokens_details.cached_tokens.unwrap_or_default() as u64,
+        },
+    }
+}
+
+/// `Message` を入力アイテムに変換する。tool calling は呼び出しごとに別のアイテムになる。
+fn transform_message(message: &Message) -> Result<Vec<InputItem>, LlmError> {
+    let items = match message {
+        Message::System(system_message) => vec![text_item(Role::System, system_message.0.clone())],
+        Message::Summary(summary_message) => vec![text_item(
+            Role::System,
+            format!("これまでの会話の要約:\n{}", summary_message.0),
+        )],
+        Message::User(user_message) => {
+            let contents: Vec<_> = user_message
+                .contents
+                .iter()
+                .map(|umc| match umc {
+                    UserMessageContent::Text(text) => json!({ "type": "input_text", "text": text }),
+                    UserMessageContent::ImageUrl(url) => {
+                        json!({ "type": "input_image", "image_url": url.to_string(), "detail": "auto" })
+                    }
+                })
+                .collect();
+            vec![InputItem::Custom(json!({
+                "type": "message",
+                "role": "user",
+                "content": contents,
+            }))]
+        }
+        Message::Assistant(assistant_message) => vec![text_item(Role::Assistant, assistant_message.text.clone())],
+        Message::FunctionCalls(function_calls_message) => {
+            let items: Result<_, serde_json::Error> = function_calls_message
+                .0
+                .iter()
+                .map(|c| {
+                    Ok(InputItem::Custom(json!({
+                        "type": "function_call",
+                        "call_id": c.id,
+                        "name": c.name,
+                        "arguments": serde_json::to_string(&c.arguments)?,
+                    })))
+                })
+                .collect();
+            items?
+        }
+        Message::FunctionResponse(function_response_message) => vec![InputItem::Custom(json!({
+            "type": "function_call_output",
+            "call_id": function_response_message.id,
+            "output": serde_json::to_string(&function_response_message.result)?,
+        }))],
+    };
+    Ok(items)
+}
+
+fn text_item(role: Role, text: String) -> InputItem {
+    InputItem::Message(InputMessage {
+        role,
+        content: InputContent::TextInput(text),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        impls::llm::{
+            fixtures::{assistant, function_call, incomplete, incomplete_with_id, user},
+            stand_in::StandInServer,
+        },
+        model::{
+            config::{AppConfigContextBudgetUnit, AppConfigLlmOpenaiApi},
+            message::{FunctionCallsMessage, FunctionResponseMessage},
+        },
+    };
+
+    use axum::http::StatusCode;
+    use serde_json::Value;
+
+    async fn create_backend(endpoint: &str) -> ResponsesBackend {
+        create_budgeted_backend(endpoint, None).await
+    }
+
+    async fn create_budgeted_backend(
+        endpoint: &str,
+        context_budget: Option<AppConfigContextBudget>,
+    ) -> ResponsesBackend {
+        let config = AppConfigLlmOpenai {
+            api: AppConfigLlmOpenaiApi::Resnposes,
+            endpoint: endpoint.to_string(),
+            token: "test".to_string(),
+            model: "test-model".to_string(),
+            max_token: 100,
+            use_structured_output: false,
+            context_budget,
+        };
+        ResponsesBackend::new(&config).await.expect("failed to create backend")
+    }
+
+    fn text_update() -> LlmUpdate {
+        LlmUpdate {
+            response: Some(LlmAssistantResponse {
+                text: "reply".to_string(),
+                language: None,
+                sensitive: None,
+            }),
+            tool_callings: None,
+            usage: None,
+        }
+    }
+
+    fn tool_update(ids: &[&str]) -> LlmUpdate {
+        LlmUpdate {
+            response: None,
+            tool_callings: Some(ids.iter().map(|id| function_call(id)).collect()),
+            usage: None,
+        }
+    }
+
+    fn to_values(message: &Message) -> Vec<Value> {
+        let items = transform_message(message).expect("failed to transform message");
+        items
+            .iter()
+            .map(|i| serde_json::to_value(i).expect("failed to serialize item"))
+            .collect()
+    }
+
+    #[test]
+    fn transforms_user_message_with_image() {
+        let message = Message::new_user(
+            [
+                UserMessageContent::Text("これは何？".to_string()),
+                UserMessageContent::ImageUrl("https://example.com/a.png".parse().expect("invalid url")),
+            ],
+            None,
+            None,
+        );
+        assert_eq!(
+            to_values(&message),
+            vec![json!({
+                "type": "message",
+                "role": "user",
+                "content": [
+                    { "type": "input_text", "text": "これは何？" },
+                    { "type": "input_image", "image_url": "https://example.com/a.png", "detail": "auto" },
+                ],
+            })]
+        );
+    }
+
+    #[test]
+    fn transforms_text_messages() {
+        let system = to_values(&Message::new_system("system"));
+        assert_eq!(system[0]["role"], "system");
+        assert_eq!(system[0]["content"], "system");
+
+        let assistant = to_values(&assistant("reply"));
+        assert_eq!(assistant[0]["role"], "assistant");
+        assert_eq!(assistant[0]["content"], "reply");
+    }
+
+    #[test]
+    fn transforms_each_tool_call_into_separate_item() {
+        let calls = Message::FunctionCalls(FunctionCallsMessage(vec![
+            MessageFunctionCall {
+                id: "call_1".to_string(),
+                name: "self_info".to_string(),
+                arguments: json!({}),
+            },
+            MessageFunctionCall {
+                id: "call_2".to_string(),
+                name: "get_illust_url".to_string(),
+                arguments: json!({ "count": 1 }),
+            },
+        ]));
+        assert_eq!(
+            to_values(&calls),
+            vec![
+                json!({ "type": "function_call", "call_id": "call_1", "name": "self_info", "arguments": "{}" }),
+                json!({ "type": "function_call", "call_id": "call_2", "name": "get_illust_url", "arguments": "{\"count\":1}" }),
+            ]
+        );
+
+        let response = Message::FunctionResponse(FunctionResponseMessage {
+            id: "call_1".to_string(),
+            name: "self_info".to_string(),
+            result: json!({ "name": "natsuki" }),
+        });
+        assert_eq!(
+            to_values(&response),
+            vec![json!({ "type": "function_call_output", "call_id": "call_1", "output": "{\"name\":\"natsuki\"}" })]
+        );
+    }
+
+    #[tokio::test]
+    async fn resumes_after_text_response() {
+        let backend = create_backend("http://127.0.0.1:1").await;
+        let id = Uuid::now_v7();
+        let mut messages = vec![Message::new_system("system"), user("hello")];
+        backend
+            .0
+            .remember_chain(
+                &incomplete_with_id(id, messages.clone()),
+                "resp_1".to_string(),
+                &text_update(),
+            )
+            .await;
+
+        // 応答を記録しただけでは新しく送るものがない
+        messages.push(assistant("reply"));
+        assert_eq!(
+            backend.0.resume_point(&incomplete_with_id(id, messages.clone())).await,
+            None
+        );
+
+        messages.push(user("again"));
+        assert_eq!(
+            backend.0.resume_point(&incomplete_with_id(id, messages)).await,
+            Some(("resp_1".to_string(), 3))
+        );
+    }
+
+    #[tokio::test]
+    async fn resends_budgeted_messages_once_chain_exceeds_budget() {
+        let server = StandInServer::spawn("/responses", |count, _| (StatusCode::OK, text_response(count))).await;
+        let budget = AppConfigContextBudget {
+            unit: AppConfigContextBudgetUnit::Characters,
+            limit: 25,
+        };
+        let backend = create_budgeted_backend(&server.endpoint, Some(budget)).await;
+        let id = Uuid::now_v7();
+
+        let mut messages = vec![Message::new_system("system"), user("hello")];
+        for (reply, next) in [("reply 1", "again"), ("reply 2", "more")] {
+            backend
+                .0
+                .send_conversation(&incomplete_with_id(id, messages.clone()))
+                .await
+                .expect("request failed");
+            messages.extend([assistant(reply), user(next)]);
+        }
+        backend
+            .0
+            .send_conversation(&incomplete_with_id(id, messages))
+            .await
+            .expect("request failed");
+
+        // 予算に収まる間は続け、超えたら古いターンを除いて送り直す
+        let requests = server.requests();
+        assert_eq!(requests[1]["previous_response_id"], "resp_1");
+        assert!(requests[2].get("previous_response_id").is_none());
+        let texts: Vec<_> = requests[2]["input"]
+            .as_array()
+            .expect("no input")
+            .iter()
+            .map(|i| i["content"].to_string())
+            .collect();
+        assert_eq!(texts.len(), 4);
+        assert!(texts[1].contains("again"));
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_when_fingerprint_differs() {
+        let backend = create_backend("http://127.0.0.1:1").await;
+        let id = Uuid::now_v7();
+        let messages = vec![Message::new_system("system"), user("hello")];
+        backend
+            .0
+            .remember_chain(&incomplete_with_id(id, messages), "resp_1".to_string(), &text_update())
+            .await;
+
+        // 要約などで前半が書き換わった
+        let rewritten = vec![
+            Message::new_system("system"),
+            user("HELLO"),
+            assistant("reply"),
+            user("again"),
+        ];
+        assert_eq!(backend.0.resume_point(&incomplete_with_id(id, rewritten)).await, None);
+    }
+
+    #[tokio::test]
+    async fn resumes_only_when_tool_call_ids_match() {
+        let backend = create_backend("http://127.0.0.1:1").await;
+        let id = Uuid::now_v7();
+        let messages = vec![Message::new_system("system"), user("hello")];
+        backend
+            .0
+            .remember_chain(
+                &incomplete_with_id(id, messages.clone()),
+                "resp_1".to_string(),
+                &tool_update(&["call_1", "call_2"]),
+            )
+            .await;
+
+        let continued = |ids: &[&str]| {
+            let mut continued = messages.clone();
+            continued.push(Message::FunctionCalls(FunctionCallsMessage(
+                ids.iter().map(|id| function_call(id)).collect(),
+            )));
+            continued.push(Message::FunctionResponse(FunctionResponseMessage {
+                id: ids[0].to_string(),
+                name: "self_info".to_string(),
+                result: json!({}),
+            }));
+            incomplete_with_id(id, continued)
+        };
+        assert_eq!(
+            backend.0.resume_point(&continued(&["call_1", "call_2"])).await,
+            Some(("resp_1".to_string(), 3))
+        );
+        assert_eq!(backend.0.resume_point(&continued(&["call_1", "call_3"])).await, None);
+        assert_eq!(backend.0.resume_point(&continued(&["call_1"])).await, None);
+
+        // テキスト応答として記録されていれば tool calling とは一致しない
+        let mut replied = messages.clone();
+        replied.extend([assistant("reply"), user("again")]);
+        assert_eq!(backend.0.resume_point(&incomplete_with_id(id, replied)).await, None);
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_chain() {
+        let backend = create_backend("http://127.0.0.1:1").await;
+        let messages = vec![user("hello")];
+        let ids: Vec<_> = (0..MAX_RESPONSE_CHAINS).map(|_| Uuid::now_v7()).collect();
+        for id in &ids {
+            backend
+                .0
+                .remember_chain(
+                    &incomplete_with_id(*id, messages.clone()),
+                    format!("resp_{id}"),
+                    &text_update(),
+                )
+                .await;
+        }
+
+        // 最も古い会話を使うと、次に古いものが先に捨てられる
+        let continued = vec![user("hello"), assistant("reply"), user("again")];
+        assert!(
+            backend
+                .0
+                .resume_point(&incomplete_with_id(ids[0], continued))
+                .await
+                .is_some()
+        );
+        backend
+            .0
+            .remember_chain(&incomplete(messages), "resp_new".to_string(), &text_update())
+            .await;
+
+        let locked = backend.0.chains.lock().await;
+        assert_eq!(locked.len(), MAX_RESPONSE_CHAINS);
+        assert!(locked.contains_key(&ids[0]));
+        assert!(!locked.contains_key(&ids[1]));
+    }
+
+    /// `count` 番目のリクエストに対するテキストの応答。
+    fn text_response(count: usize) -> Value {
+        json!({
+            "id": format!("resp_{count}"),
+            "object": "response",
+            "created_at": 0,
+            "model": "test-model",
+            "status": "completed",
+            "output": [{
+                "type": "message",
+                "id": "msg",
+                "role": "assistant",
+                "status": "completed",
+                "content": [{ "type": "output_text", "text": format!("reply {count}"), "annotations": [] }],
+            }],
+        })
+    }
+
+    /// `previous_response_id` を指定されると常に参照できないと答えるサーバー。
+    async fn spawn_mock_server() -> StandInServer {
+        StandInServer::spawn("/responses", |count, request| {
+            if request.get("previous_response_id").is_some() {
+                let error = json!({
+                    "error": {
+                        "message": "Previous response not found.",
+                        "type": "invalid_request_error",
+                        "param": "previous_response_id",
+                        "code": "previous_response_not_found",
+                    },
+                });
+                return (StatusCode::BAD_REQUEST, error);
+            }
+
+            (StatusCode::OK, text_response(count))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn resends_whole_conversation_when_previous_response_is_unavailable() {
+        let server = spawn_mock_server().await;
+        let backend = create_backend(&server.endpoint).await;
+        let id = Uuid::now_v7();
+
+        let mut messages = vec![Message::new_system("system"), user("hello")];
+        let first = backend
+            .0
+            .send_conversation(&incomplete_with_id(id, messages.clone()))
+            .await
+            .expect("first request failed");
+        assert_eq!(first.response.map(|r| r.text).as_deref(), Some("reply 1"));
+
+        messages.extend([assistant("reply 1"), user("again")]);
+        let second = backend
+            .0
+            .send_conversation(&incomplete_with_id(id, messages))
+            .await
+            .expect("fallback request failed");
+        assert_eq!(second.response.map(|r| r.text).as_deref(), Some("reply 3"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1]["previous_response_id"], "resp_1");
+        assert_eq!(requests[1]["input"].as_array().map(Vec::len), Some(1));
+        assert!(requests[2].get("previous_response_id").is_none());
+        assert_eq!(requests[2]["input"].as_array().map(Vec::len), Some(4));
+
+        // 送り直した応答から続けられる
+        let chains = backend.0.chains.lock().await;
+        assert_eq!(chains.get(&id).map(|c| c.response_id.as_str()), Some("resp_3"));
     }
 }
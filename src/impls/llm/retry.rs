@@ -0,0 +1,111 @@
+use crate::{
+    error::LlmError,
+    model::{config::AppConfigLlmRetry, conversation::IncompleteConversation},
+    specs::{
+        function::simple::SimpleFunctionDescriptor,
+        llm::{Llm, LlmStream, LlmUpdate},
+    },
+};
+
+use std::{sync::Arc, time::Duration};
+
+use futures::{FutureExt, StreamExt, future::BoxFuture, stream};
+use rand::random_range;
+use tokio::time::sleep;
+use tracing::warn;
+
+/// 一時的な障害で失敗した送信を指数バックオフで再試行する `Llm`。
+#[derive(Debug, Clone)]
+pub struct RetryLlm(Arc<RetryLlmInner>);
+
+impl RetryLlm {
+    pub fn new(inner: Box<dyn Llm + 'static>, policy: AppConfigLlmRetry) -> RetryLlm {
+        RetryLlm(Arc::new(RetryLlmInner { inner, policy }))
+    }
+}
+
+impl Llm for RetryLlm {
+    fn add_simple_function(&self, descriptor: SimpleFunctionDescriptor) -> BoxFuture<'_, ()> {
+        self.0.inner.add_simple_function(descriptor)
+    }
+
+    fn send_conversation<'a>(
+        &'a self,
+        conversation: &'a IncompleteConversation,
+    ) -> BoxFuture<'a, Result<LlmUpdate, LlmError>> {
+        async move { self.0.send_conversation(conversation).await }.boxed()
+    }
+
+    fn send_conversation_stream<'a>(&'a self, conversation: &'a IncompleteConversation) -> LlmStream<'a> {
+        async move { self.0.send_conversation_stream(conversation).await }
+            .flatten_stream()
+            .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct RetryLlmInner {
+    inner: Box<dyn Llm + 'static>,
+    policy: AppConfigLlmRetry,
+}
+
+impl RetryLlmInner {
+    async fn send_conversation(&self, conversation: &IncompleteConversation) -> Result<LlmUpdate, LlmError> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.inner.send_conversation(conversation).await {
+                Ok(update) => return Ok(update),
+                Err(err) => err,
+            };
+            let Some(delay) = self.next_delay(&err, attempt) else {
+                return Err(err);
+            };
+
+            warn!("LLM request failed (attempt {attempt}), retrying in {delay:?}: {err}");
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// 最初のイベントを受け取る前に失敗した場合のみ再試行する。
+    /// 一度でもイベントを流したあとに再試行すると、受信側に重複した内容が届いてしまうため。
+    async fn send_conversation_stream<'a>(&'a self, conversation: &'a IncompleteConversation) -> LlmStream<'a> {
+        let mut attempt = 1;
+        loop {
+            let mut llm_stream = self.inner.send_conversation_stream(conversation);
+            let first_event = llm_stream.next().await;
+            if let Some(Err(err)) = &first_event
+                && let Some(delay) = self.next_delay(err, attempt)
+            {
+                warn!("LLM stream failed (attempt {attempt}), retrying in {delay:?}: {err}");
+                sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+
+            return stream::iter(first_event).chain(llm_stream).boxed();
+        }
+    }
+
+    /// 再試行するなら次の試行までの待ち時間を返す。
+    fn next_delay(&self, err: &LlmError, attempt: usize) -> Option<Duration> {
+        if !err.is_retryable() || attempt >= self.policy.max_attempts {
+            return None;
+        }
+
+        let max_delay = Duration::from_millis(self.policy.max_delay_ms);
+        if let Some(retry_after) = err.retry_after() {
+            return (retry_after <= max_delay).then_some(retry_after);
+        }
+
+        let exponent = (attempt - 1).min(16) as u32;
+        let base_delay = Duration::from_millis(self.policy.base_delay_ms).saturating_mul(1 << exponent);
+        let jitter = self.policy.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            1.0 + random_range(-jitter..=jitter)
+        } else {
+            1.0
+        };
+        Some(base_delay.min(max_delay).mul_f64(factor))
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+};
+use serde_json::Value;
+use tokio::{net::TcpListener, spawn};
+
+/// `/image.png` で返す PNG 画像。`infer` が判定できる最小限のヘッダーだけを持つ。
+pub const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+/// 何番目 (1 始まり) のリクエストかと、その本文から応答を決める関数。
+type Responder = Box<dyn Fn(usize, &Value) -> (StatusCode, Value) + Send + Sync>;
+
+struct StandInState {
+    requests: Mutex<Vec<Value>>,
+    respond: Responder,
+}
+
+/// 受け取った JSON リクエストを記録し、`respond` の結果を返すサーバー。
+pub struct StandInServer {
+    pub endpoint: String,
+    state: Arc<StandInState>,
+}
+
+impl StandInServer {
+    /// `path` への POST を受け付けるサーバーを起動する。`/image.png` では常に PNG 画像を返す。
+    pub async fn spawn(
+        path: &str,
+        respond: impl Fn(usize, &Value) -> (StatusCode, Value) + Send + Sync + 'static,
+    ) -> StandInServer {
+        let state = Arc::new(StandInState {
+            requests: Mutex::new(vec![]),
+            respond: Box::new(respond),
+        });
+        let router = Router::new()
+            .route(path, post(handle_request))
+            .route("/image.png", get(|| async { PNG_HEADER }))
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("failed to bind");
+        let endpoint = format!("http://{}", listener.local_addr().expect("no local address"));
+        spawn(async move { axum::serve(listener, router).await });
+        StandInServer { endpoint, state }
+    }
+
+    /// `replies` を順に返すサーバーを起動する。
+    pub async fn spawn_sequence(path: &str, replies: Vec<Value>) -> StandInServer {
+        StandInServer::spawn(path, move |count, _| (StatusCode::OK, replies[count - 1].clone())).await
+    }
+
+    /// これまでに受け取ったリクエスト。
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.requests.lock().expect("requests lock poisoned").clone()
+    }
+}
+
+async fn handle_request(
+    State(state): State<Arc<StandInState>>,
+    Json(request): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    let count = {
+        let mut locked = state.requests.lock().expect("requests lock poisoned");
+        locked.push(request.clone());
+        locked.len()
+    };
+    let (status, body) = (state.respond)(count, &request);
+    (status, Json(body))
+}
@@ -11,6 +11,7 @@ use crate::error::PlatformError;
 use std::{io::Error as IoError, num::ParseIntError};
 
 use reqwest::Error as ReqwestError;
+use url::ParseError as UrlParseError;
 
 impl From<ReqwestError> for PlatformError {
     fn from(value: ReqwestError) -> Self {
@@ -24,6 +25,12 @@ impl From<IoError> for PlatformError {
     }
 }
 
+impl From<UrlParseError> for PlatformError {
+    fn from(value: UrlParseError) -> Self {
+        PlatformError::External(value.into())
+    }
+}
+
 impl From<ParseIntError> for PlatformError {
     fn from(value: ParseIntError) -> Self {
         PlatformError::External(value.into())
@@ -1,48 +1,90 @@
 use crate::{
     assistant::Assistant,
     error::PlatformError,
-    model::message::{UserMessage, UserMessageContent},
+    model::{
+        config::AppConfigPlatform,
+        conversation::ConversationOrigin,
+        message::{UserMessage, UserMessageContent},
+    },
+    reload::ReloadableConfig,
     specs::platform::ConversationPlatform,
 };
 
-use std::io::stdin;
+use std::{
+    io::{Write, stdin, stdout},
+    sync::Arc,
+    thread,
+};
 
 use colored::Colorize;
 use futures::{FutureExt, future::BoxFuture};
 use thiserror::Error as ThisError;
 use tokio::{
-    spawn,
-    sync::mpsc::{Sender, channel},
+    select, spawn,
+    sync::{
+        Mutex,
+        mpsc::{Receiver, Sender, UnboundedReceiver, channel, unbounded_channel},
+    },
 };
-use tracing::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+const PLATFORM_KEY: &str = "cli";
 
 #[derive(Debug)]
 pub struct CliPlatform {
     assistant: Assistant,
+    config: ReloadableConfig<AppConfigPlatform>,
+
+    /// stdin から読んだ行。再起動されても読み込みスレッドが 1 つで済むように、`execute` の外で持っておく。
+    input: Arc<Mutex<Receiver<String>>>,
 }
 
 impl ConversationPlatform for CliPlatform {
-    fn execute(&self) -> BoxFuture<'static, Result<(), PlatformError>> {
+    fn execute(&self, shutdown: CancellationToken) -> BoxFuture<'static, Result<(), PlatformError>> {
         let assistant = self.assistant.clone();
+        let identity = self.config.load().cli.identity.clone();
+        let input = self.input.clone();
 
         async move {
-            let mut conversation = assistant.new_conversation();
-
-            // CLI のテキスト入力を別スレッドに分ける
-            let (tx, mut rx) = channel(1);
-            spawn(CliPlatform::handle_user_input(tx));
+            let mut rx = input.lock().await;
+            let mut conversation = assistant.new_conversation(identity.as_deref());
+            let origin = ConversationOrigin::new(PLATFORM_KEY, "local");
 
-            // 応答ループ
-            while let Some(input) = rx.recv().await {
+            // 応答ループ(シャットダウンが要求されたら次の入力を待たずに抜ける)
+            while let Some(input) = select! {
+                input = rx.recv() => input,
+                _ = shutdown.cancelled() => None,
+            } {
                 info!("sending {input}");
 
                 let user_message = UserMessage {
                     contents: vec![UserMessageContent::Text(input)],
                     ..Default::default()
                 };
-                let conversation_update = assistant.process_conversation(conversation, user_message).await?;
+                // 生成中のテキストはそのまま表示していく
+                let (text_tx, text_rx) = unbounded_channel();
+                let printer = spawn(CliPlatform::print_streaming_text(text_rx));
+                let processed = assistant
+                    .process_conversation_streaming(conversation.clone(), user_message, &origin, text_tx)
+                    .await;
+                let conversation_update = match processed {
+                    Ok(update) => update,
+                    Err(err) => {
+                        // 失敗した入力はなかったことにして、それまでの会話を続ける
+                        error!("assistant reported error: {err}");
+                        printer.await.ok();
+                        println!("{}", format!("!! {err}").red());
+                        continue;
+                    }
+                };
 
-                println!(">> {}", conversation_update.assistant_message().text.bold().white());
+                // ストリーミングされた途中経過と異なる場合(されなかった場合も含む)は最終的な応答を表示し直す
+                let streamed_text = printer.await.unwrap_or_default();
+                let final_text = &conversation_update.assistant_message().text;
+                if streamed_text.trim() != final_text.trim() {
+                    println!(">> {}", final_text.bold().white());
+                }
                 conversation = conversation_update.finish();
             }
             println!("channel closed");
@@ -51,20 +93,59 @@ impl ConversationPlatform for CliPlatform {
         }
         .boxed()
     }
+
+    /// stdin が閉じられたら終了する。
+    fn restart_on_exit(&self) -> bool {
+        false
+    }
 }
 
 impl CliPlatform {
-    pub fn new(assistant: Assistant) -> CliPlatform {
-        CliPlatform { assistant }
+    pub fn new(config: ReloadableConfig<AppConfigPlatform>, assistant: Assistant) -> CliPlatform {
+        // CLI のテキスト入力を別スレッドに分ける
+        // stdin の読み込みはブロックするので、ランタイムのワーカーを塞がないように OS スレッドで行う
+        let (tx, rx) = channel(1);
+        thread::spawn(move || CliPlatform::handle_user_input(tx));
+        CliPlatform::with_input(config, assistant, rx)
+    }
+
+    /// stdin の代わりに `input` から流れてくる行を入力として扱う。
+    fn with_input(
+        config: ReloadableConfig<AppConfigPlatform>,
+        assistant: Assistant,
+        input: Receiver<String>,
+    ) -> CliPlatform {
+        CliPlatform {
+            assistant,
+            config,
+            input: Arc::new(Mutex::new(input)),
+        }
+    }
+
+    /// Receiver に流れてきたテキストを逐次表示する。表示したテキストを返す。
+    async fn print_streaming_text(mut rx: UnboundedReceiver<String>) -> String {
+        let mut streamed_text = String::new();
+        while let Some(delta) = rx.recv().await {
+            if streamed_text.is_empty() {
+                print!(">> ");
+            }
+            print!("{}", delta.bold().white());
+            stdout().flush().ok();
+            streamed_text.push_str(&delta);
+        }
+        if !streamed_text.is_empty() {
+            println!();
+        }
+        streamed_text
     }
 
     /// stdin の行を Sender に流す。
-    async fn handle_user_input(tx: Sender<String>) -> Result<(), CliError> {
-        debug!("reading stdin in another task");
+    fn handle_user_input(tx: Sender<String>) -> Result<(), CliError> {
+        debug!("reading stdin in another thread");
         let mut buffer = String::new();
         while stdin().read_line(&mut buffer).map_err(|_| CliError::Stdin)? != 0 {
             let text = buffer.trim_end().to_string();
-            tx.send(text).await.map_err(|_| CliError::Communication)?;
+            tx.blocking_send(text).map_err(|_| CliError::Communication)?;
 
             buffer.clear();
         }
@@ -80,3 +161,71 @@ pub enum CliError {
     #[error("something went wrong inter-thread communication")]
     Communication,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assistant::tests::{create_assistant, mock_profile},
+        error::AssistantError,
+        model::{conversation::IncompleteConversation, message::AssistantMessage},
+        specs::hook::{AssistantHook, HookAction},
+    };
+
+    use std::sync::Mutex as StdMutex;
+
+    const SCRIPT: &str = r#"
+[[rules]]
+pattern = "壊れて"
+error = { kind = "backend", message = "scripted failure" }
+
+[[rules]]
+turn = 1
+text = "1 回目ッス"
+
+[[rules]]
+turn = 2
+text = "2 回目ッス"
+"#;
+
+    /// 確定した応答を記録するフック。
+    #[derive(Debug)]
+    struct RecordResponses(Arc<StdMutex<Vec<String>>>);
+
+    impl AssistantHook for RecordResponses {
+        fn post_response<'a>(
+            &'a self,
+            _conversation: &'a IncompleteConversation,
+            message: &'a mut AssistantMessage,
+        ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+            self.0
+                .lock()
+                .expect("responses lock poisoned")
+                .push(message.text.clone());
+            async { Ok(HookAction::Continue) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_conversation_after_assistant_error() {
+        let responses = Arc::new(StdMutex::new(vec![]));
+        let mut profile = mock_profile(SCRIPT).await;
+        profile.add_hook(RecordResponses(responses.clone()));
+        let assistant = create_assistant(profile).await;
+
+        let (tx, rx) = channel(3);
+        for line in ["こんにちは", "壊れてる？", "まだいる？"] {
+            tx.send(line.to_string()).await.expect("failed to send input");
+        }
+        drop(tx);
+
+        let cli = CliPlatform::with_input(ReloadableConfig::new(AppConfigPlatform::default()), assistant, rx);
+        cli.execute(CancellationToken::new())
+            .await
+            .expect("CLI platform failed");
+
+        // 失敗した入力は数えずに、2 回目の入力として扱われる
+        let responses = responses.lock().expect("responses lock poisoned").clone();
+        assert_eq!(responses, ["1 回目ッス", "2 回目ッス"]);
+    }
+}
@@ -2,76 +2,126 @@ use crate::{
     assistant::Assistant,
     error::PlatformError,
     model::{
-        config::AppConfigPlatformDiscord,
+        config::AppConfigPlatform,
+        conversation::ConversationOrigin,
         message::{UserMessage, UserMessageContent},
     },
+    reload::ReloadableConfig,
     specs::platform::ConversationPlatform,
     text::markdown::sanitize_markdown_mastodon,
 };
 
-use std::sync::{Arc, LazyLock};
+use std::{
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
 
-use futures::{future::BoxFuture, prelude::*};
+use futures::{future::BoxFuture, join, prelude::*};
 use regex::Regex;
 use serenity::{
     Client as SerenityClient, Error as SerenityError,
-    all::{Context, CreateMessage, EventHandler, GatewayIntents, Message as SerenityMessage, Ready, User},
+    all::{Context, CreateMessage, EditMessage, EventHandler, GatewayIntents, Message as SerenityMessage, Ready, User},
+    utils::validate_token,
+};
+use tokio::{
+    select,
+    sync::{
+        RwLock,
+        mpsc::{UnboundedReceiver, unbounded_channel},
+    },
 };
-use tokio::sync::{Mutex, RwLock};
-use tracing::{error, info};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
 
 const PLATFORM_KEY: &str = "discord";
 
+/// ストリーミング中に返信を編集する最小間隔。
+const STREAMING_EDIT_INTERVAL: Duration = Duration::from_millis(1500);
+
 static RE_HEAD_MENTION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"^\s*<@\d+?>\s*"#).expect("invalid regex"));
 
 pub struct DiscordPlatform(Arc<DiscordPlatformInner>);
 
 impl DiscordPlatform {
     pub async fn new(
-        config_discord: &AppConfigPlatformDiscord,
+        config: ReloadableConfig<AppConfigPlatform>,
         assistant: Assistant,
     ) -> Result<DiscordPlatform, PlatformError> {
+        // 接続に関わる設定は起動時のものを使い続ける
+        let token = config.load().discord.token.clone();
         let intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
 
-        let handler = SerenityMessageHandler {
+        // Client は起動のたびに作るので、トークンの形式だけは先に確かめておく
+        validate_token(&token).map_err(|e| PlatformError::External(e.into()))?;
+
+        let tracker = TaskTracker::new();
+        let abort = CancellationToken::new();
+        let handler = Arc::new(SerenityMessageHandler {
             bot_user: RwLock::new(None),
-            max_length: config_discord.max_length,
+            config,
             assistant,
-        };
+            tracker: tracker.clone(),
+            abort: abort.clone(),
+        });
 
-        // handler itself
-        let outer_discord = Mutex::new(
-            SerenityClient::builder(&config_discord.token, intents)
-                .event_handler(handler)
-                .await?,
-        );
-        Ok(DiscordPlatform(Arc::new(DiscordPlatformInner { outer_discord })))
+        Ok(DiscordPlatform(Arc::new(DiscordPlatformInner {
+            token,
+            intents,
+            handler,
+            tracker,
+            abort,
+        })))
     }
 }
 
 impl ConversationPlatform for DiscordPlatform {
-    fn execute(&self) -> BoxFuture<'static, Result<(), PlatformError>> {
+    fn execute(&self, shutdown: CancellationToken) -> BoxFuture<'static, Result<(), PlatformError>> {
         let cloned_inner = self.0.clone();
-        cloned_inner.execute().boxed()
+        cloned_inner.execute(shutdown).boxed()
     }
 }
 
 struct DiscordPlatformInner {
-    outer_discord: Mutex<SerenityClient>,
+    token: String,
+    intents: GatewayIntents,
+    handler: Arc<SerenityMessageHandler>,
+    tracker: TaskTracker,
+    abort: CancellationToken,
 }
 
 impl DiscordPlatformInner {
-    async fn execute(self: Arc<Self>) -> Result<(), PlatformError> {
-        let mut locked = self.outer_discord.lock().await;
-        locked.start().await?;
+    async fn execute(self: Arc<Self>, shutdown: CancellationToken) -> Result<(), PlatformError> {
+        // 一度 start して終了した Client を再び start できる保証はないので、再起動のたびに作り直す
+        let mut client = SerenityClient::builder(&self.token, self.intents)
+            .event_handler_arc(self.handler.clone())
+            .await?;
+        let shard_manager = client.shard_manager.clone();
+        select! {
+            started = client.start() => started?,
+            _ = shutdown.cancelled() => {
+                // 新しいメッセージの受け付けをやめ、処理中の返信が終わってから切断する
+                info!("shutting down Discord platform, waiting for {} in-flight message(s)", self.tracker.len());
+                // 返信は serenity のタスクで動いているので、待っている途中で drop されたら明示的に中断させる
+                let _abort_guard = self.abort.clone().drop_guard();
+                self.tracker.close();
+                self.tracker.wait().await;
+                shard_manager.shutdown_all().await;
+            }
+        }
         Ok(())
     }
 }
 
 struct SerenityMessageHandler {
     bot_user: RwLock<Option<User>>,
-    max_length: usize,
+
+    /// identity・アクセス制限・返信の長さなど、再読み込みで差し替えられる設定。
+    config: ReloadableConfig<AppConfigPlatform>,
     assistant: Assistant,
+    tracker: TaskTracker,
+
+    /// キャンセルされると処理中の返信を中断する。
+    abort: CancellationToken,
 }
 
 impl EventHandler for SerenityMessageHandler {
@@ -88,7 +138,17 @@ impl EventHandler for SerenityMessageHandler {
         'a: 't,
         Self: 't,
     {
-        do_event(self.on_message(ctx, new_message))
+        // シャットダウン中は新しいメッセージを受け付けない
+        if self.tracker.is_closed() {
+            return async {}.boxed();
+        }
+        let processed = async move {
+            select! {
+                processed = self.on_message(ctx, new_message) => processed,
+                _ = self.abort.cancelled() => Ok(()),
+            }
+        };
+        do_event(self.tracker.track_future(processed))
     }
 }
 
@@ -112,6 +172,16 @@ impl SerenityMessageHandler {
             return Ok(());
         }
 
+        let mut subjects = vec![
+            format!("user:{}", message.author.id),
+            format!("channel:{}", message.channel_id),
+        ];
+        subjects.extend(message.guild_id.map(|gi| format!("guild:{gi}")));
+        if !self.config.load().discord.access.is_allowed(&subjects) {
+            info!("ignoring message from {} (access denied)", message.author.id);
+            return Ok(());
+        }
+
         self.on_mentioned_message(ctx, message).await?;
         Ok(())
     }
@@ -119,10 +189,15 @@ impl SerenityMessageHandler {
     async fn on_mentioned_message(&self, ctx: Context, message: SerenityMessage) -> Result<(), PlatformError> {
         // Conversation の検索
         let context_key = message.referenced_message.as_ref().map(|rm| rm.id.to_string());
+        let guild_id = message.guild_id.map(|gi| gi.to_string());
+        let loaded_config = self.config.load();
+        let identity = loaded_config
+            .discord
+            .identity_for(guild_id.as_deref(), &message.channel_id.to_string());
         let conversation = match context_key {
             None => {
                 info!("creating new conversation");
-                self.assistant.new_conversation()
+                self.assistant.new_conversation(identity)
             }
             Some(context) => {
                 info!("restoring conversation with last referenced message ID {context}");
@@ -130,7 +205,7 @@ impl SerenityMessageHandler {
                     Some(c) => c,
                     None => {
                         info!("conversation has been lost, creating new one");
-                        self.assistant.new_conversation()
+                        self.assistant.new_conversation(identity)
                     }
                 }
             }
@@ -149,7 +224,27 @@ impl SerenityMessageHandler {
             language: message.author.locale.clone(),
             ..Default::default()
         };
-        let conversation_update = self.assistant.process_conversation(conversation, user_message).await?;
+        let origin = ConversationOrigin::new(PLATFORM_KEY, message.author.id.to_string());
+        // 生成途中のテキストで返信を逐次編集していく
+        let (text_tx, text_rx) = unbounded_channel();
+        let (conversation_update, streamed_reply) = join!(
+            self.assistant
+                .process_conversation_streaming(conversation, user_message, &origin, text_tx),
+            self.relay_streaming_text(&ctx, &message, text_rx),
+        );
+        let conversation_update = match conversation_update {
+            Ok(update) => update,
+            Err(err) => {
+                // 途中まで表示した返信が残らないように消しておく
+                if let Some(streamed_message) = streamed_reply
+                    && let Err(delete_err) = streamed_message.delete(&ctx.http).await
+                {
+                    warn!("failed to delete partial reply: {delete_err}");
+                }
+                return Err(err.into());
+            }
+        };
+
         let assistant_message = conversation_update.assistant_message();
         let attachments = conversation_update.attachments();
         info!(
@@ -161,20 +256,38 @@ impl SerenityMessageHandler {
         // TODO: attachments
 
         // リプライ
-        // TODO: sanitize_markdown_discord
-        let mut sanitized_text = sanitize_markdown_mastodon(&assistant_message.text);
-        if sanitized_text.chars().count() > self.max_length {
-            sanitized_text = sanitized_text.chars().take(self.max_length).collect();
-            sanitized_text.push_str("...(omitted)");
-        }
-
-        let replied_message = message
-            .channel_id
-            .send_message(
-                &ctx.http,
-                CreateMessage::new().reference_message(&message).content(sanitized_text),
-            )
-            .await?;
+        let reply_text = self.format_reply_text(&assistant_message.text);
+        let edited_reply = match streamed_reply {
+            Some(mut streamed_message) => {
+                match streamed_message
+                    .edit(&ctx.http, EditMessage::new().content(reply_text.clone()))
+                    .await
+                {
+                    Ok(()) => Some(streamed_message),
+                    Err(err) => {
+                        // 返信が 2 つ並ばないように、途中まで表示した返信は消してから送り直す
+                        warn!("failed to edit streamed reply, sending final reply instead: {err}");
+                        if let Err(delete_err) = streamed_message.delete(&ctx.http).await {
+                            warn!("failed to delete partial reply: {delete_err}");
+                        }
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let replied_message = match edited_reply {
+            Some(edited_message) => edited_message,
+            None => {
+                message
+                    .channel_id
+                    .send_message(
+                        &ctx.http,
+                        CreateMessage::new().reference_message(&message).content(reply_text),
+                    )
+                    .await?
+            }
+        };
 
         // Conversation/history の更新
         let updated_conversation = conversation_update.finish();
@@ -185,6 +298,71 @@ impl SerenityMessageHandler {
 
         Ok(())
     }
+
+    /// ストリーミングされてくるテキストで返信を作成・編集する。
+    /// 返信を作成した場合はそのメッセージを返す。途中経過の表示に失敗しても最終的な応答は別に送るので、エラーにはしない。
+    async fn relay_streaming_text(
+        &self,
+        ctx: &Context,
+        message: &SerenityMessage,
+        mut text_rx: UnboundedReceiver<String>,
+    ) -> Option<SerenityMessage> {
+        let mut reply: Option<SerenityMessage> = None;
+        let mut streamed_text = String::new();
+        let mut last_edited_at = Instant::now();
+        while let Some(delta) = text_rx.recv().await {
+            streamed_text.push_str(&delta);
+            if streamed_text.trim().is_empty() {
+                continue;
+            }
+
+            let reply_text = self.format_reply_text(&streamed_text);
+            match &mut reply {
+                None => {
+                    let created = message
+                        .channel_id
+                        .send_message(
+                            &ctx.http,
+                            CreateMessage::new().reference_message(message).content(reply_text),
+                        )
+                        .await;
+                    match created {
+                        Ok(created) => reply = Some(created),
+                        Err(err) => {
+                            // 生成は続いているので、チャンネルは閉じずに読み捨てる
+                            warn!("failed to send streamed reply: {err}");
+                            while text_rx.recv().await.is_some() {}
+                            return None;
+                        }
+                    }
+                }
+                // 編集のレートリミットに引っかからないように間隔を空ける
+                Some(_) if last_edited_at.elapsed() < STREAMING_EDIT_INTERVAL => continue,
+                Some(streamed_message) => {
+                    if let Err(err) = streamed_message
+                        .edit(&ctx.http, EditMessage::new().content(reply_text))
+                        .await
+                    {
+                        warn!("failed to edit streamed reply: {err}");
+                    }
+                }
+            }
+            last_edited_at = Instant::now();
+        }
+
+        reply
+    }
+
+    fn format_reply_text(&self, text: &str) -> String {
+        // TODO: sanitize_markdown_discord
+        let max_length = self.config.load().discord.max_length;
+        let mut sanitized_text = sanitize_markdown_mastodon(text);
+        if sanitized_text.chars().count() > max_length {
+            sanitized_text = sanitized_text.chars().take(max_length).collect();
+            sanitized_text.push_str("...(omitted)");
+        }
+        sanitized_text
+    }
 }
 
 fn do_event<'t>(event_future: impl Future<Output = Result<(), PlatformError>> + Send + 't) -> BoxFuture<'t, ()> {
@@ -3,17 +3,21 @@ use crate::{
     assistant::Assistant,
     error::PlatformError,
     model::{
-        config::AppConfigPlatformMastodon,
-        conversation::ConversationAttachment,
+        config::{AppConfigAccessList, AppConfigPlatform},
+        conversation::{ConversationAttachment, ConversationOrigin},
         message::{UserMessage, UserMessageContent},
     },
+    reload::ReloadableConfig,
     specs::platform::ConversationPlatform,
     text::markdown::sanitize_markdown_mastodon,
 };
 
-use std::sync::{Arc, LazyLock};
+use std::{
+    pin::pin,
+    sync::{Arc, LazyLock},
+};
 
-use futures::{future::BoxFuture, prelude::*};
+use futures::{future::BoxFuture, prelude::*, stream::FuturesUnordered};
 use html2md::parse_html;
 use mastodon_async::{
     Error as MastodonError, Mastodon, NewStatus, Visibility,
@@ -24,7 +28,8 @@ use mastodon_async::{
 use regex::Regex;
 use reqwest::Client;
 use tempfile::NamedTempFile;
-use tokio::{fs::File, io::AsyncWriteExt, spawn};
+use tokio::{fs::File, io::AsyncWriteExt, select};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
 use url::Url;
 
@@ -38,9 +43,13 @@ pub struct MastodonPlatform(Arc<MastodonPlatformInner>);
 
 impl MastodonPlatform {
     pub async fn new(
-        config_mastodon: &AppConfigPlatformMastodon,
+        config: ReloadableConfig<AppConfigPlatform>,
         assistant: Assistant,
     ) -> Result<MastodonPlatform, PlatformError> {
+        // 接続に関わる設定は起動時のものを使い続ける
+        let loaded_config = config.load();
+        let config_mastodon = &loaded_config.mastodon;
+
         // Mastodon クライアントと自己アカウント情報
         let http_client = reqwest::ClientBuilder::new().user_agent(USER_AGENT).build()?;
         let mastodon_data = mastodon_async::Data {
@@ -50,22 +59,30 @@ impl MastodonPlatform {
         };
         let mastodon = Mastodon::new(http_client.clone(), mastodon_data);
         let self_account = mastodon.verify_credentials().await?;
+        // WebFinger のドメインと API のホストが異なるサーバーもあるので、指定があればそちらを優先する
+        let local_domain = match &config_mastodon.local_domain {
+            Some(local_domain) => local_domain.to_ascii_lowercase(),
+            None => Url::parse(&config_mastodon.server_url)?
+                .host_str()
+                .map(|h| h.to_ascii_lowercase())
+                .ok_or_else(|| PlatformError::ExpectationMismatch("server_url has no host".into()))?,
+        };
 
         Ok(MastodonPlatform(Arc::new(MastodonPlatformInner {
             assistant,
             http_client,
             mastodon,
             self_account,
-            sensitive_spoiler: config_mastodon.sensitive_spoiler.clone(),
-            max_length: config_mastodon.max_length,
+            config,
+            local_domain,
         })))
     }
 }
 
 impl ConversationPlatform for MastodonPlatform {
-    fn execute(&self) -> BoxFuture<'static, Result<(), PlatformError>> {
+    fn execute(&self, shutdown: CancellationToken) -> BoxFuture<'static, Result<(), PlatformError>> {
         let cloned_inner = self.0.clone();
-        cloned_inner.execute().boxed()
+        cloned_inner.execute(shutdown).boxed()
     }
 }
 
@@ -75,19 +92,37 @@ struct MastodonPlatformInner {
     http_client: Client,
     mastodon: Mastodon,
     self_account: Account,
-    sensitive_spoiler: String,
-    max_length: usize,
+
+    /// identity・アクセス制限・返信の長さなど、再読み込みで差し替えられる設定。
+    config: ReloadableConfig<AppConfigPlatform>,
+    local_domain: String,
 }
 
 impl MastodonPlatformInner {
-    async fn execute(self: Arc<Self>) -> Result<(), PlatformError> {
+    async fn execute(self: Arc<Self>, shutdown: CancellationToken) -> Result<(), PlatformError> {
         let user_stream = self.mastodon.stream_user().await?;
-        user_stream
-            .try_for_each(async |(e, _)| {
-                spawn(self.clone().process_event(e));
-                Ok(())
-            })
-            .await?;
+        let mut user_stream = pin!(user_stream.take_until(shutdown.cancelled()));
+
+        // 処理中の返信はこの Future の中で進め、drop されたときに一緒に中断されるようにする
+        let mut in_flight = FuturesUnordered::new();
+        let streamed = loop {
+            select! {
+                event = user_stream.next() => match event {
+                    Some(Ok((e, _))) => in_flight.push(self.clone().process_event(e)),
+                    Some(Err(err)) => break Err(err),
+                    None => break Ok(()),
+                },
+                Some(()) = in_flight.next(), if !in_flight.is_empty() => (),
+            }
+        };
+
+        // 新しいイベントの受信をやめたあと、処理中の返信が終わるのを待つ
+        info!(
+            "mastodon stream closed, waiting for {} in-flight event(s)",
+            in_flight.len()
+        );
+        while in_flight.next().await.is_some() {}
+        streamed?;
 
         Ok(())
     }
@@ -112,17 +147,24 @@ impl MastodonPlatformInner {
     }
 
     async fn process_status(&self, status: Status) -> Result<(), PlatformError> {
+        let loaded_config = self.config.load();
+        let config = &loaded_config.mastodon;
+
         // フィルタリング(bot flag と自分には応答しない)
         if status.account.bot || status.account.id == self.self_account.id {
             return Ok(());
         }
+        if !self.is_allowed_account(&config.access, &status.account) {
+            info!("ignoring status from {} (access denied)", status.account.acct);
+            return Ok(());
+        }
 
         // Conversation の検索
         let context_key = status.in_reply_to_id.map(|si| si.to_string());
         let conversation = match context_key {
             None => {
                 info!("creating new conversation");
-                self.assistant.new_conversation()
+                self.assistant.new_conversation(config.identity.as_deref())
             }
             Some(context) => {
                 info!("restoring conversation with last status ID {context}");
@@ -130,7 +172,7 @@ impl MastodonPlatformInner {
                     Some(c) => c,
                     None => {
                         info!("conversation has been lost, creating new one");
-                        self.assistant.new_conversation()
+                        self.assistant.new_conversation(config.identity.as_deref())
                     }
                 }
             }
@@ -162,7 +204,11 @@ impl MastodonPlatformInner {
             language: status.language.and_then(|l| l.to_639_1()).map(|l| l.to_string()),
             ..Default::default()
         };
-        let conversation_update = self.assistant.process_conversation(conversation, user_message).await?;
+        let origin = ConversationOrigin::new(PLATFORM_KEY, status.account.acct.clone());
+        let conversation_update = self
+            .assistant
+            .process_conversation(conversation, user_message, &origin)
+            .await?;
         let assistant_message = conversation_update.assistant_message();
         let attachments = conversation_update.attachments();
         info!(
@@ -187,8 +233,8 @@ impl MastodonPlatformInner {
         // 公開範囲は最大 unlisted でリプライ元に合わせる
         // CW はリプライ元があったらそのまま、ないときは要そぎぎなら付与
         let mut sanitized_text = sanitize_markdown_mastodon(&assistant_message.text);
-        if sanitized_text.chars().count() > self.max_length {
-            sanitized_text = sanitized_text.chars().take(self.max_length).collect();
+        if sanitized_text.chars().count() > config.max_length {
+            sanitized_text = sanitized_text.chars().take(config.max_length).collect();
             sanitized_text.push_str("...(omitted)");
         }
         let reply_text = format!("@{} {sanitized_text}", status.account.acct);
@@ -197,7 +243,7 @@ impl MastodonPlatformInner {
             otherwise => otherwise,
         };
         let reply_spoiler = match &status.spoiler_text[..] {
-            "" => assistant_message.is_sensitive.then(|| self.sensitive_spoiler.clone()),
+            "" => assistant_message.is_sensitive.then(|| config.sensitive_spoiler.clone()),
             _ => Some(status.spoiler_text),
         };
         let reply_status = NewStatus {
@@ -220,6 +266,23 @@ impl MastodonPlatformInner {
         Ok(())
     }
 
+    /// `access` の設定に従ってアカウントに応答してよいかを判定する。
+    fn is_allowed_account(&self, access: &AppConfigAccessList, account: &Account) -> bool {
+        let (acct, domain) = match account.acct.split_once('@') {
+            Some((_, domain)) => (account.acct.clone(), domain),
+            None => (
+                format!("{}@{}", account.acct, self.local_domain),
+                self.local_domain.as_str(),
+            ),
+        };
+        let subjects = [
+            format!("acct:{acct}"),
+            format!("domain:{domain}"),
+            format!("user:{}", account.id),
+        ];
+        access.is_allowed(&subjects)
+    }
+
     async fn upload_image(&self, url: &Url, description: Option<&str>) -> Result<AttachmentId, PlatformError> {
         // ダウンロード
         let response = self.http_client.get(url.to_string()).send().await?;
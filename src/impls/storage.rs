@@ -1,7 +1,8 @@
 mod memory;
+mod metered;
 mod sqlite;
 
-use self::{memory::MemoryConversationStorage, sqlite::SqliteConversationStorage};
+use self::{memory::MemoryConversationStorage, metered::MeteredConversationStorage, sqlite::SqliteConversationStorage};
 use crate::{
     error::StorageError,
     model::config::{AppConfigStorage, AppConfigStorageBackend},
@@ -11,12 +12,15 @@ use crate::{
 use rmp_serde::{decode::Error as RmpDecodeError, encode::Error as RmpEncodeError};
 use sqlx::Error as SqlxError;
 
+/// レート制限のカウンタを保持しておく期間 (秒)。使われている最も長い期間 (1 日) に合わせる。
+const COUNTER_RETENTION_SECONDS: u64 = 24 * 60 * 60;
+
 pub async fn create_storage(config: &AppConfigStorage) -> Result<Box<dyn ConversationStorage + 'static>, StorageError> {
     let boxed_storage: Box<dyn ConversationStorage> = match config.backend {
         AppConfigStorageBackend::Memory => Box::new(MemoryConversationStorage::new()),
         AppConfigStorageBackend::Sqlite => Box::new(SqliteConversationStorage::new(&config.sqlite).await?),
     };
-    Ok(boxed_storage)
+    Ok(Box::new(MeteredConversationStorage::new(boxed_storage)))
 }
 
 impl From<SqlxError> for StorageError {
@@ -36,3 +40,34 @@ impl From<RmpEncodeError> for StorageError {
         StorageError::Serialization(value.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 各バックエンドに共通するレート制限カウンタの振る舞いを確かめる。
+    /// `stored_counters` は残っているカウンタのキーと期間の開始時刻を昇順で返すこと。
+    pub(super) async fn assert_counts_up_and_prunes<F>(
+        storage: &dyn ConversationStorage,
+        stored_counters: impl Fn() -> F,
+    ) where
+        F: Future<Output = Vec<(String, u64)>>,
+    {
+        for (key, window_start, expected) in [("a", 0, 1), ("a", 0, 2), ("b", 60, 1), ("a", 60, 1)] {
+            let count = storage
+                .increment_counter(key, window_start)
+                .await
+                .expect("failed to increment");
+            assert_eq!(count, expected, "{key} at {window_start}");
+        }
+        assert_eq!(stored_counters().await, [("a".to_string(), 60), ("b".to_string(), 60)]);
+
+        // 二度と来ないユーザーのカウンタも、最も長い期間を過ぎたら捨てられる
+        let later = COUNTER_RETENTION_SECONDS + 60;
+        storage
+            .increment_counter("c", later)
+            .await
+            .expect("failed to increment");
+        assert_eq!(stored_counters().await, [("c".to_string(), later)]);
+    }
+}
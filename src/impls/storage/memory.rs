@@ -1,9 +1,21 @@
-use crate::{error::StorageError, model::conversation::Conversation, specs::storage::ConversationStorage};
+use super::COUNTER_RETENTION_SECONDS;
+use crate::{
+    error::StorageError,
+    model::{
+        conversation::{Conversation, ConversationEntry, ConversationOrigin},
+        usage::{DailyUsage, ModelUsage, UsageRecord},
+    },
+    specs::storage::ConversationStorage,
+};
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 use bimap::BiHashMap;
 use futures::{FutureExt, future::BoxFuture};
+use time::OffsetDateTime;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
@@ -15,6 +27,8 @@ impl MemoryConversationStorage {
         MemoryConversationStorage(Arc::new(MemoryConversationStorageInner {
             conversations: Mutex::new(HashMap::new()),
             platform_contexts: Mutex::new(BiHashMap::new()),
+            counters: Mutex::new(HashMap::new()),
+            usages: Mutex::new(vec![]),
         }))
     }
 }
@@ -40,12 +54,46 @@ impl ConversationStorage for MemoryConversationStorage {
     ) -> BoxFuture<'a, Result<(), StorageError>> {
         async move { self.0.upsert(conversation, platform, new_context).await }.boxed()
     }
+
+    fn list<'a>(&'a self, limit: usize, offset: usize) -> BoxFuture<'a, Result<Vec<ConversationEntry>, StorageError>> {
+        async move { self.0.list(limit, offset).await }.boxed()
+    }
+
+    fn delete<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<bool, StorageError>> {
+        async move { self.0.delete(id).await }.boxed()
+    }
+
+    fn increment_counter<'a>(&'a self, key: &'a str, window_start: u64) -> BoxFuture<'a, Result<u64, StorageError>> {
+        async move { self.0.increment_counter(key, window_start).await }.boxed()
+    }
+
+    fn record_usage<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), StorageError>> {
+        async move { self.0.record_usage(record).await }.boxed()
+    }
+
+    fn daily_usage(&self, since: u64) -> BoxFuture<'_, Result<Vec<DailyUsage>, StorageError>> {
+        async move { self.0.daily_usage(since).await }.boxed()
+    }
+
+    fn usage_by_model<'a>(
+        &'a self,
+        since: u64,
+        user: Option<&'a ConversationOrigin>,
+    ) -> BoxFuture<'a, Result<Vec<ModelUsage>, StorageError>> {
+        async move { self.0.usage_by_model(since, user).await }.boxed()
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
 }
 
 #[derive(Debug)]
 struct MemoryConversationStorageInner {
     conversations: Mutex<HashMap<Uuid, Conversation>>,
     platform_contexts: Mutex<BiHashMap<(String, String), Uuid>>,
+    counters: Mutex<HashMap<String, (u64, u64)>>,
+    usages: Mutex<Vec<UsageRecord>>,
 }
 
 impl MemoryConversationStorageInner {
@@ -78,4 +126,122 @@ impl MemoryConversationStorageInner {
         locked_pc.insert((platform.to_string(), new_context.to_string()), conversation.id());
         Ok(())
     }
+
+    async fn list(&self, limit: usize, offset: usize) -> Result<Vec<ConversationEntry>, StorageError> {
+        let locked_conv = self.conversations.lock().await;
+        let locked_pc = self.platform_contexts.lock().await;
+
+        let mut ids: Vec<_> = locked_conv.keys().copied().collect();
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        let entries = ids
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|id| {
+                let platform_context = locked_pc.get_by_right(&id);
+                ConversationEntry {
+                    id,
+                    platform: platform_context.map(|(p, _)| p.clone()),
+                    context: platform_context.map(|(_, c)| c.clone()),
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    async fn delete(&self, id: &Uuid) -> Result<bool, StorageError> {
+        let mut locked_conv = self.conversations.lock().await;
+        let mut locked_pc = self.platform_contexts.lock().await;
+
+        locked_pc.remove_by_right(id);
+        Ok(locked_conv.remove(id).is_some())
+    }
+
+    async fn increment_counter(&self, key: &str, window_start: u64) -> Result<u64, StorageError> {
+        let mut locked = self.counters.lock().await;
+
+        // どの期間でも終わっているカウンタは捨てる
+        locked.retain(|_, (current_window, _)| *current_window + COUNTER_RETENTION_SECONDS > window_start);
+
+        // 期間が変わっていたら数え直す
+        let (current_window, count) = locked.entry(key.to_string()).or_insert((window_start, 0));
+        if *current_window != window_start {
+            *current_window = window_start;
+            *count = 0;
+        }
+        *count += 1;
+        Ok(*count)
+    }
+
+    async fn record_usage(&self, record: &UsageRecord) -> Result<(), StorageError> {
+        let mut locked = self.usages.lock().await;
+        locked.push(record.clone());
+        Ok(())
+    }
+
+    async fn daily_usage(&self, since: u64) -> Result<Vec<DailyUsage>, StorageError> {
+        let locked = self.usages.lock().await;
+
+        let mut daily: BTreeMap<(String, String), DailyUsage> = BTreeMap::new();
+        for record in locked.iter().filter(|r| r.recorded_at >= since) {
+            let date = format_utc_date(record.recorded_at)?;
+            let entry = daily
+                .entry((date.clone(), record.model.clone()))
+                .or_insert_with(|| DailyUsage {
+                    date,
+                    model: record.model.clone(),
+                    tokens: Default::default(),
+                    turns: 0,
+                });
+            entry.tokens += record.tokens;
+            entry.turns += 1;
+        }
+        Ok(daily.into_values().collect())
+    }
+
+    async fn usage_by_model(
+        &self,
+        since: u64,
+        user: Option<&ConversationOrigin>,
+    ) -> Result<Vec<ModelUsage>, StorageError> {
+        let locked = self.usages.lock().await;
+
+        let mut by_model: HashMap<&str, ModelUsage> = HashMap::new();
+        let records = locked
+            .iter()
+            .filter(|r| r.recorded_at >= since && user.is_none_or(|u| r.platform == u.platform && r.user == u.user));
+        for record in records {
+            let entry = by_model.entry(&record.model).or_insert_with(|| ModelUsage {
+                model: record.model.clone(),
+                tokens: Default::default(),
+            });
+            entry.tokens += record.tokens;
+        }
+        Ok(by_model.into_values().collect())
+    }
+}
+
+fn format_utc_date(timestamp: u64) -> Result<String, StorageError> {
+    let datetime =
+        OffsetDateTime::from_unix_timestamp(timestamp as i64).map_err(|e| StorageError::Serialization(e.into()))?;
+    Ok(datetime.date().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::storage::tests::assert_counts_up_and_prunes;
+
+    #[tokio::test]
+    async fn counts_up_within_window_and_prunes_expired_ones() {
+        let storage = MemoryConversationStorage::new();
+        let inner = &storage.0;
+        assert_counts_up_and_prunes(&storage, move || async move {
+            let locked = inner.counters.lock().await;
+            let mut counters: Vec<_> = locked.iter().map(|(key, (window, _))| (key.clone(), *window)).collect();
+            counters.sort();
+            counters
+        })
+        .await;
+    }
 }
@@ -0,0 +1,96 @@
+use crate::{
+    error::StorageError,
+    metrics::METRICS,
+    model::{
+        conversation::{Conversation, ConversationEntry, ConversationOrigin},
+        usage::{DailyUsage, ModelUsage, UsageRecord},
+    },
+    specs::storage::ConversationStorage,
+};
+
+use futures::{FutureExt, future::BoxFuture};
+use uuid::Uuid;
+
+/// 内側の `ConversationStorage` のエラーを操作ごとに数える。
+#[derive(Debug)]
+pub struct MeteredConversationStorage {
+    inner: Box<dyn ConversationStorage>,
+}
+
+impl MeteredConversationStorage {
+    pub fn new(inner: Box<dyn ConversationStorage>) -> MeteredConversationStorage {
+        MeteredConversationStorage { inner }
+    }
+}
+
+impl ConversationStorage for MeteredConversationStorage {
+    fn find_by_id<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<Option<Conversation>, StorageError>> {
+        metered("find_by_id", self.inner.find_by_id(id))
+    }
+
+    fn find_by_platform_context<'a>(
+        &'a self,
+        platform: &'a str,
+        context: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Conversation>, StorageError>> {
+        metered(
+            "find_by_platform_context",
+            self.inner.find_by_platform_context(platform, context),
+        )
+    }
+
+    fn upsert<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+        platform: &'a str,
+        new_context: &'a str,
+    ) -> BoxFuture<'a, Result<(), StorageError>> {
+        metered("upsert", self.inner.upsert(conversation, platform, new_context))
+    }
+
+    fn list<'a>(&'a self, limit: usize, offset: usize) -> BoxFuture<'a, Result<Vec<ConversationEntry>, StorageError>> {
+        metered("list", self.inner.list(limit, offset))
+    }
+
+    fn delete<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<bool, StorageError>> {
+        metered("delete", self.inner.delete(id))
+    }
+
+    fn increment_counter<'a>(&'a self, key: &'a str, window_start: u64) -> BoxFuture<'a, Result<u64, StorageError>> {
+        metered("increment_counter", self.inner.increment_counter(key, window_start))
+    }
+
+    fn record_usage<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), StorageError>> {
+        metered("record_usage", self.inner.record_usage(record))
+    }
+
+    fn daily_usage(&self, since: u64) -> BoxFuture<'_, Result<Vec<DailyUsage>, StorageError>> {
+        metered("daily_usage", self.inner.daily_usage(since))
+    }
+
+    fn usage_by_model<'a>(
+        &'a self,
+        since: u64,
+        user: Option<&'a ConversationOrigin>,
+    ) -> BoxFuture<'a, Result<Vec<ModelUsage>, StorageError>> {
+        metered("usage_by_model", self.inner.usage_by_model(since, user))
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        self.inner.close()
+    }
+}
+
+fn metered<'a, T: Send + 'a>(
+    operation: &'static str,
+    future: BoxFuture<'a, Result<T, StorageError>>,
+) -> BoxFuture<'a, Result<T, StorageError>> {
+    async move {
+        let result = future.await;
+        if result.is_err() {
+            METRICS.storage_errors.with_label_values(&[operation]).inc();
+        }
+        result
+    }
+    .boxed()
+}
@@ -1,6 +1,11 @@
+use super::COUNTER_RETENTION_SECONDS;
 use crate::{
     error::StorageError,
-    model::{config::AppConfigStorageSqlite, conversation::Conversation},
+    model::{
+        config::AppConfigStorageSqlite,
+        conversation::{Conversation, ConversationEntry, ConversationOrigin},
+        usage::{DailyUsage, ModelUsage, TokenUsage, UsageRecord},
+    },
     specs::storage::ConversationStorage,
 };
 
@@ -10,15 +15,39 @@ use futures::{FutureExt, future::BoxFuture};
 use sqlx::{SqlitePool, prelude::FromRow};
 use uuid::Uuid;
 
+/// 既存のデータベースにも適用する、後から追加したテーブル。内容は schema-sqlite.sql と揃えること。
+const SCHEMA_MIGRATIONS: &[&str] = &[
+    r#"CREATE TABLE IF NOT EXISTS rate_counters(
+    counter_key TEXT NOT NULL,
+    window_start INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (counter_key, window_start)
+);"#,
+    r#"CREATE INDEX IF NOT EXISTS rate_counter_window_index ON rate_counters(window_start);"#,
+    r#"CREATE TABLE IF NOT EXISTS usages(
+    platform TEXT NOT NULL,
+    user TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    cached_tokens INTEGER NOT NULL,
+    recorded_at INTEGER NOT NULL
+);"#,
+    r#"CREATE INDEX IF NOT EXISTS usage_user_index ON usages(platform, user, recorded_at);"#,
+    r#"CREATE INDEX IF NOT EXISTS usage_conversation_index ON usages(conversation_id);"#,
+    r#"CREATE INDEX IF NOT EXISTS usage_recorded_at_index ON usages(recorded_at);"#,
+];
+
 #[derive(Debug, Clone)]
 pub struct SqliteConversationStorage(Arc<SqliteConversationStorageInner>);
 
 impl SqliteConversationStorage {
     pub async fn new(config: &AppConfigStorageSqlite) -> Result<SqliteConversationStorage, StorageError> {
         let pool = SqlitePool::connect(&config.filepath.to_string_lossy()).await?;
-        Ok(SqliteConversationStorage(Arc::new(SqliteConversationStorageInner {
-            pool,
-        })))
+        let inner = SqliteConversationStorageInner { pool };
+        inner.migrate().await?;
+        Ok(SqliteConversationStorage(Arc::new(inner)))
     }
 }
 
@@ -43,6 +72,38 @@ impl ConversationStorage for SqliteConversationStorage {
     ) -> BoxFuture<'a, Result<(), StorageError>> {
         async move { self.0.upsert(conversation, platform, new_context).await }.boxed()
     }
+
+    fn list<'a>(&'a self, limit: usize, offset: usize) -> BoxFuture<'a, Result<Vec<ConversationEntry>, StorageError>> {
+        async move { self.0.list(limit, offset).await }.boxed()
+    }
+
+    fn delete<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<bool, StorageError>> {
+        async move { self.0.delete(id).await }.boxed()
+    }
+
+    fn increment_counter<'a>(&'a self, key: &'a str, window_start: u64) -> BoxFuture<'a, Result<u64, StorageError>> {
+        async move { self.0.increment_counter(key, window_start).await }.boxed()
+    }
+
+    fn record_usage<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), StorageError>> {
+        async move { self.0.record_usage(record).await }.boxed()
+    }
+
+    fn daily_usage(&self, since: u64) -> BoxFuture<'_, Result<Vec<DailyUsage>, StorageError>> {
+        async move { self.0.daily_usage(since).await }.boxed()
+    }
+
+    fn usage_by_model<'a>(
+        &'a self,
+        since: u64,
+        user: Option<&'a ConversationOrigin>,
+    ) -> BoxFuture<'a, Result<Vec<ModelUsage>, StorageError>> {
+        async move { self.0.usage_by_model(since, user).await }.boxed()
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        async { self.0.pool.close().await }.boxed()
+    }
 }
 
 #[derive(Debug)]
@@ -51,6 +112,13 @@ struct SqliteConversationStorageInner {
 }
 
 impl SqliteConversationStorageInner {
+    async fn migrate(&self) -> Result<(), StorageError> {
+        for statement in SCHEMA_MIGRATIONS {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
     async fn find_by_id(&self, id: &Uuid) -> Result<Option<Conversation>, StorageError> {
         let row: Option<SqliteRowConversation> =
             sqlx::query_as(r#"SELECT id, conversation_blob FROM conversations WHERE id = ?"#)
@@ -98,6 +166,161 @@ impl SqliteConversationStorageInner {
 
         Ok(())
     }
+
+    async fn list(&self, limit: usize, offset: usize) -> Result<Vec<ConversationEntry>, StorageError> {
+        // UUIDv7 なので ID の降順がそのまま新しい順になる
+        let rows: Vec<SqliteRowConversationEntry> = sqlx::query_as(
+            r#"
+            SELECT c.id, p.platform, p.context
+            FROM conversations c
+            LEFT JOIN platform_contexts p ON p.conversation_id = c.id
+            ORDER BY c.id DESC
+            LIMIT ? OFFSET ?
+            "#,
+        )
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let entries = rows
+            .into_iter()
+            .map(|r| ConversationEntry {
+                id: r.id,
+                platform: r.platform,
+                context: r.context,
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    async fn delete(&self, id: &Uuid) -> Result<bool, StorageError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM platform_contexts WHERE conversation_id = ?"#)
+            .bind(id)
+            .execute(&mut *transaction)
+            .await?;
+        let result = sqlx::query(r#"DELETE FROM conversations WHERE id = ?"#)
+            .bind(id)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn increment_counter(&self, key: &str, window_start: u64) -> Result<u64, StorageError> {
+        let window_start = window_start as i64;
+
+        let expired_before = window_start - COUNTER_RETENTION_SECONDS as i64;
+
+        // 同じ key の古い期間のカウンタと、どの期間でも終わっているカウンタは捨てる
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM rate_counters WHERE (counter_key = ? AND window_start < ?) OR window_start <= ?"#)
+            .bind(key)
+            .bind(window_start)
+            .bind(expired_before)
+            .execute(&mut *transaction)
+            .await?;
+        let (count,): (i64,) = sqlx::query_as(r#"INSERT INTO rate_counters (counter_key, window_start, count) VALUES (?, ?, 1) ON CONFLICT DO UPDATE SET count = count + 1 RETURNING count;"#)
+            .bind(key)
+            .bind(window_start)
+            .fetch_one(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(count as u64)
+    }
+
+    async fn record_usage(&self, record: &UsageRecord) -> Result<(), StorageError> {
+        sqlx::query(r#"INSERT INTO usages (platform, user, conversation_id, model, prompt_tokens, completion_tokens, cached_tokens, recorded_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?);"#)
+            .bind(&record.platform)
+            .bind(&record.user)
+            .bind(record.conversation_id)
+            .bind(&record.model)
+            .bind(record.tokens.prompt_tokens as i64)
+            .bind(record.tokens.completion_tokens as i64)
+            .bind(record.tokens.cached_tokens as i64)
+            .bind(record.recorded_at as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn daily_usage(&self, since: u64) -> Result<Vec<DailyUsage>, StorageError> {
+        let rows: Vec<SqliteRowDailyUsage> = sqlx::query_as(
+            r#"
+            SELECT
+                date(recorded_at, 'unixepoch') AS date,
+                model,
+                SUM(prompt_tokens) AS prompt_tokens,
+                SUM(completion_tokens) AS completion_tokens,
+                SUM(cached_tokens) AS cached_tokens,
+                COUNT(*) AS turns
+            FROM usages
+            WHERE recorded_at >= ?
+            GROUP BY date, model
+            ORDER BY date, model
+            "#,
+        )
+        .bind(since as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let daily_usages = rows
+            .into_iter()
+            .map(|r| DailyUsage {
+                date: r.date,
+                model: r.model,
+                tokens: TokenUsage {
+                    prompt_tokens: r.prompt_tokens as u64,
+                    completion_tokens: r.completion_tokens as u64,
+                    cached_tokens: r.cached_tokens as u64,
+                },
+                turns: r.turns as u64,
+            })
+            .collect();
+        Ok(daily_usages)
+    }
+
+    async fn usage_by_model(
+        &self,
+        since: u64,
+        user: Option<&ConversationOrigin>,
+    ) -> Result<Vec<ModelUsage>, StorageError> {
+        let rows: Vec<SqliteRowModelUsage> = sqlx::query_as(
+            r#"
+            SELECT
+                model,
+                SUM(prompt_tokens) AS prompt_tokens,
+                SUM(completion_tokens) AS completion_tokens,
+                SUM(cached_tokens) AS cached_tokens
+            FROM usages
+            WHERE recorded_at >= ? AND (? IS NULL OR (platform = ? AND user = ?))
+            GROUP BY model
+            "#,
+        )
+        .bind(since as i64)
+        .bind(user.map(|u| &u.platform))
+        .bind(user.map(|u| &u.platform))
+        .bind(user.map(|u| &u.user))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let model_usages = rows
+            .into_iter()
+            .map(|r| ModelUsage {
+                model: r.model,
+                tokens: TokenUsage {
+                    prompt_tokens: r.prompt_tokens as u64,
+                    completion_tokens: r.completion_tokens as u64,
+                    cached_tokens: r.cached_tokens as u64,
+                },
+            })
+            .collect();
+        Ok(model_usages)
+    }
 }
 
 #[derive(Debug, Clone, FromRow)]
@@ -114,3 +337,58 @@ struct SqliteRowPlatformContext {
     platform: String,
     context: String,
 }
+
+#[derive(Debug, Clone, FromRow)]
+struct SqliteRowConversationEntry {
+    id: Uuid,
+    platform: Option<String>,
+    context: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct SqliteRowDailyUsage {
+    date: String,
+    model: String,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    cached_tokens: i64,
+    turns: i64,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct SqliteRowModelUsage {
+    model: String,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    cached_tokens: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impls::storage::tests::assert_counts_up_and_prunes;
+
+    use tempfile::NamedTempFile;
+
+    async fn stored_counters(storage: &SqliteConversationStorage) -> Vec<(String, u64)> {
+        let rows: Vec<(String, i64)> =
+            sqlx::query_as(r#"SELECT counter_key, window_start FROM rate_counters ORDER BY counter_key, window_start"#)
+                .fetch_all(&storage.0.pool)
+                .await
+                .expect("failed to fetch counters");
+        rows.into_iter().map(|(key, window)| (key, window as u64)).collect()
+    }
+
+    #[tokio::test]
+    async fn counts_up_within_window_and_prunes_expired_ones() {
+        let database = NamedTempFile::new().expect("failed to create database file");
+        let config = AppConfigStorageSqlite {
+            filepath: database.path().to_path_buf(),
+        };
+        let storage = SqliteConversationStorage::new(&config)
+            .await
+            .expect("failed to open storage");
+
+        assert_counts_up_and_prunes(&storage, || stored_counters(&storage)).await;
+    }
+}
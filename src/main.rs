@@ -2,29 +2,48 @@ mod assistant;
 mod cli;
 mod error;
 mod impls;
+mod metrics;
 mod model;
+mod reload;
+mod server;
 mod specs;
+mod supervisor;
 mod text;
 
 use crate::{
-    assistant::Assistant,
+    assistant::{Assistant, AssistantProfile},
     impls::{
         function::{GetIllustUrl, ImageGenerator, LocalInfo, SelfInfo},
+        hook::KeywordFilter,
         llm::create_llm,
         platform::{CliPlatform, DiscordPlatform, MastodonPlatform},
         storage::create_storage,
     },
     model::config::AppConfig,
-    specs::platform::ConversationPlatform,
+    reload::ReloadableConfig,
+    supervisor::Supervisor,
 };
 
-use std::path::Path;
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context as _, Result, bail};
 use clap::Parser;
 use futures::future::join_all;
-use tokio::{fs::read_to_string, spawn};
-use tracing::info;
+use tokio::{
+    fs::read_to_string,
+    select,
+    signal::{
+        ctrl_c,
+        unix::{SignalKind, signal},
+    },
+    spawn,
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
 /// クライアントに設定する UserAgent。
 pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
@@ -33,60 +52,195 @@ pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PK
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let args = cli::Arguments::parse();
-    let config = load_config(args.config).await?;
+    let config = load_config(&args.config).await?;
+    validate_config(&config)?;
 
-    let Some(assistant_identity) = config.assistant.identities.get(&config.assistant.identity) else {
-        bail!("assistant identity {} not defined", config.assistant.identity);
-    };
+    if let Some(command) = args.command {
+        return match command {
+            cli::Command::UsageReport { days } => print_usage_report(&config, days).await,
+        };
+    }
 
-    let llm = create_llm(&config.llm).await?;
+    let profile = create_assistant_profile(&config).await?;
     let storage = create_storage(&config.storage).await?;
-    let assistant = Assistant::new(assistant_identity, llm, storage);
+    let assistant = Assistant::new(profile, storage);
 
-    assistant.add_simple_function(SelfInfo::new()).await;
-    assistant.add_simple_function(LocalInfo::new()?).await;
-    if config.tool.image_generator.enabled {
-        assistant
-            .add_simple_function(ImageGenerator::new(&config.tool.image_generator)?)
-            .await;
-    }
-    if config.tool.get_illust_url.enabled {
-        assistant
-            .add_simple_function(GetIllustUrl::new(&config.tool.get_illust_url).await?)
-            .await;
-    }
+    let platform_config = ReloadableConfig::new(config.platform.clone());
+    spawn(reload::watch_config(
+        args.config.clone(),
+        assistant.clone(),
+        platform_config.clone(),
+    ));
 
+    let shutdown = CancellationToken::new();
+    let supervisor = Supervisor::new(&config.supervisor, shutdown.clone());
     let mut platform_tasks = vec![];
 
     // CLI
     if config.platform.cli.enabled {
         info!("starting CLI platform");
-        let cli_platform = CliPlatform::new(assistant.clone());
-        let cli_task = spawn(cli_platform.execute());
-        platform_tasks.push(Box::new(cli_task));
+        let cli_platform = CliPlatform::new(platform_config.clone(), assistant.clone());
+        platform_tasks.push(supervisor.spawn("cli", cli_platform));
     }
 
     // Mastodon
     if config.platform.mastodon.enabled {
         info!("starting Mastodon platform");
-        let mastodon_platform = MastodonPlatform::new(&config.platform.mastodon, assistant.clone()).await?;
-        let mastodon_task = spawn(mastodon_platform.execute());
-        platform_tasks.push(Box::new(mastodon_task));
+        let mastodon_platform = MastodonPlatform::new(platform_config.clone(), assistant.clone()).await?;
+        platform_tasks.push(supervisor.spawn("mastodon", mastodon_platform));
     }
 
     // Discord
     if config.platform.discord.enabled {
         info!("starting Discord platform");
-        let discord_platform = DiscordPlatform::new(&config.platform.discord, assistant.clone()).await?;
-        let discord_task = spawn(discord_platform.execute());
-        platform_tasks.push(Box::new(discord_task));
+        let discord_platform = DiscordPlatform::new(platform_config.clone(), assistant.clone()).await?;
+        platform_tasks.push(supervisor.spawn("discord", discord_platform));
+    }
+
+    if config.server.enabled {
+        let server_config = config.server.clone();
+        let (server_supervisor, server_assistant, server_shutdown) =
+            (supervisor.clone(), assistant.clone(), shutdown.clone());
+        spawn(async move {
+            if let Err(err) = server::serve(&server_config, server_supervisor, server_assistant, server_shutdown).await
+            {
+                error!("HTTP server failed: {err}");
+            }
+        });
+    }
+
+    let platform_aborts: Vec<_> = platform_tasks.iter().map(|t| t.abort_handle()).collect();
+    let mut platforms_finished = join_all(platform_tasks);
+    select! {
+        _ = &mut platforms_finished => (),
+        _ = wait_shutdown_signal() => {
+            let grace_period = config.shutdown.grace_period();
+            info!("shutdown requested, waiting up to {grace_period:?} for in-flight replies");
+            shutdown.cancel();
+            if timeout(grace_period, &mut platforms_finished).await.is_err() {
+                // ストレージを閉じる前に、処理中の返信ごとプラットフォームを止めきる
+                warn!("grace period exceeded, cancelling in-flight replies");
+                for platform_abort in &platform_aborts {
+                    platform_abort.abort();
+                }
+                platforms_finished.await;
+            }
+        }
     }
 
-    join_all(platform_tasks).await;
+    for (name, state) in supervisor.states() {
+        info!("platform {name}: {state}");
+    }
+    assistant.close().await;
+    info!("shut down");
     Ok(())
 }
 
+/// Ctrl-C か SIGTERM を受信するまで待つ。
+async fn wait_shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("failed to listen SIGTERM: {e}");
+            ctrl_c().await.ok();
+            return;
+        }
+    };
+    select! {
+        _ = ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
 async fn load_config(path: impl AsRef<Path>) -> Result<AppConfig> {
-    let config_str = read_to_string(path).await.context("failed to read config file")?;
-    toml::from_str(&config_str).context("failed to parse config")
+    let config_str = read_to_string(&path).await.context("failed to read config file")?;
+    let mut config: AppConfig = toml::from_str(&config_str).context("failed to parse config")?;
+    config.resolve_paths(path.as_ref());
+    Ok(config)
+}
+
+fn validate_config(config: &AppConfig) -> Result<()> {
+    for identity in config.referenced_identities() {
+        if !config.assistant.identities.contains_key(identity) {
+            bail!("assistant identity {identity} not defined");
+        }
+    }
+    if config.server.admin.enabled && !config.server.enabled {
+        bail!("admin API requires [server] to be enabled");
+    }
+    if config.server.admin.enabled && config.server.admin.token.is_empty() {
+        bail!("admin API requires a token");
+    }
+    Ok(())
+}
+
+/// 日ごとのトークン使用量を表示する。
+async fn print_usage_report(config: &AppConfig, days: u64) -> Result<()> {
+    const DAY_SECONDS: u64 = 24 * 60 * 60;
+
+    let storage = create_storage(&config.storage).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let since = (now - now % DAY_SECONDS).saturating_sub(days.saturating_sub(1) * DAY_SECONDS);
+    let daily_usages = storage.daily_usage(since).await?;
+
+    println!(
+        "{:<10}  {:<40}  {:>6}  {:>10}  {:>10}  {:>10}",
+        "date", "model", "turns", "prompt", "cached", "completion"
+    );
+    for daily in daily_usages {
+        println!(
+            "{:<10}  {:<40}  {:>6}  {:>10}  {:>10}  {:>10}",
+            daily.date,
+            daily.model,
+            daily.turns,
+            daily.tokens.prompt_tokens,
+            daily.tokens.cached_tokens,
+            daily.tokens.completion_tokens
+        );
+    }
+    Ok(())
+}
+
+/// 設定から `AssistantProfile` を構築する。設定の再読み込み時にも利用する。
+async fn create_assistant_profile(config: &AppConfig) -> Result<AssistantProfile> {
+    let llm = create_llm(&config.llm).await?;
+    let mut profile = AssistantProfile::new(&config.assistant, llm);
+    if let Some(budget) = &config.assistant.budget {
+        let economy_llm_config = match &budget.economy_model {
+            Some(model) => config.llm.with_model(model),
+            None => config.llm.clone(),
+        };
+        profile = profile.with_economy_llm(create_llm(&economy_llm_config).await?);
+    }
+
+    let tool_config = &config.tool;
+    profile
+        .add_simple_function(SelfInfo::new(), tool_config.timeout(None))
+        .await;
+    profile
+        .add_simple_function(LocalInfo::new()?, tool_config.timeout(None))
+        .await;
+    if tool_config.image_generator.enabled {
+        profile
+            .add_simple_function(
+                ImageGenerator::new(&tool_config.image_generator)?,
+                tool_config.timeout(tool_config.image_generator.timeout_seconds),
+            )
+            .await;
+    }
+    if tool_config.get_illust_url.enabled {
+        profile
+            .add_simple_function(
+                GetIllustUrl::new(&tool_config.get_illust_url).await?,
+                tool_config.timeout(tool_config.get_illust_url.timeout_seconds),
+            )
+            .await;
+    }
+
+    let hook_config = &config.hook;
+    if hook_config.keyword_filter.enabled {
+        profile.add_hook(KeywordFilter::new(&hook_config.keyword_filter));
+    }
+
+    Ok(profile)
 }
@@ -0,0 +1,95 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder, exponential_buckets,
+};
+
+/// プロセス全体で共有するメトリクス。
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Prometheus 形式で公開するメトリクス群。
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+
+    /// プラットフォームごとのリクエスト数。
+    pub requests: IntCounterVec,
+
+    /// モデルごとの LLM の応答にかかった時間。
+    pub llm_latency: HistogramVec,
+
+    /// モデル・種別ごとのトークン使用量。
+    pub tokens: IntCounterVec,
+
+    /// `SimpleFunction` ごとの呼び出し回数。
+    pub tool_calls: IntCounterVec,
+
+    /// `SimpleFunction` ごとの失敗回数。タイムアウトも含む。
+    pub tool_failures: IntCounterVec,
+
+    /// 操作ごとの永続化層のエラー数。
+    pub storage_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("natsuki".to_string()), None).expect("invalid registry prefix");
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests received from platforms"),
+            &["platform"],
+        )
+        .expect("invalid metric");
+        let llm_latency = HistogramVec::new(
+            HistogramOpts::new("llm_request_duration_seconds", "Time taken for LLM responses")
+                .buckets(exponential_buckets(0.25, 2.0, 10).expect("invalid buckets")),
+            &["model", "result"],
+        )
+        .expect("invalid metric");
+        let tokens = IntCounterVec::new(
+            Opts::new("llm_tokens_total", "Tokens consumed by LLM requests"),
+            &["model", "kind"],
+        )
+        .expect("invalid metric");
+        let tool_calls =
+            IntCounterVec::new(Opts::new("tool_calls_total", "Tool calls"), &["function"]).expect("invalid metric");
+        let tool_failures = IntCounterVec::new(
+            Opts::new("tool_call_failures_total", "Failed tool calls"),
+            &["function"],
+        )
+        .expect("invalid metric");
+        let storage_errors = IntCounterVec::new(
+            Opts::new("storage_errors_total", "Errors from the storage backend"),
+            &["operation"],
+        )
+        .expect("invalid metric");
+
+        for collector in [&requests, &tokens, &tool_calls, &tool_failures, &storage_errors] {
+            registry
+                .register(Box::new(collector.clone()))
+                .expect("duplicate metric");
+        }
+        registry
+            .register(Box::new(llm_latency.clone()))
+            .expect("duplicate metric");
+
+        Metrics {
+            registry,
+            requests,
+            llm_latency,
+            tokens,
+            tool_calls,
+            tool_failures,
+            storage_errors,
+        }
+    }
+
+    /// Prometheus のテキスト形式で書き出す。
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("failed to encode metrics");
+        String::from_utf8(buffer).expect("metrics must be UTF-8")
+    }
+}
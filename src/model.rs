@@ -1,4 +1,6 @@
+pub mod command;
 pub mod config;
 pub mod conversation;
 pub mod message;
 pub mod schema;
+pub mod usage;
@@ -0,0 +1,32 @@
+use crate::model::message::{UserMessage, UserMessageContent};
+
+/// LLM に送らずに Assistant が直接処理するコマンド。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssistantCommand {
+    /// `!reset`: 会話をリセットする。
+    Reset,
+
+    /// `!identity [name]`: identity を表示・切り替える。
+    Identity(Option<String>),
+
+    /// `!tools`: 利用可能な tool を表示する。
+    Tools,
+}
+
+impl AssistantCommand {
+    /// `UserMessage` の最初のテキストがコマンドであれば解釈する。
+    pub fn parse(user_message: &UserMessage) -> Option<AssistantCommand> {
+        let text = user_message.contents.iter().find_map(|umc| match umc {
+            UserMessageContent::Text(text) => Some(text),
+            _ => None,
+        })?;
+
+        let mut words = text.trim().strip_prefix('!')?.split_whitespace();
+        match words.next()? {
+            "reset" => Some(AssistantCommand::Reset),
+            "identity" => Some(AssistantCommand::Identity(words.next().map(|w| w.to_string()))),
+            "tools" => Some(AssistantCommand::Tools),
+            _ => None,
+        }
+    }
+}
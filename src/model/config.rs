@@ -1,4 +1,8 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use serde::Deserialize;
 
@@ -10,6 +14,135 @@ pub struct AppConfig {
     pub llm: AppConfigLlm,
     pub storage: AppConfigStorage,
     pub assistant: AppConfigAssistant,
+
+    #[serde(default = "Default::default")]
+    pub hook: AppConfigHook,
+
+    #[serde(default = "Default::default")]
+    pub shutdown: AppConfigShutdown,
+
+    #[serde(default = "Default::default")]
+    pub supervisor: AppConfigSupervisor,
+
+    #[serde(default = "Default::default")]
+    pub server: AppConfigServer,
+}
+
+/// [server]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigServer {
+    pub enabled: bool,
+
+    /// `/healthz` と `/metrics` を待ち受けるアドレス。
+    #[serde(default = "default_server_bind_address")]
+    pub bind_address: String,
+
+    #[serde(default = "Default::default")]
+    pub admin: AppConfigServerAdmin,
+}
+
+/// [server.admin]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AppConfigServerAdmin {
+    pub enabled: bool,
+
+    /// `Authorization: Bearer <token>` として要求するトークン。
+    pub token: String,
+}
+
+impl Default for AppConfigServer {
+    fn default() -> AppConfigServer {
+        AppConfigServer {
+            enabled: false,
+            bind_address: default_server_bind_address(),
+            admin: AppConfigServerAdmin::default(),
+        }
+    }
+}
+
+fn default_server_bind_address() -> String {
+    "127.0.0.1:9090".to_string()
+}
+
+/// [supervisor]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigSupervisor {
+    /// 初回の再起動までの秒数。以降は 2 倍ずつ伸びる。
+    #[serde(default = "default_supervisor_base_delay_seconds")]
+    pub base_delay_seconds: u64,
+
+    #[serde(default = "default_supervisor_max_delay_seconds")]
+    pub max_delay_seconds: u64,
+
+    /// 連続して再起動する回数の上限。未指定なら諦めない。
+    #[serde(default = "Default::default")]
+    pub max_restarts: Option<usize>,
+
+    /// これ以上の秒数動いていたら再起動回数を数え直す。
+    #[serde(default = "default_supervisor_reset_after_seconds")]
+    pub reset_after_seconds: u64,
+}
+
+impl Default for AppConfigSupervisor {
+    fn default() -> AppConfigSupervisor {
+        AppConfigSupervisor {
+            base_delay_seconds: default_supervisor_base_delay_seconds(),
+            max_delay_seconds: default_supervisor_max_delay_seconds(),
+            max_restarts: None,
+            reset_after_seconds: default_supervisor_reset_after_seconds(),
+        }
+    }
+}
+
+impl AppConfigSupervisor {
+    /// `attempt` 回目の再起動までの待ち時間を返す。
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16) as u32;
+        let seconds = self.base_delay_seconds.saturating_mul(1 << exponent);
+        Duration::from_secs(seconds.min(self.max_delay_seconds))
+    }
+
+    pub fn reset_after(&self) -> Duration {
+        Duration::from_secs(self.reset_after_seconds)
+    }
+}
+
+fn default_supervisor_base_delay_seconds() -> u64 {
+    1
+}
+
+fn default_supervisor_max_delay_seconds() -> u64 {
+    300
+}
+
+fn default_supervisor_reset_after_seconds() -> u64 {
+    600
+}
+
+/// [shutdown]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigShutdown {
+    /// シャットダウン要求から処理中の返信を打ち切るまでの秒数。
+    #[serde(default = "default_shutdown_grace_period_seconds")]
+    pub grace_period_seconds: u64,
+}
+
+impl Default for AppConfigShutdown {
+    fn default() -> AppConfigShutdown {
+        AppConfigShutdown {
+            grace_period_seconds: default_shutdown_grace_period_seconds(),
+        }
+    }
+}
+
+impl AppConfigShutdown {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_seconds)
+    }
+}
+
+fn default_shutdown_grace_period_seconds() -> u64 {
+    20
 }
 
 /// [platform]
@@ -29,6 +162,10 @@ pub struct AppConfigPlatform {
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct AppConfigPlatformCli {
     pub enabled: bool,
+
+    /// 利用する assistant identity。未指定なら [assistant].identity。
+    #[serde(default = "Default::default")]
+    pub identity: Option<String>,
 }
 
 /// [platform.mastodon]
@@ -39,6 +176,19 @@ pub struct AppConfigPlatformMastodon {
     pub token: String,
     pub sensitive_spoiler: String,
     pub max_length: usize,
+
+    /// このアカウントで利用する assistant identity。未指定なら [assistant].identity。
+    #[serde(default = "Default::default")]
+    pub identity: Option<String>,
+
+    /// ローカルのアカウントのドメイン。未指定なら `server_url` のホスト。
+    #[serde(default = "Default::default")]
+    pub local_domain: Option<String>,
+
+    /// 応答するアカウントの制限。
+    /// `acct:user@example.com` (ローカルのアカウントもドメインを付ける)、`domain:example.com`、`user:<アカウント ID>` で指定する。
+    #[serde(default = "Default::default")]
+    pub access: AppConfigAccessList,
 }
 
 /// [platform.discord]
@@ -47,11 +197,74 @@ pub struct AppConfigPlatformDiscord {
     pub enabled: bool,
     pub token: String,
     pub max_length: usize,
+
+    /// 利用する assistant identity。未指定なら [assistant].identity。
+    #[serde(default = "Default::default")]
+    pub identity: Option<String>,
+
+    /// ギルド ID ごとの assistant identity。
+    #[serde(default = "Default::default")]
+    pub guild_identities: HashMap<String, String>,
+
+    /// チャンネル ID ごとの assistant identity。ギルドの指定より優先される。
+    #[serde(default = "Default::default")]
+    pub channel_identities: HashMap<String, String>,
+
+    /// 応答するユーザー・場所の制限。
+    /// `user:<ユーザー ID>`、`guild:<ギルド ID>`、`channel:<チャンネル ID>` で指定する。
+    #[serde(default = "Default::default")]
+    pub access: AppConfigAccessList,
 }
 
-/// [tool]
+impl AppConfigPlatformDiscord {
+    /// ギルド・チャンネルに対応する assistant identity を返す。
+    pub fn identity_for(&self, guild_id: Option<&str>, channel_id: &str) -> Option<&str> {
+        self.channel_identities
+            .get(channel_id)
+            .or_else(|| guild_id.and_then(|g| self.guild_identities.get(g)))
+            .or(self.identity.as_ref())
+            .map(|i| i.as_str())
+    }
+}
+
+/// [platform.*.access]
 #[derive(Debug, Clone, Default, Deserialize)]
+pub struct AppConfigAccessList {
+    /// true なら `allow` のいずれかに該当する場合のみ応答する。
+    #[serde(default = "Default::default")]
+    pub allowlist_only: bool,
+
+    #[serde(default = "Default::default")]
+    pub allow: Vec<String>,
+
+    /// `allow` より優先される。
+    #[serde(default = "Default::default")]
+    pub deny: Vec<String>,
+}
+
+impl AppConfigAccessList {
+    /// リクエスト元を表す `subjects` (`user:...` など) に応答してよいかを判定する。
+    /// acct やドメインは大文字小文字を区別しないので、ASCII の範囲で無視して比較する。
+    pub fn is_allowed<S: AsRef<str>>(&self, subjects: &[S]) -> bool {
+        let matches = |list: &[String]| {
+            subjects
+                .iter()
+                .any(|s| list.iter().any(|e| e.eq_ignore_ascii_case(s.as_ref())))
+        };
+        if matches(&self.deny) {
+            return false;
+        }
+        !self.allowlist_only || matches(&self.allow)
+    }
+}
+
+/// [tool]
+#[derive(Debug, Clone, Deserialize)]
 pub struct AppConfigTool {
+    /// 個別に指定されていない tool の実行タイムアウト秒数。
+    #[serde(default = "default_tool_timeout_seconds")]
+    pub timeout_seconds: u64,
+
     #[serde(default = "Default::default")]
     pub image_generator: AppConfigToolImageGenerator,
 
@@ -59,6 +272,17 @@ pub struct AppConfigTool {
     pub get_illust_url: AppConfigToolGetIllustUrl,
 }
 
+impl AppConfigTool {
+    /// tool 個別のタイムアウト秒数を考慮した実行タイムアウトを返す。
+    pub fn timeout(&self, specific_seconds: Option<u64>) -> Duration {
+        Duration::from_secs(specific_seconds.unwrap_or(self.timeout_seconds))
+    }
+}
+
+fn default_tool_timeout_seconds() -> u64 {
+    30
+}
+
 /// [tool.image_generator]
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct AppConfigToolImageGenerator {
@@ -66,6 +290,9 @@ pub struct AppConfigToolImageGenerator {
     pub endpoint: String,
     pub token: String,
     pub model: String,
+
+    #[serde(default = "Default::default")]
+    pub timeout_seconds: Option<u64>,
 }
 
 /// [tool.get_illust_url]
@@ -73,6 +300,26 @@ pub struct AppConfigToolImageGenerator {
 pub struct AppConfigToolGetIllustUrl {
     pub enabled: bool,
     pub database_filepath: String,
+
+    #[serde(default = "Default::default")]
+    pub timeout_seconds: Option<u64>,
+}
+
+/// [hook]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AppConfigHook {
+    #[serde(default = "Default::default")]
+    pub keyword_filter: AppConfigHookKeywordFilter,
+}
+
+/// [hook.keyword_filter]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AppConfigHookKeywordFilter {
+    pub enabled: bool,
+
+    /// ユーザーの入力か応答にこれらのいずれかを含む場合に `reply` で置き換える。大文字小文字は区別しない。
+    pub keywords: Vec<String>,
+    pub reply: String,
 }
 
 /// [storage]
@@ -99,13 +346,109 @@ pub struct AppConfigStorageSqlite {
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppConfigLlm {
     pub backend: AppConfigLlmBackend,
-    pub openai: AppConfigLlmOpenai,
+
+    /// `backend` に対応するセクションのみ必要。
+    #[serde(default = "Default::default")]
+    pub openai: Option<AppConfigLlmOpenai>,
+
+    #[serde(default = "Default::default")]
+    pub claude: Option<AppConfigLlmClaude>,
+
+    #[serde(default = "Default::default")]
+    pub ollama: Option<AppConfigLlmOllama>,
+
+    #[serde(default = "Default::default")]
+    pub gemini: Option<AppConfigLlmGemini>,
+
+    #[serde(default = "Default::default")]
+    pub mock: Option<AppConfigLlmMock>,
+
+    /// 一時的な障害時の再試行設定。未指定なら再試行しない。
+    #[serde(default = "Default::default")]
+    pub retry: Option<AppConfigLlmRetry>,
+
+    /// 再試行も含めて応答を待つ最大秒数。超えた場合は失敗として扱う。
+    /// ストリーミングでは、最初のイベントの後も各イベントをこの秒数まで待つ。
+    #[serde(default = "Default::default")]
+    pub timeout_seconds: Option<u64>,
+
+    /// 失敗したときに順に試すバックエンド。
+    #[serde(default = "Default::default")]
+    pub fallbacks: Vec<AppConfigLlm>,
+}
+
+/// [llm.retry]
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AppConfigLlmRetry {
+    /// 初回を含めた最大試行回数。
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: usize,
+
+    /// 初回の再試行までの待ち時間。以降は 2 倍ずつ伸びる。
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub base_delay_ms: u64,
+
+    /// 待ち時間の上限。`Retry-After` がこれを超える場合は再試行しない。
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub max_delay_ms: u64,
+
+    /// 待ち時間をランダムに増減させる割合。
+    #[serde(default = "default_retry_jitter")]
+    pub jitter: f64,
+}
+
+fn default_retry_max_attempts() -> usize {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_retry_jitter() -> f64 {
+    0.2
+}
+
+impl AppConfigLlm {
+    /// 利用するモデルだけを差し替えた設定を返す。
+    pub fn with_model(&self, model: &str) -> AppConfigLlm {
+        let mut config = self.clone();
+        let model_field = match config.backend {
+            AppConfigLlmBackend::Openai => config.openai.as_mut().map(|c| &mut c.model),
+            AppConfigLlmBackend::Claude => config.claude.as_mut().map(|c| &mut c.model),
+            AppConfigLlmBackend::Ollama => config.ollama.as_mut().map(|c| &mut c.model),
+            AppConfigLlmBackend::Gemini => config.gemini.as_mut().map(|c| &mut c.model),
+            AppConfigLlmBackend::Mock => config.mock.as_mut().map(|c| &mut c.model),
+        };
+        if let Some(model_field) = model_field {
+            *model_field = model.to_string();
+        }
+        config
+    }
+
+    /// 設定内の相対パスを `base_dir` 起点に解決する。
+    fn resolve_paths(&mut self, base_dir: &Path) {
+        if let Some(mock) = &mut self.mock {
+            mock.script = base_dir.join(&mock.script);
+        }
+        for fallback in &mut self.fallbacks {
+            fallback.resolve_paths(base_dir);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum AppConfigLlmBackend {
     Openai,
+    Claude,
+    Ollama,
+    Gemini,
+    Mock,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -115,20 +458,271 @@ pub struct AppConfigLlmOpenai {
     pub token: String,
     pub model: String,
     pub max_token: usize,
+
+    /// JSON Schema で出力形式を指定する。有効にすると本文が JSON に埋まるので、ストリーミングでの逐次表示はされない。
     pub use_structured_output: bool,
+
+    #[serde(default = "Default::default")]
+    pub context_budget: Option<AppConfigContextBudget>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum AppConfigLlmOpenaiApi {
     ChatCompletion,
+
+    #[serde(alias = "responses")]
     Resnposes,
 }
 
+/// [llm.claude]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigLlmClaude {
+    #[serde(default = "default_claude_endpoint")]
+    pub endpoint: String,
+    pub token: String,
+    pub model: String,
+    pub max_token: usize,
+
+    /// 応答用の tool を強制することで `sensitive` などを構造化して受け取る。
+    pub use_structured_output: bool,
+
+    /// system prompt と tool 定義を prompt caching の対象にする。
+    #[serde(default = "default_claude_prompt_caching")]
+    pub prompt_caching: bool,
+
+    #[serde(default = "Default::default")]
+    pub context_budget: Option<AppConfigContextBudget>,
+}
+
+fn default_claude_endpoint() -> String {
+    "https://api.anthropic.com".to_string()
+}
+
+fn default_claude_prompt_caching() -> bool {
+    true
+}
+
+/// [llm.ollama]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigLlmOllama {
+    #[serde(default = "default_ollama_endpoint")]
+    pub endpoint: String,
+    pub model: String,
+    pub max_token: usize,
+
+    /// `language` と `sensitive` の受け取り方。
+    #[serde(default = "default_ollama_structured_output")]
+    pub structured_output: AppConfigLlmOllamaStructuredOutput,
+
+    /// tool calling に対応していないモデルでは false にする。
+    #[serde(default = "default_ollama_use_tools")]
+    pub use_tools: bool,
+
+    /// 画像を扱えるモデルでは true にする。最新のユーザー入力の画像だけを base64 で送る。
+    #[serde(default = "Default::default")]
+    pub use_images: bool,
+
+    #[serde(default = "Default::default")]
+    pub context_budget: Option<AppConfigContextBudget>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppConfigLlmOllamaStructuredOutput {
+    /// 受け取らない。
+    None,
+
+    /// JSON schema で出力形式を指定する。
+    JsonSchema,
+
+    /// 応答の先頭行に書くよう prompt で指示する。
+    Prompt,
+}
+
+fn default_ollama_endpoint() -> String {
+    "http://localhost:11434".to_string()
+}
+
+fn default_ollama_structured_output() -> AppConfigLlmOllamaStructuredOutput {
+    AppConfigLlmOllamaStructuredOutput::Prompt
+}
+
+fn default_ollama_use_tools() -> bool {
+    true
+}
+
+/// [llm.gemini]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigLlmGemini {
+    #[serde(default = "default_gemini_endpoint")]
+    pub endpoint: String,
+    pub token: String,
+    pub model: String,
+    pub max_token: usize,
+
+    /// 出力形式を指定する。tool と `responseSchema` は同時に使えないので、
+    /// tool を送る間は代わりに応答用の関数を必ず呼ばせる。
+    pub use_structured_output: bool,
+
+    #[serde(default = "Default::default")]
+    pub context_budget: Option<AppConfigContextBudget>,
+}
+
+fn default_gemini_endpoint() -> String {
+    "https://generativelanguage.googleapis.com/v1beta".to_string()
+}
+
+/// [llm.mock]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigLlmMock {
+    /// 応答のスクリプト。拡張子が `.jsonl` なら JSON Lines、それ以外は TOML として読む。
+    /// 相対パスは設定ファイルのあるディレクトリから解決する。
+    pub script: PathBuf,
+
+    /// 使用量の記録に使うモデル名。
+    #[serde(default = "default_mock_model")]
+    pub model: String,
+}
+
+fn default_mock_model() -> String {
+    "mock".to_string()
+}
+
+/// モデルに送信する履歴の上限。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct AppConfigContextBudget {
+    pub unit: AppConfigContextBudgetUnit,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppConfigContextBudgetUnit {
+    /// トークン数(文字種からの概算)。
+    Tokens,
+
+    /// 文字数。
+    Characters,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AppConfigAssistant {
     pub identity: String,
     pub identities: HashMap<String, AppConfigAssistantIdentity>,
+
+    #[serde(default = "Default::default")]
+    pub rate_limit: AppConfigRateLimit,
+
+    /// 利用料金の上限。未指定なら制限しない。
+    #[serde(default = "Default::default")]
+    pub budget: Option<AppConfigBudget>,
+}
+
+/// [assistant.budget]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigBudget {
+    /// モデルごとの 100 万トークンあたりの料金。ここにないモデルの料金は 0 として扱う。
+    pub prices: HashMap<String, AppConfigModelPrice>,
+
+    /// 全ユーザー合計の上限。
+    #[serde(default = "Default::default")]
+    pub global: AppConfigBudgetLimit,
+
+    /// ユーザーごとの上限。
+    #[serde(default = "Default::default")]
+    pub per_user: AppConfigBudgetLimit,
+
+    /// 上限に対してこの割合を使ったら節約モードに切り替える。
+    #[serde(default = "default_budget_economy_ratio")]
+    pub economy_ratio: f64,
+
+    /// 節約モードで利用するモデル。未指定なら同じモデルのまま。
+    #[serde(default = "Default::default")]
+    pub economy_model: Option<String>,
+
+    /// 節約モードで無効にする tool。
+    #[serde(default = "Default::default")]
+    pub economy_disabled_tools: Vec<String>,
+}
+
+fn default_budget_economy_ratio() -> f64 {
+    0.8
+}
+
+/// [assistant.budget.prices.*]
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AppConfigModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+
+    /// キャッシュが効いた入力トークンの料金。未指定なら `prompt` と同じ。
+    #[serde(default = "Default::default")]
+    pub cached: Option<f64>,
+}
+
+/// [assistant.budget.global] および [assistant.budget.per_user]
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct AppConfigBudgetLimit {
+    /// 1 日 (UTC) あたりの上限。
+    #[serde(default = "Default::default")]
+    pub daily: Option<f64>,
+
+    /// 1 か月 (UTC) あたりの上限。
+    #[serde(default = "Default::default")]
+    pub monthly: Option<f64>,
+}
+
+/// [assistant.rate_limit]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AppConfigRateLimit {
+    /// 全プラットフォーム共通のユーザーごとの制限。
+    #[serde(flatten)]
+    pub default: AppConfigRateLimitRule,
+
+    /// プラットフォームごとの制限。指定されたプラットフォームでは `default` の代わりに利用する。
+    #[serde(default = "Default::default")]
+    pub platforms: HashMap<String, AppConfigRateLimitRule>,
+}
+
+impl AppConfigRateLimit {
+    /// 指定したプラットフォームに適用する制限を返す。
+    pub fn rule_for(&self, platform: &str) -> &AppConfigRateLimitRule {
+        self.platforms.get(platform).unwrap_or(&self.default)
+    }
+}
+
+/// [assistant.rate_limit] および [assistant.rate_limit.platforms.*]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AppConfigRateLimitRule {
+    /// ユーザーごとの 1 分あたりのリクエスト数。未指定なら制限しない。
+    #[serde(default = "Default::default")]
+    pub requests_per_minute: Option<u64>,
+
+    /// ユーザーごとの 1 日あたりの tool 呼び出し回数。キーは tool 名。
+    #[serde(default = "Default::default")]
+    pub tool_calls_per_day: HashMap<String, u64>,
+}
+
+impl AppConfig {
+    /// 設定内で参照されている assistant identity を列挙する。
+    pub fn referenced_identities(&self) -> impl Iterator<Item = &str> {
+        let discord = &self.platform.discord;
+        [&self.assistant.identity]
+            .into_iter()
+            .chain(&self.platform.cli.identity)
+            .chain(&self.platform.mastodon.identity)
+            .chain(&discord.identity)
+            .chain(discord.guild_identities.values())
+            .chain(discord.channel_identities.values())
+            .map(|i| i.as_str())
+    }
+
+    /// 設定内の相対パスを、カレントディレクトリではなく設定ファイルのあるディレクトリ起点に解決する。
+    pub fn resolve_paths(&mut self, config_path: &Path) {
+        let base_dir = config_path.parent().unwrap_or(Path::new(""));
+        self.llm.resolve_paths(base_dir);
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -137,4 +731,62 @@ pub struct AppConfigAssistantIdentity {
 
     #[serde(default = "Default::default")]
     pub sensitive_marker: String,
+
+    /// 1 回の応答で tool calling を繰り返してよい最大ラウンド数。
+    #[serde(default = "default_max_tool_rounds")]
+    pub max_tool_rounds: usize,
+
+    /// `max_tool_rounds` を超過したときに代わりに返す応答。未指定ならエラーになる。
+    #[serde(default = "Default::default")]
+    pub tool_rounds_exceeded_message: Option<String>,
+
+    /// 長くなった会話の要約設定。未指定なら要約しない。
+    #[serde(default = "Default::default")]
+    pub summary: Option<AppConfigAssistantSummary>,
+
+    /// リクエスト数の制限に達したときに返す応答。
+    #[serde(default = "default_rate_limited_message")]
+    pub rate_limited_message: String,
+
+    /// 利用料金の上限に達したときに返す応答。
+    #[serde(default = "default_out_of_energy_message")]
+    pub out_of_energy_message: String,
+}
+
+fn default_max_tool_rounds() -> usize {
+    4
+}
+
+fn default_rate_limited_message() -> String {
+    "先パイ、ちょっと話しかけすぎッス……少し休ませてほしいッス。".to_string()
+}
+
+fn default_out_of_energy_message() -> String {
+    "今日はもう電池切れッス……また明日話しかけてほしいッス。".to_string()
+}
+
+/// [assistant.identities.*.summary]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfigAssistantSummary {
+    /// system message を除くメッセージ数がこれを超えたら古いターンを要約する。
+    pub threshold_messages: usize,
+
+    /// 要約せずにそのまま残す直近のターン数。
+    #[serde(default = "default_summary_keep_recent_turns")]
+    pub keep_recent_turns: usize,
+
+    /// 要約を生成させるときの指示。
+    #[serde(default = "default_summary_prompt")]
+    pub prompt: String,
+}
+
+fn default_summary_keep_recent_turns() -> usize {
+    2
+}
+
+fn default_summary_prompt() -> String {
+    "以下はユーザーとアシスタントのこれまでの会話の記録です。\
+    この後も会話を続けられるように、話題の流れ・ユーザーについて判明したこと・約束や決定事項を中心に、\
+    会話で使われている言語で簡潔に要約してください。要約の本文だけを出力してください。"
+        .to_string()
 }
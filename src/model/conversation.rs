@@ -1,6 +1,12 @@
-use crate::model::message::{AssistantMessage, Message, UserMessage};
+use crate::model::{
+    config::AppConfigContextBudget,
+    message::{AssistantMessage, Message, UserMessage},
+};
+
+use std::collections::BTreeSet;
 
 use serde::{Deserialize, Serialize};
+use tracing::debug;
 use url::Url;
 use uuid::Uuid;
 
@@ -8,25 +14,96 @@ use uuid::Uuid;
 pub struct Conversation {
     id: Uuid,
     messages: Vec<Message>,
+
+    /// この `Conversation` を開始したときの assistant identity 名。
+    #[serde(default = "Default::default")]
+    identity: Option<String>,
 }
 
 impl Conversation {
-    pub fn new_now(system: Option<Message>) -> Conversation {
+    pub fn new_now(identity: impl Into<String>, system: Option<Message>) -> Conversation {
         Conversation {
             id: Uuid::now_v7(),
             messages: system.into_iter().collect(),
+            identity: Some(identity.into()),
         }
     }
 
     pub fn id(&self) -> Uuid {
         self.id
     }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// identity を切り替え、system message を置き換える。
+    pub fn switch_identity(&mut self, identity: impl Into<String>, system: Message) {
+        self.messages.retain(|m| !matches!(m, Message::System(_)));
+        self.messages.insert(0, system);
+        self.identity = Some(identity.into());
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// この `Conversation` 中で呼び出された tool の名前を重複なく返す。
+    pub fn tool_names(&self) -> BTreeSet<&str> {
+        self.messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::FunctionCalls(calls) => Some(calls.0.iter().map(|c| c.name.as_str())),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// 最新 `keep_recent_turns` ターンより前の、要約の対象となるメッセージを返す。
+    /// system message は対象に含まないが、以前の要約は含む。
+    pub fn summary_targets(&self, keep_recent_turns: usize) -> Vec<&Message> {
+        let cut_index = self.summary_cut_index(keep_recent_turns);
+        self.messages[..cut_index]
+            .iter()
+            .filter(|m| !matches!(m, Message::System(_)))
+            .collect()
+    }
+
+    /// `summary_targets` が返すメッセージを要約で置き換える。
+    pub fn apply_summary(&mut self, keep_recent_turns: usize, summary: impl Into<String>) {
+        let cut_index = self.summary_cut_index(keep_recent_turns);
+        let recent_messages = self.messages.split_off(cut_index);
+        self.messages.retain(|m| matches!(m, Message::System(_)));
+        self.messages.push(Message::new_summary(summary));
+        self.messages.extend(recent_messages);
+    }
+
+    fn summary_cut_index(&self, keep_recent_turns: usize) -> usize {
+        let turn_starts: Vec<_> = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| matches!(m, Message::User(_)))
+            .map(|(i, _)| i)
+            .collect();
+        // 残すターンより前にターンがなければ、以前の要約だけを要約し直すことになるので何もしない
+        match turn_starts.len().checked_sub(keep_recent_turns) {
+            None | Some(0) => 0,
+            Some(first_kept) if first_kept < turn_starts.len() => turn_starts[first_kept],
+            Some(_) => self.messages.len(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct IncompleteConversation {
     pub id: Uuid,
     pub latest_messages: Vec<Message>,
+    pub identity: Option<String>,
+
+    /// 登録されている tool を使わせるかどうか。要約など、会話への応答でない送信では無効にする。
+    pub use_tools: bool,
 }
 
 impl IncompleteConversation {
@@ -36,7 +113,71 @@ impl IncompleteConversation {
         IncompleteConversation {
             id: conversation.id,
             latest_messages: conversation.messages,
+            identity: conversation.identity,
+            use_tools: true,
+        }
+    }
+
+    /// `budget` に収まるように古いターンを除外した、送信すべきメッセージ列を返す。
+    /// system message と要約、最新のターンは常に残す。ターンは user message を起点に区切るので、
+    /// `FunctionCalls` と `FunctionResponse` の組が分断されることはない。
+    pub fn budgeted_messages(&self, budget: Option<&AppConfigContextBudget>) -> Vec<&Message> {
+        let Some(budget) = budget else {
+            return self.latest_messages.iter().collect();
+        };
+
+        let estimate = |messages: &[Message]| -> usize {
+            messages
+                .iter()
+                .filter(|m| !matches!(m, Message::System(_) | Message::Summary(_)))
+                .map(|m| m.estimate_size(budget.unit))
+                .sum()
+        };
+        let pinned_size: usize = self
+            .latest_messages
+            .iter()
+            .filter(|m| matches!(m, Message::System(_) | Message::Summary(_)))
+            .map(|m| m.estimate_size(budget.unit))
+            .sum();
+
+        // ターンの開始位置(先頭に user message 以外がある場合はそれも 1 つのターンとみなす)
+        let mut turn_starts: Vec<_> = self
+            .latest_messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| matches!(m, Message::User(_)))
+            .map(|(i, _)| i)
+            .collect();
+        if turn_starts.first() != Some(&0) {
+            turn_starts.insert(0, 0);
+        }
+
+        // 新しいターンから積み上げて、収まらなくなったところで打ち切る
+        let mut total_size = pinned_size;
+        let mut turn_end = self.latest_messages.len();
+        let mut cut_index = turn_end;
+        for &turn_start in turn_starts.iter().rev() {
+            let turn_size = estimate(&self.latest_messages[turn_start..turn_end]);
+            if total_size + turn_size > budget.limit && cut_index != self.latest_messages.len() {
+                break;
+            }
+            total_size += turn_size;
+            cut_index = turn_start;
+            turn_end = turn_start;
+        }
+
+        if cut_index > 0 {
+            debug!(
+                "trimmed conversation {} before index {cut_index} to fit context budget",
+                self.id
+            );
         }
+        self.latest_messages
+            .iter()
+            .enumerate()
+            .filter(|(i, m)| *i >= cut_index || matches!(m, Message::System(_) | Message::Summary(_)))
+            .map(|(_, m)| m)
+            .collect()
     }
 
     pub fn finish(
@@ -48,9 +189,36 @@ impl IncompleteConversation {
             conversation: Conversation {
                 id: self.id,
                 messages: self.latest_messages,
+                identity: self.identity,
             },
             assistant_message: last_assistant_message,
             attachments,
+            record_assistant_message: true,
+        }
+    }
+}
+
+/// 永続化されている `Conversation` の一覧の項目。
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationEntry {
+    pub id: Uuid,
+    pub platform: Option<String>,
+    pub context: Option<String>,
+}
+
+/// 会話のリクエスト元のユーザー。
+/// `user` はプラットフォーム内で一意な値であればよい。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConversationOrigin {
+    pub platform: String,
+    pub user: String,
+}
+
+impl ConversationOrigin {
+    pub fn new(platform: impl Into<String>, user: impl Into<String>) -> ConversationOrigin {
+        ConversationOrigin {
+            platform: platform.into(),
+            user: user.into(),
         }
     }
 }
@@ -65,9 +233,21 @@ pub struct ConversationUpdate {
     conversation: Conversation,
     assistant_message: AssistantMessage,
     attachments: Vec<ConversationAttachment>,
+    record_assistant_message: bool,
 }
 
 impl ConversationUpdate {
+    /// 会話履歴には残さない応答を持つ `ConversationUpdate` を生成する。
+    /// コマンドの応答など、LLM を経由しない応答に用いる。
+    pub fn without_history(conversation: Conversation, assistant_message: AssistantMessage) -> ConversationUpdate {
+        ConversationUpdate {
+            conversation,
+            assistant_message,
+            attachments: vec![],
+            record_assistant_message: false,
+        }
+    }
+
     pub fn assistant_message(&self) -> &AssistantMessage {
         &self.assistant_message
     }
@@ -77,7 +257,210 @@ impl ConversationUpdate {
     }
 
     pub fn finish(mut self) -> Conversation {
-        self.conversation.messages.push(self.assistant_message.into());
+        if self.record_assistant_message {
+            self.conversation.messages.push(self.assistant_message.into());
+        }
         self.conversation
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        impls::llm::fixtures::{assistant, function_call, incomplete, user},
+        model::{
+            config::AppConfigContextBudgetUnit,
+            message::{FunctionCallsMessage, FunctionResponseMessage, UserMessageContent},
+        },
+    };
+
+    use serde_json::json;
+
+    fn function_calls(id: &str) -> Message {
+        Message::FunctionCalls(FunctionCallsMessage(vec![function_call(id)]))
+    }
+
+    fn function_response(id: &str) -> Message {
+        Message::FunctionResponse(FunctionResponseMessage {
+            id: id.to_string(),
+            name: "self_info".to_string(),
+            result: json!({ "v": 1 }),
+        })
+    }
+
+    fn budget(unit: AppConfigContextBudgetUnit, limit: usize) -> AppConfigContextBudget {
+        AppConfigContextBudget { unit, limit }
+    }
+
+    fn texts(messages: &[&Message]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|m| match m {
+                Message::System(system) => format!("system:{}", system.0),
+                Message::Summary(summary) => format!("summary:{}", summary.0),
+                Message::User(user) => match &user.contents[0] {
+                    UserMessageContent::Text(text) => format!("user:{text}"),
+                    UserMessageContent::ImageUrl(url) => format!("user:{url}"),
+                },
+                Message::Assistant(assistant) => format!("assistant:{}", assistant.text),
+                Message::FunctionCalls(calls) => format!("calls:{}", calls.0[0].id),
+                Message::FunctionResponse(response) => format!("response:{}", response.id),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_everything_without_budget() {
+        let conversation = incomplete(vec![Message::new_system("s"), user("q1"), assistant("a1"), user("q2")]);
+        assert_eq!(conversation.budgeted_messages(None).len(), 4);
+    }
+
+    #[test]
+    fn always_keeps_system_summary_and_latest_turn() {
+        let conversation = incomplete(vec![
+            Message::new_system("system prompt that is longer than the limit"),
+            Message::new_summary("summary"),
+            user("q1"),
+            assistant("a1"),
+            user("q2"),
+            assistant("a2"),
+        ]);
+        let messages = conversation.budgeted_messages(Some(&budget(AppConfigContextBudgetUnit::Characters, 4)));
+        assert_eq!(
+            texts(&messages),
+            vec![
+                "system:system prompt that is longer than the limit",
+                "summary:summary",
+                "user:q2",
+                "assistant:a2"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_older_turns_within_budget() {
+        let conversation = incomplete(vec![
+            Message::new_system("s"),
+            user("q1"),
+            assistant("a1"),
+            user("q2"),
+            assistant("a2"),
+            user("q3"),
+        ]);
+        // system(1) + q3(2) + q2/a2(4) = 7
+        let messages = conversation.budgeted_messages(Some(&budget(AppConfigContextBudgetUnit::Characters, 7)));
+        assert_eq!(texts(&messages), vec!["system:s", "user:q2", "assistant:a2", "user:q3"]);
+    }
+
+    #[test]
+    fn never_splits_function_calls_and_response() {
+        let conversation = incomplete(vec![
+            Message::new_system("s"),
+            user("q1"),
+            function_calls("call_1"),
+            function_response("call_1"),
+            assistant("a1"),
+            user("q2"),
+            function_calls("call_2"),
+            function_response("call_2"),
+            assistant("a2"),
+        ]);
+        for limit in 0..60 {
+            let messages = conversation.budgeted_messages(Some(&budget(AppConfigContextBudgetUnit::Characters, limit)));
+            let texts = texts(&messages);
+            for id in ["call_1", "call_2"] {
+                assert_eq!(
+                    texts.contains(&format!("calls:{id}")),
+                    texts.contains(&format!("response:{id}")),
+                    "split at limit {limit}: {texts:?}"
+                );
+            }
+            assert!(texts.contains(&"calls:call_2".to_string()));
+        }
+    }
+
+    #[test]
+    fn estimates_size_by_unit() {
+        // ASCII は 4 文字で 1 トークン、それ以外は 1 文字 1 トークンとして数える
+        let conversation = incomplete(vec![user("abcdefghabcdefgh"), assistant("ok"), user("あいう")]);
+        let by_characters = conversation.budgeted_messages(Some(&budget(AppConfigContextBudgetUnit::Characters, 10)));
+        assert_eq!(texts(&by_characters), vec!["user:あいう"]);
+        let by_tokens = conversation.budgeted_messages(Some(&budget(AppConfigContextBudgetUnit::Tokens, 10)));
+        assert_eq!(by_tokens.len(), 3);
+
+        // 画像は URL の長さに関わらず一定のトークン数として数える
+        let image = "https://example.com/a.png".parse().expect("invalid url");
+        let conversation = incomplete(vec![
+            Message::new_user([UserMessageContent::ImageUrl(image)], None, None),
+            assistant("ok"),
+            user("q"),
+        ]);
+        let by_characters = conversation.budgeted_messages(Some(&budget(AppConfigContextBudgetUnit::Characters, 100)));
+        assert_eq!(by_characters.len(), 3);
+        let by_tokens = conversation.budgeted_messages(Some(&budget(AppConfigContextBudgetUnit::Tokens, 100)));
+        assert_eq!(texts(&by_tokens), vec!["user:q"]);
+    }
+
+    fn conversation(messages: Vec<Message>) -> Conversation {
+        Conversation {
+            id: Uuid::now_v7(),
+            messages,
+            identity: Some("test".to_string()),
+        }
+    }
+
+    #[test]
+    fn summary_targets_exclude_system_and_recent_turns() {
+        let conversation = conversation(vec![
+            Message::new_system("s"),
+            Message::new_summary("old"),
+            user("q1"),
+            function_calls("call_1"),
+            function_response("call_1"),
+            assistant("a1"),
+            user("q2"),
+            assistant("a2"),
+            user("q3"),
+            assistant("a3"),
+        ]);
+        let targets: Vec<_> = conversation.summary_targets(2);
+        assert_eq!(
+            texts(&targets),
+            vec![
+                "summary:old",
+                "user:q1",
+                "calls:call_1",
+                "response:call_1",
+                "assistant:a1"
+            ]
+        );
+
+        // 残すターン数が会話より多ければ何も要約しない
+        assert!(conversation.summary_targets(3).is_empty());
+        assert!(conversation.summary_targets(10).is_empty());
+    }
+
+    #[test]
+    fn apply_summary_replaces_targets() {
+        let mut conversation = conversation(vec![
+            Message::new_system("s"),
+            Message::new_summary("old"),
+            user("q1"),
+            assistant("a1"),
+            user("q2"),
+            assistant("a2"),
+        ]);
+        conversation.apply_summary(1, "new");
+        let messages: Vec<_> = conversation.messages().iter().collect();
+        assert_eq!(
+            texts(&messages),
+            vec!["system:s", "summary:new", "user:q2", "assistant:a2"]
+        );
+
+        // 要約済みの会話をさらに要約しても要約は 1 つにまとまる
+        conversation.apply_summary(0, "newer");
+        let messages: Vec<_> = conversation.messages().iter().collect();
+        assert_eq!(texts(&messages), vec!["system:s", "summary:newer"]);
+    }
+}
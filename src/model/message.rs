@@ -1,15 +1,18 @@
+use crate::model::config::AppConfigContextBudgetUnit;
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use url::Url;
 
 /// `Conversation` 中の単一メッセージ。
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 pub enum Message {
     System(SystemMessage),
     User(UserMessage),
     FunctionCalls(FunctionCallsMessage),
     FunctionResponse(FunctionResponseMessage),
     Assistant(AssistantMessage),
+    Summary(SummaryMessage),
 }
 
 #[allow(dead_code)]
@@ -49,6 +52,65 @@ impl Message {
             language,
         })
     }
+
+    pub fn new_summary(text: impl Into<String>) -> Message {
+        Message::Summary(SummaryMessage(text.into()))
+    }
+
+    /// コンテキストとして送信したときのおおよその大きさを見積もる。
+    pub fn estimate_size(&self, unit: AppConfigContextBudgetUnit) -> usize {
+        match self {
+            Message::System(system_message) => estimate_text_size(&system_message.0, unit),
+            Message::User(user_message) => user_message
+                .contents
+                .iter()
+                .map(|umc| match umc {
+                    UserMessageContent::Text(text) => estimate_text_size(text, unit),
+                    UserMessageContent::ImageUrl(url) => match unit {
+                        AppConfigContextBudgetUnit::Tokens => IMAGE_ESTIMATED_TOKENS,
+                        AppConfigContextBudgetUnit::Characters => url.as_str().chars().count(),
+                    },
+                })
+                .sum(),
+            Message::FunctionCalls(function_calls_message) => function_calls_message
+                .0
+                .iter()
+                .map(|c| estimate_text_size(&c.name, unit) + estimate_text_size(&c.arguments.to_string(), unit))
+                .sum(),
+            Message::FunctionResponse(function_response_message) => {
+                estimate_text_size(&function_response_message.result.to_string(), unit)
+            }
+            Message::Assistant(assistant_message) => estimate_text_size(&assistant_message.text, unit),
+            Message::Summary(summary_message) => estimate_text_size(&summary_message.0, unit),
+        }
+    }
+}
+
+/// 古いターンを要約した内容。
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct SummaryMessage(pub String);
+
+impl From<SummaryMessage> for Message {
+    fn from(value: SummaryMessage) -> Message {
+        Message::Summary(value)
+    }
+}
+
+/// 画像 1 枚あたりのトークン数の見積もり。
+const IMAGE_ESTIMATED_TOKENS: usize = 765;
+
+/// テキストの大きさを見積もる。
+/// トークン数は ASCII 4 文字で 1 トークン、それ以外は 1 文字 1 トークンとして概算する。
+fn estimate_text_size(text: &str, unit: AppConfigContextBudgetUnit) -> usize {
+    match unit {
+        AppConfigContextBudgetUnit::Characters => text.chars().count(),
+        AppConfigContextBudgetUnit::Tokens => {
+            let (ascii_chars, other_chars): (usize, usize) = text
+                .chars()
+                .fold((0, 0), |(a, o), c| if c.is_ascii() { (a + 1, o) } else { (a, o + 1) });
+            ascii_chars.div_ceil(4) + other_chars
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
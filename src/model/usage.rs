@@ -0,0 +1,65 @@
+use crate::model::config::AppConfigModelPrice;
+
+use std::ops::AddAssign;
+
+use uuid::Uuid;
+
+/// トークン使用量。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+
+    /// `prompt_tokens` のうちキャッシュが効いた分。
+    pub cached_tokens: u64,
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, rhs: TokenUsage) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+        self.cached_tokens += rhs.cached_tokens;
+    }
+}
+
+impl TokenUsage {
+    /// 100 万トークンあたりの料金から利用料金を計算する。
+    pub fn cost(&self, price: &AppConfigModelPrice) -> f64 {
+        let uncached_prompt_tokens = self.prompt_tokens.saturating_sub(self.cached_tokens);
+        let cached_price = price.cached.unwrap_or(price.prompt);
+        (uncached_prompt_tokens as f64 * price.prompt
+            + self.cached_tokens as f64 * cached_price
+            + self.completion_tokens as f64 * price.completion)
+            / 1_000_000.0
+    }
+}
+
+/// モデルごとのトークン使用量の合計。
+#[derive(Debug, Clone)]
+pub struct ModelUsage {
+    pub model: String,
+    pub tokens: TokenUsage,
+}
+
+/// 1 ターン・1 モデルあたりのトークン使用量の記録。
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    pub platform: String,
+    pub user: String,
+    pub conversation_id: Uuid,
+    pub model: String,
+    pub tokens: TokenUsage,
+
+    /// 記録時刻 (UNIX 秒)。
+    pub recorded_at: u64,
+}
+
+/// 日ごと (UTC)・モデルごとのトークン使用量の集計。
+#[derive(Debug, Clone)]
+pub struct DailyUsage {
+    /// `YYYY-MM-DD` 形式の日付。
+    pub date: String,
+    pub model: String,
+    pub tokens: TokenUsage,
+    pub turns: u64,
+}
@@ -0,0 +1,100 @@
+use crate::{
+    assistant::Assistant, create_assistant_profile, load_config, model::config::AppConfigPlatform, validate_config,
+};
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use tokio::{
+    fs::metadata,
+    signal::unix::{SignalKind, signal},
+    time::interval,
+};
+use tracing::{error, info, warn};
+
+/// 設定ファイルの更新を確認する間隔。
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 再読み込みで差し替えられる設定。利用する側は使うたびに `load()` で最新のものを取り出す。
+#[derive(Debug)]
+pub struct ReloadableConfig<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Clone for ReloadableConfig<T> {
+    fn clone(&self) -> Self {
+        ReloadableConfig(self.0.clone())
+    }
+}
+
+impl<T> ReloadableConfig<T> {
+    pub fn new(value: T) -> ReloadableConfig<T> {
+        ReloadableConfig(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    /// 現在の設定を返す。処理の途中で差し替えられても、返されたものは変わらない。
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().expect("config lock poisoned").clone()
+    }
+
+    pub fn replace(&self, value: T) {
+        let mut locked = self.0.write().expect("config lock poisoned");
+        *locked = Arc::new(value);
+    }
+}
+
+/// SIGHUP を受信するか設定ファイルが更新されたら設定を再読み込みし、`Assistant` と各 platform に反映する。
+/// identity・LLM・tool の設定に加えて、platform ごとの identity の割り当て・アクセス制限・返信の長さなども反映される。
+/// platform の接続に関わる設定 (`enabled`・`token`・`server_url`・`local_domain`) と storage の変更には再起動が必要。
+pub async fn watch_config(path: PathBuf, assistant: Assistant, platform_config: ReloadableConfig<AppConfigPlatform>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            warn!("failed to listen SIGHUP, falling back to file watching only: {e}");
+            None
+        }
+    };
+    let mut ticker = interval(WATCH_INTERVAL);
+    let mut last_modified = modified_time(&path).await;
+
+    loop {
+        tokio::select! {
+            Some(()) = async { hangup.as_mut()?.recv().await } => {
+                info!("received SIGHUP, reloading config");
+            }
+            _ = ticker.tick() => {
+                let modified = modified_time(&path).await;
+                if modified.is_none() || modified == last_modified {
+                    continue;
+                }
+                info!("config file modified, reloading config");
+            }
+        }
+
+        // 読み込みに失敗しても同じ内容で再試行し続けないように先に更新しておく
+        last_modified = modified_time(&path).await;
+        match reload(&path, &assistant, &platform_config).await {
+            Ok(()) => info!("config reloaded (platform connection and storage changes require restart)"),
+            Err(e) => error!("failed to reload config, keeping current one: {e:#}"),
+        }
+    }
+}
+
+async fn reload(
+    path: &Path,
+    assistant: &Assistant,
+    platform_config: &ReloadableConfig<AppConfigPlatform>,
+) -> Result<()> {
+    let config = load_config(path).await?;
+    validate_config(&config)?;
+    let profile = create_assistant_profile(&config).await?;
+    assistant.replace_profile(profile);
+    platform_config.replace(config.platform);
+    Ok(())
+}
+
+async fn modified_time(path: &Path) -> Option<SystemTime> {
+    metadata(path).await.ok()?.modified().ok()
+}
@@ -0,0 +1,71 @@
+mod admin;
+
+use crate::{
+    assistant::Assistant,
+    metrics::METRICS,
+    model::config::AppConfigServer,
+    supervisor::{PlatformState, Supervisor},
+};
+
+use std::{collections::BTreeMap, io::Error as IoError};
+
+use axum::{Json, Router, extract::State, http::StatusCode, response::IntoResponse, routing::get};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// ヘルスチェックとメトリクス、管理用 API を公開する HTTP サーバーを起動する。
+pub async fn serve(
+    config: &AppConfigServer,
+    supervisor: Supervisor,
+    assistant: Assistant,
+    shutdown: CancellationToken,
+) -> Result<(), IoError> {
+    let mut router = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(metrics))
+        .with_state(ServerState { supervisor });
+    if config.admin.enabled {
+        info!("admin API enabled");
+        router = router.nest("/admin", admin::router(&config.admin, assistant));
+    }
+
+    let listener = TcpListener::bind(&config.bind_address).await?;
+    info!("HTTP server listening on {}", listener.local_addr()?);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await
+}
+
+#[derive(Debug, Clone)]
+struct ServerState {
+    supervisor: Supervisor,
+}
+
+/// 各プラットフォームの状態を返す。再起動待ちか失敗しているものがあれば 503 とする。
+async fn healthz(State(state): State<ServerState>) -> impl IntoResponse {
+    let states = state.supervisor.states();
+    let healthy = states
+        .iter()
+        .all(|(_, s)| matches!(s, PlatformState::Running | PlatformState::Stopped));
+    let platforms: BTreeMap<_, _> = states.into_iter().map(|(n, s)| (n, s.to_string())).collect();
+
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "status": if healthy { "ok" } else { "unhealthy" },
+        "platforms": platforms,
+    });
+    (status, Json(body))
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [("content-type", "text/plain; version=0.0.4; charset=utf-8")],
+        METRICS.render(),
+    )
+}
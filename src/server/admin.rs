@@ -0,0 +1,143 @@
+use crate::{
+    assistant::Assistant,
+    error::AssistantError,
+    model::{config::AppConfigServerAdmin, conversation::Conversation},
+};
+
+use std::sync::Arc;
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, header::AUTHORIZATION},
+    middleware::{Next, from_fn_with_state},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// 一覧で一度に返す件数の上限。
+const MAX_LIST_LIMIT: usize = 100;
+
+/// `/admin` 以下のルーティングを構築する。すべて Bearer トークンによる認証を要求する。
+pub fn router(config: &AppConfigServerAdmin, assistant: Assistant) -> Router {
+    let token: Arc<str> = config.token.as_str().into();
+    Router::new()
+        .route("/conversations", get(list_conversations))
+        .route("/conversations/lookup", get(lookup_conversation))
+        .route("/conversations/{id}", get(get_conversation).delete(delete_conversation))
+        .layer(from_fn_with_state(token, authorize))
+        .with_state(assistant)
+}
+
+async fn authorize(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => next.run(request).await,
+        _ => {
+            warn!("unauthorized admin API request to {}", request.uri());
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    #[serde(default = "default_list_limit")]
+    limit: usize,
+
+    #[serde(default = "Default::default")]
+    offset: usize,
+}
+
+fn default_list_limit() -> usize {
+    20
+}
+
+async fn list_conversations(
+    State(assistant): State<Assistant>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Value>, AdminError> {
+    let limit = query.limit.min(MAX_LIST_LIMIT);
+    let entries = assistant.list_conversations(limit, query.offset).await?;
+    Ok(Json(json!({ "conversations": entries })))
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupQuery {
+    platform: String,
+    context: String,
+}
+
+async fn lookup_conversation(
+    State(assistant): State<Assistant>,
+    Query(query): Query<LookupQuery>,
+) -> Result<Json<Value>, AdminError> {
+    let conversation = assistant
+        .restore_conversation(&query.platform, &query.context)
+        .await?
+        .ok_or(AdminError::NotFound)?;
+    Ok(Json(render_conversation(&conversation)))
+}
+
+async fn get_conversation(State(assistant): State<Assistant>, Path(id): Path<Uuid>) -> Result<Json<Value>, AdminError> {
+    let conversation = assistant.find_conversation(&id).await?.ok_or(AdminError::NotFound)?;
+    Ok(Json(render_conversation(&conversation)))
+}
+
+async fn delete_conversation(
+    State(assistant): State<Assistant>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, AdminError> {
+    if !assistant.delete_conversation(&id).await? {
+        return Err(AdminError::NotFound);
+    }
+    info!("conversation {id} deleted via admin API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn render_conversation(conversation: &Conversation) -> Value {
+    json!({
+        "id": conversation.id(),
+        "identity": conversation.identity(),
+        "tools": conversation.tool_names(),
+        "messages": conversation.messages(),
+    })
+}
+
+/// 長さ以外の情報が比較時間から漏れないように比較する。
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug)]
+enum AdminError {
+    NotFound,
+    Assistant(AssistantError),
+}
+
+impl From<AssistantError> for AdminError {
+    fn from(value: AssistantError) -> AdminError {
+        AdminError::Assistant(value)
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        match self {
+            AdminError::NotFound => (StatusCode::NOT_FOUND, Json(json!({ "error": "not found" }))).into_response(),
+            AdminError::Assistant(err) => {
+                warn!("admin API failed: {err}");
+                let body = json!({ "error": err.to_string() });
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+            }
+        }
+    }
+}
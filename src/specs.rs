@@ -1,4 +1,5 @@
 pub mod function;
+pub mod hook;
 pub mod llm;
 pub mod platform;
 pub mod storage;
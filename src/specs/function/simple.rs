@@ -7,7 +7,7 @@ use std::fmt::Debug;
 
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Value, json};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SimpleFunctionDescriptor {
@@ -22,6 +22,19 @@ pub struct SimpleFunctionResponse {
     pub attachments: Vec<ConversationAttachment>,
 }
 
+impl SimpleFunctionResponse {
+    /// 実行に失敗したことを LLM に伝えるレスポンスを生成する。
+    /// LLM はこの内容を元にキャラクターとして失敗を説明する。
+    pub fn error(message: impl Into<String>) -> SimpleFunctionResponse {
+        SimpleFunctionResponse {
+            result: json!({
+                "error": message.into(),
+            }),
+            ..Default::default()
+        }
+    }
+}
+
 pub trait SimpleFunction: Send + Sync + Debug {
     /// この `SimpleFunction` のディスクリプタを返す。
     fn get_descriptor(&self) -> SimpleFunctionDescriptor;
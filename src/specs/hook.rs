@@ -0,0 +1,56 @@
+use crate::{
+    error::AssistantError,
+    model::{conversation::IncompleteConversation, message::AssistantMessage},
+    specs::llm::LlmUpdate,
+};
+
+use std::fmt::Debug;
+
+use futures::{FutureExt, future::BoxFuture};
+
+/// `Assistant` の処理の各段階に割り込むフック。
+/// 登録された順に呼ばれ、いずれかが `HookAction::Reply` を返した時点で処理を打ち切る。
+pub trait AssistantHook: Send + Sync + Debug {
+    /// 生成途中のテキストをそのまま表示してよいかどうか。
+    /// `post_llm` や `post_response` で応答を差し替える可能性があるフックは false を返すこと。
+    fn allows_streaming(&self) -> bool {
+        true
+    }
+
+    /// LLM への最初の送信前に 1 度だけ呼ばれる。
+    fn pre_send<'a>(
+        &'a self,
+        _conversation: &'a mut IncompleteConversation,
+    ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+        async { Ok(HookAction::Continue) }.boxed()
+    }
+
+    /// LLM から応答を受け取るたびに呼ばれる。tool calling の各ラウンドも含む。
+    fn post_llm<'a>(
+        &'a self,
+        _conversation: &'a IncompleteConversation,
+        _update: &'a mut LlmUpdate,
+    ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+        async { Ok(HookAction::Continue) }.boxed()
+    }
+
+    /// 最終的な応答が確定したあと、会話履歴に記録される前に呼ばれる。
+    fn post_response<'a>(
+        &'a self,
+        _conversation: &'a IncompleteConversation,
+        _message: &'a mut AssistantMessage,
+    ) -> BoxFuture<'a, Result<HookAction, AssistantError>> {
+        async { Ok(HookAction::Continue) }.boxed()
+    }
+}
+
+/// フックの処理結果。
+#[derive(Debug, Clone)]
+pub enum HookAction {
+    /// 処理を続ける。
+    Continue,
+
+    /// 処理を打ち切り、この内容で応答する。
+    /// 打ち切った場合はユーザーのメッセージも含めて会話履歴に記録しない。
+    Reply(AssistantMessage),
+}
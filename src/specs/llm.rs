@@ -1,14 +1,20 @@
 use crate::{
     error::LlmError,
-    model::{conversation::IncompleteConversation, message::MessageFunctionCall},
+    model::{conversation::IncompleteConversation, message::MessageFunctionCall, usage::TokenUsage},
     specs::function::simple::SimpleFunctionDescriptor,
 };
 
 use std::fmt::Debug;
 
-use futures::future::BoxFuture;
+use futures::{
+    future::{BoxFuture, FutureExt, TryFutureExt},
+    stream::{BoxStream, StreamExt},
+};
 use serde::Deserialize;
 
+/// `Llm::send_conversation_stream` が返すストリーム。
+pub type LlmStream<'a> = BoxStream<'a, Result<LlmStreamEvent, LlmError>>;
+
 #[allow(dead_code)]
 pub trait Llm: Send + Sync + Debug {
     /// `SimpleFunction` の追加を告知する。
@@ -19,6 +25,15 @@ pub trait Llm: Send + Sync + Debug {
         &'a self,
         conversation: &'a IncompleteConversation,
     ) -> BoxFuture<'a, Result<LlmUpdate, LlmError>>;
+
+    /// `Conversation` を送信し、生成された内容を逐次受け取る。
+    /// ストリーミングに対応しないバックエンドでは `send_conversation` の結果がまとめて `Finished` として返される。
+    fn send_conversation_stream<'a>(&'a self, conversation: &'a IncompleteConversation) -> LlmStream<'a> {
+        self.send_conversation(conversation)
+            .map_ok(LlmStreamEvent::Finished)
+            .into_stream()
+            .boxed()
+    }
 }
 
 /// Conversation を送信した結果生成された内容。
@@ -26,6 +41,16 @@ pub trait Llm: Send + Sync + Debug {
 pub struct LlmUpdate {
     pub response: Option<LlmAssistantResponse>,
     pub tool_callings: Option<Vec<MessageFunctionCall>>,
+
+    /// バックエンドが利用量を返さなかった場合は `None`。
+    pub usage: Option<LlmUsage>,
+}
+
+/// 1 回の送信で消費されたトークン。
+#[derive(Debug, Clone)]
+pub struct LlmUsage {
+    pub model: String,
+    pub tokens: TokenUsage,
 }
 
 /// assistant role としての応答内容。
@@ -35,3 +60,25 @@ pub struct LlmAssistantResponse {
     pub language: Option<String>,
     pub sensitive: Option<bool>,
 }
+
+/// ストリーミング中に生成される内容。
+#[derive(Debug, Clone)]
+pub enum LlmStreamEvent {
+    /// 応答テキストの差分。
+    TextDelta(String),
+
+    /// tool calling の断片。
+    ToolCallingDelta(LlmToolCallingDelta),
+
+    /// 生成が完了した。内容は `Llm::send_conversation` の結果と同等。
+    Finished(LlmUpdate),
+}
+
+/// ストリーミング中の tool calling の断片。
+#[derive(Debug, Clone)]
+pub struct LlmToolCallingDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
@@ -1,9 +1,17 @@
 use crate::error::PlatformError;
 
 use futures::future::BoxFuture;
+use tokio_util::sync::CancellationToken;
 
 pub trait ConversationPlatform {
     /// このプラットフォームに対して処理を開始する。
     /// 基本的には返される Future は半永久的に処理が続くが、`execute()` 自身は複数回呼ばれる可能性を考慮しなければならない。
-    fn execute(&self) -> BoxFuture<'static, Result<(), PlatformError>>;
+    /// `shutdown` がキャンセルされたら新しいイベントの受け付けをやめ、処理中の返信を済ませてから終了すること。
+    /// 猶予時間を過ぎると返される Future は drop されるので、処理中の返信もそれに合わせて中断すること。
+    fn execute(&self, shutdown: CancellationToken) -> BoxFuture<'static, Result<(), PlatformError>>;
+
+    /// `execute()` が正常に終了したときにも再起動すべきかどうか。
+    fn restart_on_exit(&self) -> bool {
+        true
+    }
 }
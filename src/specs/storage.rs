@@ -1,4 +1,10 @@
-use crate::{error::StorageError, model::conversation::Conversation};
+use crate::{
+    error::StorageError,
+    model::{
+        conversation::{Conversation, ConversationEntry, ConversationOrigin},
+        usage::{DailyUsage, ModelUsage, UsageRecord},
+    },
+};
 
 use std::fmt::Debug;
 
@@ -26,4 +32,32 @@ pub trait ConversationStorage: Send + Sync + Debug {
         platform: &'a str,
         new_context: &'a str,
     ) -> BoxFuture<'a, Result<(), StorageError>>;
+
+    /// `Conversation` を新しい順に列挙する。
+    fn list<'a>(&'a self, limit: usize, offset: usize) -> BoxFuture<'a, Result<Vec<ConversationEntry>, StorageError>>;
+
+    /// `Conversation` とその platform-context を削除する。存在した場合は true を返す。
+    fn delete<'a>(&'a self, id: &'a Uuid) -> BoxFuture<'a, Result<bool, StorageError>>;
+
+    /// レート制限用のカウンタを 1 進め、進めた後の値を返す。
+    /// `window_start` はカウンタの期間の開始時刻 (UNIX 秒) で、同じ `key` のより古い期間のカウンタは破棄してよい。
+    /// 他の `key` のカウンタも、最も長い期間 (1 日) より前に始まったものは破棄してよい。
+    fn increment_counter<'a>(&'a self, key: &'a str, window_start: u64) -> BoxFuture<'a, Result<u64, StorageError>>;
+
+    /// トークン使用量を記録する。
+    fn record_usage<'a>(&'a self, record: &'a UsageRecord) -> BoxFuture<'a, Result<(), StorageError>>;
+
+    /// `since` (UNIX 秒) 以降のトークン使用量を日ごと・モデルごとに集計する。日付の昇順で返す。
+    fn daily_usage(&self, since: u64) -> BoxFuture<'_, Result<Vec<DailyUsage>, StorageError>>;
+
+    /// `since` (UNIX 秒) 以降のトークン使用量をモデルごとに集計する。
+    /// `user` が指定された場合はそのユーザーの分のみを集計する。
+    fn usage_by_model<'a>(
+        &'a self,
+        since: u64,
+        user: Option<&'a ConversationOrigin>,
+    ) -> BoxFuture<'a, Result<Vec<ModelUsage>, StorageError>>;
+
+    /// 永続化層を閉じる。以降の操作は失敗してよい。
+    fn close(&self) -> BoxFuture<'_, ()>;
 }
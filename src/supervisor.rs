@@ -0,0 +1,130 @@
+use crate::{model::config::AppConfigSupervisor, specs::platform::ConversationPlatform};
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use tokio::{select, spawn, task::JoinHandle, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// `ConversationPlatform` の実行状態。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlatformState {
+    Running,
+
+    /// 異常終了したので再起動を待っている。
+    BackingOff {
+        attempt: usize,
+        delay: Duration,
+    },
+
+    /// 再起動の上限に達したので諦めた。
+    Failed(String),
+
+    /// 正常に終了した。
+    Stopped,
+}
+
+impl Display for PlatformState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            PlatformState::Running => write!(f, "running"),
+            PlatformState::BackingOff { attempt, delay } => {
+                write!(f, "backing off (attempt {attempt}, next restart in {delay:?})")
+            }
+            PlatformState::Failed(reason) => write!(f, "failed ({reason})"),
+            PlatformState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// `ConversationPlatform` を監視し、終了したら指数バックオフで再起動する。
+#[derive(Debug, Clone)]
+pub struct Supervisor(Arc<SupervisorInner>);
+
+impl Supervisor {
+    pub fn new(config: &AppConfigSupervisor, shutdown: CancellationToken) -> Supervisor {
+        Supervisor(Arc::new(SupervisorInner {
+            config: config.clone(),
+            shutdown,
+            states: RwLock::new(BTreeMap::new()),
+        }))
+    }
+
+    /// プラットフォームを監視下で起動する。返される `JoinHandle` は監視を終えたときに完了する。
+    pub fn spawn(&self, name: &str, platform: impl ConversationPlatform + Send + Sync + 'static) -> JoinHandle<()> {
+        let cloned_inner = self.0.clone();
+        let name = name.to_string();
+        spawn(async move { cloned_inner.supervise(name, platform).await })
+    }
+
+    /// 各プラットフォームの現在の状態を名前順に返す。
+    pub fn states(&self) -> Vec<(String, PlatformState)> {
+        let locked = self.0.states.read().expect("states lock poisoned");
+        locked.iter().map(|(n, s)| (n.clone(), s.clone())).collect()
+    }
+}
+
+#[derive(Debug)]
+struct SupervisorInner {
+    config: AppConfigSupervisor,
+    shutdown: CancellationToken,
+    states: RwLock<BTreeMap<String, PlatformState>>,
+}
+
+impl SupervisorInner {
+    async fn supervise(&self, name: String, platform: impl ConversationPlatform) {
+        let mut attempt = 0;
+        loop {
+            self.set_state(&name, PlatformState::Running);
+            let started_at = Instant::now();
+            let result = platform.execute(self.shutdown.clone()).await;
+
+            if self.shutdown.is_cancelled() {
+                self.set_state(&name, PlatformState::Stopped);
+                return;
+            }
+            let reason = match result {
+                Ok(()) if !platform.restart_on_exit() => {
+                    info!("platform {name} finished");
+                    self.set_state(&name, PlatformState::Stopped);
+                    return;
+                }
+                Ok(()) => "exited unexpectedly".to_string(),
+                Err(err) => err.to_string(),
+            };
+            error!("platform {name} {reason}");
+
+            // しばらく動いていたなら一時的な障害とみなして数え直す
+            if started_at.elapsed() >= self.config.reset_after() {
+                attempt = 0;
+            }
+            attempt += 1;
+            if self.config.max_restarts.is_some_and(|max| attempt > max) {
+                error!("platform {name} exceeded restart limit, giving up");
+                self.set_state(&name, PlatformState::Failed(reason));
+                return;
+            }
+
+            let delay = self.config.delay(attempt);
+            warn!("restarting platform {name} in {delay:?} (attempt {attempt})");
+            self.set_state(&name, PlatformState::BackingOff { attempt, delay });
+            select! {
+                _ = sleep(delay) => (),
+                _ = self.shutdown.cancelled() => {
+                    self.set_state(&name, PlatformState::Stopped);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn set_state(&self, name: &str, state: PlatformState) {
+        let mut locked = self.states.write().expect("states lock poisoned");
+        locked.insert(name.to_string(), state);
+    }
+}